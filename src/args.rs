@@ -1,12 +1,16 @@
-use crate::cli::{dedup_args::DedupArgs, extract_args::ExtractArgs};
+use crate::cli::{
+    dedup_args::DedupArgs, extract_args::ExtractArgs, merge_shards_args::MergeShardsArgs,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// Deduplicate or cluster reads based on UMI barcodes, with error correction.
-    Dedup(DedupArgs),
+    Dedup(Box<DedupArgs>),
     /// Extract UMI barcodes from read sequence in fastq/fastq.gz files.
     Extract(ExtractArgs),
+    /// Combine the outputs of `dedup --shard` into one BAM and report.
+    MergeShards(MergeShardsArgs),
 
     Test,
 }
@@ -0,0 +1,286 @@
+use crate::cli::dedup_args::fingerprint_changes;
+use crate::cli::AlignmentFormat;
+use crate::group_report::GroupReport;
+use crate::status;
+use crate::utils::make_bam_writer;
+use anyhow::{Context, Error};
+use rust_htslib::bam::{self, Header, Read, Writer};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// a part is closed at the end of the first reference that brings it to this many input reads, so
+// that references too small to be worth a checkpoint of their own share one.
+const PART_MIN_READS: u64 = 1_000_000;
+
+const PROGRESS_FILE: &str = "progress.tsv";
+
+/// Checkpoints of a BAM output with `--checkpoint`. Rather than to the output file, reads are
+/// written to part files, each closed at the end of a reference. Once a part is closed, the last
+/// reference it holds and its [GroupReport] are recorded in a progress file, so that a run that
+/// died can be resumed after that reference with `--resume`. Once all references are done, the
+/// parts are concatenated into the output file.
+///
+/// Parts are kept in `<output>_checkpoint`, which is removed once the output is complete.
+pub struct Checkpoint {
+    dir: PathBuf,
+    header: Header,
+    num_threads: usize,
+    // reports of closed parts, and the tid of the last reference of each
+    parts: Vec<(u32, GroupReport)>,
+    // the output file's writer, held while a part is written in its place
+    pub output: Option<Writer>,
+    part_report: GroupReport,
+    part_reads: u64,
+}
+
+/// Directory holding the checkpoint of an output file.
+pub fn checkpoint_dir(outfile: &str) -> PathBuf {
+    PathBuf::from(format!("{outfile}_checkpoint"))
+}
+
+impl Checkpoint {
+    /// Start checkpointing an output file. With `resume`, parts closed by an earlier run are
+    /// picked up, provided that run deduplicated the same input with the same options, as told by
+    /// their `fingerprint` (see [crate::cli::DedupArgs::fingerprint]); otherwise, any earlier
+    /// checkpoint is discarded.
+    pub fn open(
+        outfile: &str,
+        header: Header,
+        num_threads: usize,
+        fingerprint: &[String],
+        resume: bool,
+    ) -> Result<Self, Error> {
+        let dir = checkpoint_dir(outfile);
+        let progress = dir.join(PROGRESS_FILE);
+        let fingerprint = format!("#{}", fingerprint.join("\t"));
+
+        let mut checkpoint = Self {
+            dir: dir.clone(),
+            header,
+            num_threads,
+            parts: Vec::new(),
+            output: None,
+            part_report: GroupReport::new(),
+            part_reads: 0,
+        };
+
+        if resume && progress.exists() {
+            checkpoint.parts = read_progress(&progress, &fingerprint)
+                .with_context(|| format!("Failed to resume from checkpoint {}", dir.display()))?;
+            for part in 0..checkpoint.parts.len() {
+                if !checkpoint.part_path(part).exists() {
+                    anyhow::bail!(
+                        "Part {part} of checkpoint {} is missing; rerun without --resume to start over",
+                        dir.display()
+                    )
+                }
+            }
+            status!(
+                "Resuming from checkpoint {}: {} part(s) done",
+                dir.display(),
+                checkpoint.parts.len()
+            );
+            return Ok(checkpoint);
+        }
+
+        if resume {
+            status!("No checkpoint found at {}; starting over", dir.display());
+        }
+        if dir.exists() {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to clear checkpoint {}", dir.display()))?;
+        }
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create checkpoint {}", dir.display()))?;
+        writeln!(File::create(&progress)?, "{fingerprint}")?;
+
+        Ok(checkpoint)
+    }
+
+    /// The last reference done by earlier runs, if any; reading resumes after it.
+    pub fn last_done(&self) -> Option<u32> {
+        self.parts.last().map(|(tid, _)| *tid)
+    }
+
+    /// The combined report of references done by earlier runs.
+    pub fn done_report(&self) -> GroupReport {
+        let mut report = GroupReport::new();
+        for (_, part_report) in &self.parts {
+            report.merge(part_report);
+        }
+        report
+    }
+
+    fn part_path(&self, part: usize) -> PathBuf {
+        self.dir.join(format!("part{part:05}.bam"))
+    }
+
+    /// Make a writer for the next part.
+    pub fn open_part(&self) -> Writer {
+        let path = self.part_path(self.parts.len());
+        make_bam_writer(
+            &path.to_string_lossy(),
+            self.header.clone(),
+            self.num_threads,
+            AlignmentFormat::Bam,
+            None,
+        )
+    }
+
+    /// Add the report of a batch written to the current part, and the number of input reads it
+    /// was grouped from.
+    pub fn tally(&mut self, report: &GroupReport, num_reads: u64) {
+        self.part_report.merge(report);
+        self.part_report.num_reads_input_file += num_reads as i64;
+        self.part_reads += num_reads;
+    }
+
+    /// Whether the current part holds enough reads to be closed at the end of a reference.
+    pub fn part_is_full(&self) -> bool {
+        self.part_reads >= PART_MIN_READS
+    }
+
+    /// Record the current part, just closed, as holding references up to `last_tid`.
+    pub fn record_part(&mut self, last_tid: u32) -> Result<(), Error> {
+        let report = std::mem::replace(&mut self.part_report, GroupReport::new());
+        self.part_reads = 0;
+
+        // the part must be on disk before the progress file says it's done
+        File::open(self.part_path(self.parts.len()))?.sync_all()?;
+        let mut progress = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(PROGRESS_FILE))?;
+        write!(progress, "{last_tid}\t{}", report.tsv_row())?;
+        progress.sync_all()?;
+
+        self.parts.push((last_tid, report));
+        Ok(())
+    }
+
+    /// Write the reads of all parts to the output file, in order.
+    pub fn concatenate(&self, writer: &mut Writer) -> Result<(), Error> {
+        let mut record = bam::Record::new();
+        for part in 0..self.parts.len() {
+            let path = self.part_path(part);
+            let mut reader = bam::Reader::from_path(&path)
+                .with_context(|| format!("Failed to open checkpoint part {}", path.display()))?;
+            reader.set_threads(self.num_threads)?;
+            while let Some(res) = reader.read(&mut record) {
+                res?;
+                writer.write(&record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// parts recorded in a progress file, checking they were made from the same input with the same
+// options.
+fn read_progress(path: &Path, fingerprint: &str) -> Result<Vec<(u32, GroupReport)>, Error> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let recorded = lines.next().transpose()?.unwrap_or_default();
+    if recorded != fingerprint {
+        anyhow::bail!(
+            "It was made from another input or with other options ({}); rerun without --resume \
+            to start over",
+            fingerprint_changes(&recorded, fingerprint).join(", ")
+        )
+    }
+
+    let mut parts = Vec::new();
+    for line in lines {
+        let line = line?;
+        let (tid, row) = line.split_once('\t').unwrap_or_default();
+        let part = tid
+            .parse::<u32>()
+            .ok()
+            .zip(GroupReport::from_tsv_row(row))
+            .with_context(|| format!("Invalid progress line: {line}"))?;
+        parts.push(part);
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::DedupArgs;
+    use crate::testing::{test_header, TempDir};
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let dir = TempDir::new("checkpoint");
+        let outfile = dir.join("a_RUMINA.bam").to_string_lossy().to_string();
+        let parameters = ["rumina parameters: grouping_method=raw".to_string()];
+        let header = test_header();
+
+        let mut checkpoint =
+            Checkpoint::open(&outfile, header.clone(), 1, &parameters, false).unwrap();
+        let mut report = GroupReport::new();
+        report.num_reads_output_file = 2;
+        report.max_reads_per_group = 4;
+        report.max_reads_group = "ABCDEFGH".into();
+        for last_tid in [0, 3] {
+            drop(checkpoint.open_part());
+            checkpoint.tally(&report, 5);
+            checkpoint.record_part(last_tid).unwrap();
+        }
+
+        let resumed = Checkpoint::open(&outfile, header.clone(), 1, &parameters, true).unwrap();
+        assert_eq!(resumed.last_done(), Some(3));
+        let done = resumed.done_report();
+        assert_eq!(done.num_reads_input_file, 10);
+        assert_eq!(done.num_reads_output_file, 4);
+        assert_eq!(done.max_reads_group, "ABCDEFGH");
+        assert!(GroupReport::from_tsv_row(&GroupReport::new().tsv_row())
+            .unwrap()
+            .is_blank());
+
+        // checkpoints of other parameters can't be resumed, and are cleared when starting over
+        let other = ["rumina parameters: grouping_method=directional".to_string()];
+        assert!(Checkpoint::open(&outfile, header.clone(), 1, &other, true).is_err());
+        let restarted = Checkpoint::open(&outfile, header, 1, &other, false).unwrap();
+        assert_eq!(restarted.last_done(), None);
+    }
+
+    #[test]
+    fn test_checkpoint_fingerprint() {
+        let dir = TempDir::new("fingerprint");
+        // a space in the path doesn't split its field
+        let input = dir.join("a b.bam").to_string_lossy().to_string();
+        let outfile = dir.join("a_RUMINA.bam").to_string_lossy().to_string();
+        fs::write(&input, "reads").unwrap();
+
+        let header = test_header();
+        let args = DedupArgs::for_test(&input, &dir.to_string_lossy());
+        let fingerprint = args.fingerprint(&input);
+        assert!(
+            fingerprint[2].contains("a%20b.bam size=5 "),
+            "{fingerprint:?}"
+        );
+
+        let mut checkpoint =
+            Checkpoint::open(&outfile, header.clone(), 1, &fingerprint, false).unwrap();
+        drop(checkpoint.open_part());
+        checkpoint.record_part(0).unwrap();
+        let resume = |args: &DedupArgs| {
+            Checkpoint::open(&outfile, header.clone(), 1, &args.fingerprint(&input), true)
+        };
+        assert_eq!(resume(&args).unwrap().last_done(), Some(0));
+
+        // a filter changed between runs
+        let mut filtered = args.clone();
+        filtered.min_mapq = 20;
+        let err = format!("{:#}", resume(&filtered).err().unwrap());
+        assert!(err.contains("(min_mapq=0)"), "{err}");
+
+        // so did the input
+        fs::write(&input, "other reads").unwrap();
+        let err = format!("{:#}", resume(&args).err().unwrap());
+        assert!(err.contains("size=5"), "{err}");
+        assert!(!err.contains("path="), "{err}");
+    }
+}
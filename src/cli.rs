@@ -1,7 +1,12 @@
 pub mod dedup_args;
 pub mod extract_args;
+pub mod merge_shards_args;
 pub mod misc;
 
-pub use crate::dedup_args::{DedupArgs, GroupingMethod};
+pub use crate::dedup_args::{
+    AlignmentFormat, AlignmentPolicy, DedupArgs, GroupingMethod, OutputSort, ReadGroupPool,
+    SeqKeyMethod, UnmappedReads,
+};
 pub use crate::extract_args::*;
+pub use crate::merge_shards_args::*;
 pub use crate::misc::*;
@@ -1,4 +1,8 @@
+use crate::io::fastqio::STDIO_PATH;
+use crate::io::shards::Shard;
+use crate::read_filter::parse_flags;
 use anyhow::Error;
+use clap::builder::PossibleValue;
 use clap::{Parser, ValueEnum};
 use colored::Colorize;
 
@@ -6,6 +10,10 @@ const DEFAULT_PERCENT: f32 = 0.5;
 const DEFAULT_MIN_DEPTH: usize = 3;
 const DEFAULT_MAX_EDIT: u32 = 1;
 const DEFAULT_MIN_OVERLAP: i64 = 3;
+const DEFAULT_MIN_OVERLAP_LEN: usize = 10;
+const DEFAULT_MAX_MISMATCH_DENSITY: f32 = 0.25;
+pub const DEFAULT_MAX_MEMORY_MB: u64 = 4096;
+pub const DEFAULT_MAX_CLIP: i64 = 1_000;
 static DEFAULT_THREADS: std::sync::LazyLock<usize> = std::sync::LazyLock::new(num_cpus::get);
 
 #[derive(ValueEnum, Debug, Clone)]
@@ -14,11 +22,97 @@ pub enum GroupingMethod {
     Directional,
     Raw,
 }
-#[derive(Parser, Debug)]
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum SeqKeyMethod {
+    Prefix,
+    Minimizer,
+}
+
+/// Orders BAM output can be sorted in. Template-coordinate matches `samtools sort -M
+/// template-coordinate`, placing the reads of a template, and of a UMI group, next to each other.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSort {
+    Coordinate,
+    Queryname,
+    TemplateCoordinate,
+    Unsorted,
+}
+
+/// What to do with secondary or supplementary alignments: group them like any other read, drop
+/// them, or output them only if their primary alignment is output.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentPolicy {
+    Group,
+    Drop,
+    FollowPrimary,
+}
+
+/// What to do with unmapped reads that have no reference, which can't be grouped by position:
+/// drop them, copy them to the output unchanged, write them to a BAM of their own, or group them
+/// by UMI (and sequence, with `--seq-key-len`) like FASTQ reads.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmappedReads {
+    Drop,
+    Keep,
+    Separate,
+    Dedup,
+}
+
+/// With `--per-read-group`, what reads are deduplicated apart by: their read group, or the library
+/// of their read group, pooling lanes of a library.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadGroupPool {
+    Id,
+    Library,
+}
+
+/// Alignment file formats read and written by `rumina dedup`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Bam,
+    Sam,
+    Cram,
+}
+
+impl AlignmentFormat {
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path.rsplit_once('.')?.1 {
+            "bam" => Some(Self::Bam),
+            "sam" => Some(Self::Sam),
+            "cram" => Some(Self::Cram),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Bam => "bam",
+            Self::Sam => "sam",
+            Self::Cram => "cram",
+        }
+    }
+
+    pub fn hts_format(&self) -> rust_htslib::bam::Format {
+        match self {
+            Self::Bam => rust_htslib::bam::Format::Bam,
+            Self::Sam => rust_htslib::bam::Format::Sam,
+            Self::Cram => rust_htslib::bam::Format::Cram,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, override_help = DEDUP_HELP)]
 pub struct DedupArgs {
-    #[clap(short = 'i')]
-    pub input: String,
+    #[clap(short = 'i', required_unless_present = "samplesheet")]
+    pub input: Option<String>,
+
+    #[clap(short = 'I', conflicts_with = "samplesheet")]
+    pub input2: Option<String>,
+
+    #[arg(long = "samplesheet", conflicts_with = "input")]
+    pub samplesheet: Option<String>,
 
     #[clap(long = "test")]
     pub test: bool,
@@ -56,6 +150,51 @@ pub struct DedupArgs {
     #[arg(long = "sort", help_heading = "MISC OPTIONS")]
     pub ensure_sorted: bool,
 
+    #[arg(long = "output-sort", default_value = "coordinate")]
+    pub output_sort: OutputSort,
+
+    #[arg(long = "output-format")]
+    pub output_format: Option<AlignmentFormat>,
+
+    #[arg(long = "reference")]
+    pub reference: Option<String>,
+
+    #[arg(long = "csi")]
+    pub csi: bool,
+
+    #[arg(long = "region")]
+    pub region: Vec<String>,
+
+    #[arg(long = "regions-bed")]
+    pub regions_bed: Option<String>,
+
+    #[arg(long = "shard")]
+    pub shard: Option<Shard>,
+
+    #[arg(long = "require-flags", value_parser = parse_flags, default_value = "0")]
+    pub require_flags: u16,
+
+    #[arg(long = "exclude-flags", value_parser = parse_flags, default_value = "0")]
+    pub exclude_flags: u16,
+
+    #[arg(long = "min-mapq", default_value_t = 0)]
+    pub min_mapq: u8,
+
+    #[arg(long = "secondary", default_value = "group")]
+    pub secondary: AlignmentPolicy,
+
+    #[arg(long = "supplementary", default_value = "group")]
+    pub supplementary: AlignmentPolicy,
+
+    #[arg(long = "unmapped", default_value = "drop")]
+    pub unmapped: UnmappedReads,
+
+    #[arg(long = "rejects-out")]
+    pub rejects_out: Option<String>,
+
+    #[arg(long = "per-read-group", num_args = 0..=1, default_missing_value = "id")]
+    pub per_read_group: Option<ReadGroupPool>,
+
     #[arg(value_parser = clap::value_parser!(i64).range(1..), short = 'x', long = "split-window")]
     pub split_window: Option<i64>,
 
@@ -65,15 +204,54 @@ pub struct DedupArgs {
     #[arg(short = 'b', long = "min-overlap-bp", default_value_t = DEFAULT_MIN_OVERLAP)]
     pub min_overlap_bp: i64,
 
+    #[arg(long = "merge-overlap", requires = "input2")]
+    pub merge_overlap: bool,
+
+    #[arg(long = "min-overlap-len", default_value_t = DEFAULT_MIN_OVERLAP_LEN)]
+    pub min_overlap_len: usize,
+
+    #[arg(long = "max-mismatch-density", default_value_t = DEFAULT_MAX_MISMATCH_DENSITY)]
+    pub max_mismatch_density: f32,
+
+    #[arg(value_parser = clap::value_parser!(u64).range(1..), long = "seq-key-len")]
+    pub seq_key_len: Option<u64>,
+
+    #[arg(long = "seq-key", default_value = "prefix", requires = "seq_key_len")]
+    pub seq_key: SeqKeyMethod,
+
     #[arg(short = 'l', long = "paired", conflicts_with = "merge_pairs")]
     pub paired: bool,
 
+    #[arg(long = "tmpdir")]
+    pub tmpdir: Option<String>,
+
+    #[arg(value_parser = clap::value_parser!(u64).range(1..), long = "max-memory")]
+    pub max_memory: Option<u64>,
+
+    #[arg(value_parser = clap::value_parser!(u64).range(1..), long = "max-reads-per-window")]
+    pub max_reads_per_window: Option<u64>,
+
+    #[arg(value_parser = clap::value_parser!(i64).range(0..), long = "max-clip", default_value_t = DEFAULT_MAX_CLIP)]
+    pub max_clip: i64,
+
     #[arg(short = 't', long = "threads", default_value_t = *DEFAULT_THREADS)]
     pub threads: usize,
 
     #[arg(short = 'c', long = "strict-threads", default_value_t = false)]
     pub strict_threads: bool,
 
+    #[arg(long = "pipeline-depth", default_value_t = 1)]
+    pub pipeline_depth: usize,
+
+    #[arg(long = "parallel-files", default_value_t = 1)]
+    pub parallel_files: usize,
+
+    #[arg(long = "checkpoint")]
+    pub checkpoint: bool,
+
+    #[arg(long = "resume")]
+    pub resume: bool,
+
     #[arg(short = 'q', long = "progress", default_value_t = false)]
     pub progress: bool,
 }
@@ -86,8 +264,277 @@ impl DedupArgs {
             self.percentage
         )
         }
+
+        // with --samplesheet, inputs are checked per sample (see crate::io::file_io::gather_samples)
+        let input = self.input.as_deref().unwrap_or_default();
+        let multiple_inputs = self.samplesheet.is_some() || std::path::Path::new(input).is_dir();
+
+        if self.parallel_files == 0 {
+            anyhow::bail!("--parallel-files must be at least 1")
+        }
+
+        if let Some(input2) = &self.input2 {
+            if multiple_inputs {
+                anyhow::bail!("-I cannot be used with a directory input; supply an R1 file with -i")
+            }
+
+            if !is_fastq(input) || !is_fastq(input2) {
+                anyhow::bail!("-i and -I must both be FASTQ files when deduplicating paired reads")
+            }
+        }
+
+        if input == STDIO_PATH && (self.paired || self.input2.is_some()) {
+            anyhow::bail!("Input from stdin can't be paired: -l/--paired retrieves mates through the input's index")
+        }
+
+        let has_regions = !self.region.is_empty() || self.regions_bed.is_some();
+        if has_regions && (input == STDIO_PATH || is_fastq(input)) {
+            anyhow::bail!("--region and --regions-bed require an indexed alignment input")
+        }
+
+        if self.unmapped != UnmappedReads::Drop && has_regions {
+            anyhow::bail!("--unmapped can't be combined with --region or --regions-bed, as unmapped reads lie outside any region")
+        }
+
+        if let Some(shard) = self.shard {
+            if input == STDIO_PATH || is_fastq(input) {
+                anyhow::bail!("--shard requires an indexed alignment input")
+            }
+            if has_regions {
+                anyhow::bail!("--shard can't be combined with --region or --regions-bed")
+            }
+            // shards are merged by coordinate, and hold only part of each read's alignments
+            if self.output_sort != OutputSort::Coordinate {
+                anyhow::bail!("--shard requires coordinate-sorted output")
+            }
+            if self.secondary == AlignmentPolicy::FollowPrimary
+                || self.supplementary == AlignmentPolicy::FollowPrimary
+            {
+                anyhow::bail!("--shard can't be used with --secondary/--supplementary follow-primary, as a primary may lie in another shard")
+            }
+            if self.rejects_out.is_some() || self.merge_pairs.is_some() {
+                anyhow::bail!("--shard can't be used with --rejects-out or -m/--merge-pairs")
+            }
+            if self.checkpoint || self.resume {
+                anyhow::bail!("--shard can't be used with --checkpoint or --resume")
+            }
+            if self.outdir == STDIO_PATH && shard.count > 1 {
+                anyhow::bail!("--shard can't write to stdout; shards are combined from files with rumina merge-shards")
+            }
+        }
+
+        if self.unmapped == UnmappedReads::Dedup && self.paired {
+            anyhow::bail!("--unmapped dedup can't be used with -l/--paired, as unmapped mates can't be retrieved by position; use --unmapped keep or separate")
+        }
+
+        if self.rejects_out.is_some() && (is_fastq(input) || multiple_inputs) {
+            anyhow::bail!("--rejects-out requires a single alignment input (BAM/SAM/CRAM)")
+        }
+
+        if self.per_read_group.is_some() && is_fastq(input) {
+            anyhow::bail!("--per-read-group requires alignment input (BAM/SAM/CRAM)")
+        }
+
+        if self.outdir == STDIO_PATH {
+            if self.unmapped == UnmappedReads::Separate {
+                anyhow::bail!("--unmapped separate can't write to stdout; use --unmapped keep")
+            }
+
+            if is_fastq(input) || multiple_inputs {
+                anyhow::bail!("Output to stdout requires a single alignment input (BAM/SAM/CRAM)")
+            }
+
+            if self.merge_pairs.is_some() {
+                anyhow::bail!("-m/--merge-pairs can't write to stdout")
+            }
+        }
+
+        if self.checkpoint || self.resume {
+            let flag = match self.resume {
+                true => "--resume",
+                false => "--checkpoint",
+            };
+            if input == STDIO_PATH || self.outdir == STDIO_PATH {
+                anyhow::bail!("{flag} can't be used with input from stdin or output to stdout")
+            }
+            if is_fastq(input) {
+                anyhow::bail!("{flag} requires alignment input (BAM/SAM/CRAM)")
+            }
+            // these hold reads, or reports, until all references are done
+            if self.output_sort != OutputSort::Coordinate || self.ensure_sorted {
+                anyhow::bail!("{flag} requires coordinate-sorted output, without --sort")
+            }
+            if self.secondary == AlignmentPolicy::FollowPrimary
+                || self.supplementary == AlignmentPolicy::FollowPrimary
+            {
+                anyhow::bail!(
+                    "{flag} can't be used with --secondary/--supplementary follow-primary"
+                )
+            }
+            if has_regions || self.per_read_group.is_some() {
+                anyhow::bail!(
+                    "{flag} can't be used with --region, --regions-bed or --per-read-group"
+                )
+            }
+            if self.rejects_out.is_some() || self.unmapped == UnmappedReads::Separate {
+                anyhow::bail!("{flag} can't be used with --rejects-out or --unmapped separate")
+            }
+        }
+
+        if self.merge_pairs.is_some() && self.output_sort != OutputSort::Coordinate {
+            anyhow::bail!(
+                "-m/--merge-pairs requires coordinate-sorted output (--output-sort coordinate)"
+            )
+        }
+
+        if self.max_mismatch_density < 0.0 || self.max_mismatch_density > 1.0 {
+            anyhow::bail!(
+                "Invalid value {} for --max-mismatch-density! Choose a value between (inclusive) 0.0 and 1.0",
+                self.max_mismatch_density
+            )
+        }
+
+        if self.pipeline_depth == 0 {
+            anyhow::bail!("--pipeline-depth must be at least 1")
+        }
         Ok(())
     }
+
+    /// Minimum number of reads for a group to be output.
+    pub fn min_depth(&self) -> usize {
+        match self.singletons {
+            true => 1,
+            false => self.min_cluster_depth,
+        }
+    }
+
+    /// What to do with unmapped reads without a reference. With --shard, only the last shard
+    /// takes them.
+    pub fn unmapped_reads(&self) -> UnmappedReads {
+        match self.shard {
+            Some(shard) if !shard.takes_unmapped() => UnmappedReads::Drop,
+            _ => self.unmapped,
+        }
+    }
+
+    /// Parameters deciding how reads are grouped, recorded as `@CO` lines in alignment output.
+    pub fn header_comments(&self) -> Vec<String> {
+        let method = self
+            .grouping_method
+            .to_possible_value()
+            .map_or(String::new(), |value| value.get_name().to_string());
+
+        let mut parameters = format!(
+            "rumina parameters: grouping_method={method} percentage={} max_edit={} min_depth={} \
+            length={} rev={} only_group={} separator={}",
+            self.percentage,
+            self.max_edit,
+            self.min_depth(),
+            self.length,
+            self.cluster_rev,
+            self.only_group,
+            escape_value(&self.separator),
+        );
+        if let Some(value) = self
+            .per_read_group
+            .and_then(|pool_by| pool_by.to_possible_value())
+        {
+            parameters.push_str(&format!(" per_read_group={}", value.get_name()));
+        }
+        // merge-shards checks that shards were made alike, and that none is missing
+        if let Some(shard) = self.shard {
+            parameters.push_str(&format!(" shard={shard}"));
+        }
+
+        vec![parameters]
+    }
+
+    /// Everything deciding which reads of `input` are output and how they are tagged: the grouping
+    /// parameters (see [DedupArgs::header_comments]), the options selecting reads, and the input
+    /// file by path, size and modification time. Recorded in checkpoints, which are only resumed
+    /// if theirs matches, and with `--shard` as `@CO` lines in alignment output, as shards are
+    /// only merged if theirs match. Values are escaped (see [escape_value]), so that lines split into
+    /// `name=value` fields on spaces.
+    pub fn fingerprint(&self, input: &str) -> Vec<String> {
+        let name = |value: Option<PossibleValue>| {
+            value.map_or(String::new(), |value| value.get_name().to_string())
+        };
+        let or_none = |value: Option<&str>| escape_value(value.unwrap_or("none"));
+
+        let options = format!(
+            "rumina options: min_mapq={} require_flags={} exclude_flags={} secondary={} \
+            supplementary={} unmapped={} paired={} merge_pairs={} min_overlap_bp={} seq_key={} \
+            seq_key_len={} output_sort={} region={} regions_bed={}",
+            self.min_mapq,
+            self.require_flags,
+            self.exclude_flags,
+            name(self.secondary.to_possible_value()),
+            name(self.supplementary.to_possible_value()),
+            name(self.unmapped.to_possible_value()),
+            self.paired,
+            or_none(self.merge_pairs.as_deref()),
+            self.min_overlap_bp,
+            name(self.seq_key.to_possible_value()),
+            or_none(self.seq_key_len.map(|len| len.to_string()).as_deref()),
+            name(self.output_sort.to_possible_value()),
+            escape_value(&self.region.join(",")),
+            or_none(self.regions_bed.as_deref()),
+        );
+
+        let path = std::fs::canonicalize(input)
+            .map_or(input.to_string(), |path| path.to_string_lossy().to_string());
+        let (size, mtime) = std::fs::metadata(input).map_or((0, 0), |metadata| {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos());
+            (metadata.len(), mtime)
+        });
+        let input = format!(
+            "rumina input: path={} size={size} mtime={mtime}",
+            escape_value(&path)
+        );
+
+        let mut fingerprint = self.header_comments();
+        fingerprint.extend([options, input]);
+        fingerprint
+    }
+}
+
+/// Fields of a recorded [DedupArgs::fingerprint] that differ from those of `current`, e.g.
+/// `min_mapq=0` where it is now `min_mapq=20`. Fingerprints may be joined from their lines in any
+/// way.
+pub fn fingerprint_changes(recorded: &str, current: &str) -> Vec<String> {
+    let fields = |text: &str| -> Vec<String> {
+        text.split(['\t', ' ', '\n'])
+            .filter(|field| field.contains('='))
+            .map(String::from)
+            .collect()
+    };
+    let current = fields(current);
+
+    fields(recorded)
+        .into_iter()
+        .filter(|field| !current.contains(field))
+        .collect()
+}
+
+/// Percent-encode `%`, spaces, tabs and newlines in a value of a `name=value` field of
+/// [DedupArgs::header_comments] or [DedupArgs::fingerprint], which would otherwise split it.
+pub fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' | ' ' | '\t' | '\n' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_fastq(fname: &str) -> bool {
+    fname.ends_with(".fastq.gz")
 }
 
 impl std::fmt::Display for DedupArgs {
@@ -110,7 +557,7 @@ impl std::fmt::Display for DedupArgs {
             {}: {}\n
 ",
             "Input".purple(),
-            self.input,
+            self.input.as_ref().or(self.samplesheet.as_ref()).unwrap(),
             "Grouping method".purple(),
             self.grouping_method,
             "Separator".purple(),
@@ -147,15 +594,25 @@ const DEDUP_HELP: &str = r#"
 RUMINA dedup: cluster and deduplicate or group reads by UMI barcodes
 
 usage:
-    rumina dedup -i [*.bam|*.fastq|*.fastq.gz] -g {directional, acyclic, raw} -s <UMI SEPARATOR> [OPTIONS] -o [OUTDIR]
+    rumina dedup -i [*.bam|*.cram|*.fastq|*.fastq.gz] -g {directional, acyclic, raw} -s <UMI SEPARATOR> [OPTIONS] -o [OUTDIR]
 
-    The input can be either one FASTQ/BAM file or a folder containing FASTQ/BAM files. 
-    In the latter case, RUMINA will process all FASTQ/BAM files sequentially.
+    The input can be either one FASTQ/BAM/CRAM file or a folder containing FASTQ/BAM/CRAM files. 
+    In the latter case, RUMINA will process all FASTQ/BAM/CRAM files sequentially, or several at
+    once with --parallel-files.
+
+    Alternatively, list samples in a sample sheet with --samplesheet instead of -i.
 
 arguments:
 
     [[required]]
-    -i: input files: BAMs must be sorted and indexed.
+    -i: input files. BAMs and CRAMs without an index (BAI or CSI, CRAI) are indexed, and copies of
+    unsorted ones sorted, first. Give `-` to stream a coordinate-sorted BAM/SAM/CRAM from stdin
+    instead; no index is needed
+
+    --samplesheet: instead of -i, a CSV with a header row and columns sample, r1, r2 (optional)
+    and bam: each sample has an r1 FASTQ, with its R2 mate if paired, or a bam (BAM/SAM/CRAM).
+    Outputs are named after the sample. Optional columns separator, grouping_method, min_depth,
+    max_edit and percentage override the options of the same name per sample
 
     -g, --grouping-method: Specifies UMI clustering method. Choose from:
         - directional: as in UMI-tools: predict mutant UMIs based on hamming distance and frequency 
@@ -168,8 +625,12 @@ arguments:
     -l, --length: stratify reads additionally by sequence length (including soft-clipped bases)
     -u, --rev: search for reverse complements of UMIs when clustering
     -v, --only-group: do not deduplicate clusters; instead annotate reads with cluster ID in UG tag
+    (FASTQ: in the header comment as MI:Z:<group> RX:Z:<UMI> cD:i:<group size>)
     -d, --min-depth: minimum number of reads in a cluster for it to be output [3]
     -f, --singletons: remove minimum depth limit for clusters. Identical to --min_depth 1
+    --per-read-group: never group reads of different read groups (RG tag) together, and write a
+    report per read group. Give `library` to pool read groups of the same library (LB), e.g. the
+    lanes of a library [id]
 
     [[grouping - advanced]]
     -p, --percentage: The fraction of a parent UMI's read count an offshoot's count must be [0.5]
@@ -179,12 +640,82 @@ arguments:
     -t, --threads: number of threads to parallelize coordinate processing. Defaults to # sys threads
     -c, --strict-threads: restrict IO operations to the number specified in --threads 
 
+    --pipeline-depth: BAM windows that may be held in memory at once while reading, grouping
+    and writing run alongside each other. 1 runs them in turn, one window at a time [1]
+
+    --parallel-files: deduplicate this many files of an input folder at once, splitting --threads
+    between them [1]. Each file logs to its own <file>_rumina_group.log, and the console shows a
+    summary line per file
+
     -x, --split-window: Process an input reference alignment by x coordinates at at time.
     Not using this option will process the entire alignment at once. 
     This is recommended when dealing with extreme depth and limited memory
 
-    -s, --ensure-sorted: only relevant when --split_window is used.
-    Ensure output is sorted by buffering all outbound reads until all windows have completed
+    --tmpdir: deduplicate out-of-core: spill reads to partitions in this directory, and group
+    one partition at a time. Reads are partitioned by position (FASTQ: see --seq-key-len)
+
+    --max-memory: approximate memory budget in MB for the reads of one window, or of one
+    partition with --tmpdir [partitions: 4096]. BAM windows holding more reads are cut short,
+    and the remaining reads processed as another window
+
+    --max-reads-per-window: cut BAM windows short once they hold this many reads
+
+    --max-clip: longest leading soft clip expected of a forward BAM read. Reads are read this
+    far past the end of a window or around a region, and a read clipped further is an error
+    when windowing or given regions [1000]
+    --checkpoint: write BAM output in parts closed at the end of references, recording progress
+    in <output>_checkpoint, so that a run that dies can be resumed
+    --resume: resume a run made with --checkpoint (and the same input and options), skipping
+    references it finished. Starts over if there is no checkpoint
+
+    [[read filters]]
+    --require-flags: only group BAM reads with all of these SAM flags set, given as a number
+    (0x hex allowed) or comma-separated names, e.g. PAIRED,PROPER_PAIR [0]
+    --exclude-flags: don't group BAM reads with any of these flags set, e.g. UNMAP,QCFAIL [0]
+    --min-mapq: don't group BAM reads with a lower mapping quality [0]
+    --secondary, --supplementary: what to do with secondary/supplementary alignments. Choose from:
+        - group: group them like any other read, subject to the filters above [default]
+        - drop: remove them from the output
+        - follow-primary: output them, with their primary's group tags, only if their primary
+          alignment is output
+    --unmapped: what to do with unmapped reads without a reference, which can't be grouped by
+    position. Choose from:
+        - drop: leave them out of the output [default]
+        - keep: copy them to the output unchanged
+        - separate: write them unchanged to a *_unmapped BAM next to the output
+        - dedup: group them by UMI alone, or by UMI and sequence with --seq-key-len, as in FASTQ
+          mode
+
+    --rejects-out: write reads left out of the output to this BAM, tagged with the reason in
+    RJ (duplicate, below_min_depth, discordant_pair, filtered) and, if grouped, the group's ID in
+    UG. Unsorted. Duplicates are kept in memory until grouped, so memory use grows
+
+    [[regions]]
+    --region: only deduplicate reads of families grouped in this region (chr, chr:start or
+    chr:start-end; 1-based, inclusive). May be given more than once
+    --regions-bed: as --region, for each interval of a BED file. Named after the BED's 4th
+    column if it exists. A per-region report is written alongside the main report
+    --shard: deduplicate only shard i of N (given as i/N) of an indexed input, to split it across
+    jobs. References are dealt out to shards by their read counts in the index, and the largest
+    are split by position, so that shards hold about as many reads. The last shard takes unmapped
+    reads. Outputs are named <stem>_RUMINA_shard<i>of<N>; combine them with rumina merge-shards
+
+    --output-sort: order of BAM output reads [coordinate, queryname, template-coordinate,
+    unsorted] [default: coordinate]. Sets @HD SO/GO/SS to match. Only coordinate-sorted output is
+    indexed
+
+    --sort: sort output reads by coordinate on their way out, spilling sorted runs to --tmpdir
+    (or --outdir) beyond --max-memory. Only needed with --paired, where retrieved mates may
+    break coordinate order; unsorted output is otherwise sorted before indexing
+
+    [[alignment formats]]
+    --reference: reference FASTA used to decode CRAM input and encode CRAM output
+
+    --output-format: format of alignment output [bam, sam, cram]. Defaults to the input format.
+    BAM is indexed with a BAI, CRAM with a CRAI; SAM is not indexed
+
+    --csi: index BAM output with a CSI rather than a BAI. Chosen automatically if a reference is
+    longer than a BAI can index (2^29 - 1 bp)
 
     [[paired-end]]
     -l, --paired: Use only R1 for deduplication, and pair output R1 with R2, similar to UMI-tools
@@ -195,8 +726,90 @@ arguments:
     -b, --min-overlap-bp: Minimum number of overlapping, matching bases for merging two reads.
     Reads not meeting this criterion will both be discarded. Only relevant with -m/--merge-pairs
 
+    [[FASTQ]]
+    --seq-key-len: bucket FASTQ reads by a k-mer of their sequence in addition to the UMI,
+    so that UMI + sequence defines a molecule. Buckets are grouped in parallel. Applies to
+    unmapped BAM reads as well, with --unmapped dedup
+    --seq-key: which k-mer to use with --seq-key-len. Choose from:
+        - prefix: the first k bases of the read [default]
+        - minimizer: the k-mer with the lowest hash value in the read
+
+    [[paired-end, FASTQ]]
+    -I: R2 FASTQ accompanying the R1 FASTQ given with -i. Without --merge-overlap, each R1/R2
+    pair is deduplicated as a unit (UMI from the R1 header, representative chosen by the combined
+    sequence), and synchronized *_RUMINA R1 and R2 outputs are written
+
+    --merge-overlap: merge overlapping R1/R2 by sequence overlap (no reference needed),
+    then deduplicate merged fragments. Pairs that cannot be merged are written unchanged to
+    separate *_unmerged_R1/R2 outputs
+
+    --min-overlap-len: minimum overlap between R1 and reverse-complemented R2 for merging [10]
+
+    --max-mismatch-density: maximum fraction of mismatching bases in the overlap [0.25]
+
     [[misc]]
-    -o, --outdir: directory relative to $(pwd) in which to store output files
-    -q, --progress: show progress bar, prints to stdout
+    -o, --outdir: directory relative to $(pwd) in which to store output files. Give `-` to
+    write alignments to stdout instead, without reports, logs or an index
+    -q, --progress: show progress bar, prints to stderr
 
 "#;
+
+#[cfg(test)]
+impl DedupArgs {
+    /// Arguments of `dedup -i <input> -g directional -s _ -t 1 -o <outdir>`, with every other option
+    /// left at its default, for tests.
+    pub fn for_test(input: &str, outdir: &str) -> Self {
+        Self {
+            input: Some(input.to_string()),
+            input2: None,
+            samplesheet: None,
+            test: false,
+            grouping_method: GroupingMethod::Directional,
+            separator: "_".to_string(),
+            percentage: DEFAULT_PERCENT,
+            max_edit: DEFAULT_MAX_EDIT,
+            min_cluster_depth: DEFAULT_MIN_DEPTH,
+            cluster_rev: false,
+            length: false,
+            only_group: false,
+            singletons: false,
+            outdir: outdir.to_string(),
+            ensure_sorted: false,
+            output_sort: OutputSort::Coordinate,
+            output_format: None,
+            reference: None,
+            csi: false,
+            region: Vec::new(),
+            regions_bed: None,
+            shard: None,
+            require_flags: 0,
+            exclude_flags: 0,
+            min_mapq: 0,
+            secondary: AlignmentPolicy::Group,
+            supplementary: AlignmentPolicy::Group,
+            unmapped: UnmappedReads::Drop,
+            rejects_out: None,
+            per_read_group: None,
+            split_window: None,
+            merge_pairs: None,
+            min_overlap_bp: DEFAULT_MIN_OVERLAP,
+            merge_overlap: false,
+            min_overlap_len: DEFAULT_MIN_OVERLAP_LEN,
+            max_mismatch_density: DEFAULT_MAX_MISMATCH_DENSITY,
+            seq_key_len: None,
+            seq_key: SeqKeyMethod::Prefix,
+            paired: false,
+            tmpdir: None,
+            max_memory: None,
+            max_reads_per_window: None,
+            max_clip: DEFAULT_MAX_CLIP,
+            threads: 1,
+            strict_threads: false,
+            pipeline_depth: 1,
+            parallel_files: 1,
+            checkpoint: false,
+            resume: false,
+            progress: false,
+        }
+    }
+}
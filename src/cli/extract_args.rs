@@ -23,24 +23,27 @@ impl std::fmt::Display for FastqQualEncoding {
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(author, version, override_help = EXTRACT_HELP)]
 pub struct ExtractArgs {
     #[clap(long = "test")]
     pub test: bool,
 
-    #[arg(short = 'i')]
-    pub in1: PathBuf,
+    #[arg(short = 'i', required_unless_present = "samplesheet")]
+    pub in1: Option<PathBuf>,
 
-    #[arg(short = 'I')]
+    #[arg(short = 'I', conflicts_with = "samplesheet")]
     pub in2: Option<PathBuf>,
 
     #[arg(short = 'o')]
     pub out1: PathBuf,
 
-    #[arg(short = 'O')]
+    #[arg(short = 'O', conflicts_with = "samplesheet")]
     pub out2: Option<PathBuf>,
 
+    #[arg(long = "samplesheet", conflicts_with = "in1")]
+    pub samplesheet: Option<String>,
+
     #[arg(short = 's', default_value_t = '_')]
     pub umi_separator: char,
 
@@ -80,7 +83,11 @@ Usage:
         rumina extract -i <FASTQ> -p <PATTERN> -o <OUTPUT1>
             -I <FASTQ2> -P <PATTERN2> -O <OUTPUT2>
 
-    All FASTQ files must end with either .fastq or .fastq.gz.
+    sample sheet:
+        rumina extract --samplesheet <CSV> -p <PATTERN> -o <OUTDIR> [OPTIONS]
+
+    All FASTQ files must end with either .fastq or .fastq.gz. Alternatively, give `-` to -i to
+    read from stdin (plaintext or gzipped), or to -o to write plaintext FASTQ to stdout.
 
     Barcodes are only extracted from a mate file if an accompanying pattern (-p or -P) is provided,
     e.g., barcodes are not extracted from R2 if -P is not set.
@@ -90,14 +97,19 @@ Options:
         --version: show version
 
     extract:
-        -i: first FASTQ input. Must end in .fastq or .fastq.gz
+        -i: first FASTQ input. Must end in .fastq or .fastq.gz, or be `-` for stdin
         -I: second FASTQ input. Must end in .fastq or .fastq.gz
-        -o: first FASTQ output. Must end in .fastq or .fastq.gz
+        -o: first FASTQ output. Must end in .fastq or .fastq.gz, or be `-` for stdout
         -O: second FASTQ output. Must end in .fastq. or .fastq.gz
 
         -p: extraction pattern for file given with -i
         -P: extraction pattern for file given with -I
 
+        --samplesheet: extract each sample of a CSV sample sheet with columns sample, r1 and
+        (optionally) r2, writing <sample>.fastq(.gz), or <sample>_R1/_R2 for pairs, to the
+        directory given with -o. Columns pattern, pattern2 and separator override -p, -P and -s
+        per sample. Samples with only a bam are skipped
+
         -s: character to use to delimit barcodes from each other and read header ['_']
 
         --retain-seq: don't remove barcode bases from read sequences during extraction.
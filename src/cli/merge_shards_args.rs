@@ -0,0 +1,45 @@
+use clap::Parser;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, override_help = MERGE_SHARDS_HELP)]
+pub struct MergeShardsArgs {
+    #[arg(required = true)]
+    pub shards: Vec<String>,
+
+    #[arg(short = 'o')]
+    pub output: String,
+
+    #[arg(long = "reference")]
+    pub reference: Option<String>,
+
+    #[arg(long = "csi")]
+    pub csi: bool,
+
+    #[arg(short = 't', long = "threads", default_value_t = num_cpus::get())]
+    pub threads: usize,
+}
+
+const MERGE_SHARDS_HELP: &str = r#"
+rumina merge-shards - combine the shards of an input deduplicated with `rumina dedup --shard`
+
+Usage:
+    rumina merge-shards -o <OUTPUT> <SHARD>... [OPTIONS]
+
+    Give the output of every shard, e.g. sample_RUMINA_shard*of4.bam. Shards must have been
+    deduplicated from the same input with the same options, and none may be missing.
+
+    Reads are merged in coordinate order, and group tags (UG) prefixed with their shard's index
+    (e.g. 2-ABCDEFGH) so that they stay unique across shards. The shards' reports are summed into <OUTPUT stem>_rumina_report.tsv, and the
+    output is indexed.
+
+Options:
+    misc:
+        --version: show version
+
+    merge-shards:
+        -o: merged output file (.bam, .sam or .cram)
+        --reference: reference FASTA, required for CRAM shards or output
+        --csi: index the output with a CSI index rather than BAI. CSI is used regardless if a
+        reference is too long for BAI
+        -t/--threads: number of threads to use for (de)compression [Number of system threads]
+"#;
@@ -1,5 +1,9 @@
 use crate::cli::DedupArgs;
+use crate::group_report::GroupReport;
+use anyhow::Error;
 use colored::Colorize;
+use num_format::{Locale, ToFormattedString};
+use std::time::Duration;
 
 const LOGO: &str = r#"
 
@@ -15,22 +19,46 @@ const LOGO: &str = r#"
 const DIVIDER: &str = "=========================";
 
 pub fn print_logo() {
-    println!("{}", LOGO.bright_blue())
+    eprintln!("{}", LOGO.bright_blue())
 }
 
 pub fn print_init(args: &DedupArgs) {
-    println!("{}", "INIT".purple());
-    println!("{}", DIVIDER.purple());
-    println!("{args}");
+    eprintln!("{}", "INIT".purple());
+    eprintln!("{}", DIVIDER.purple());
+    eprintln!("{args}");
 }
 
 pub fn print_file_info(file_name: &String, cur_file_num: usize, num_files: usize) {
-    println!(
+    eprintln!(
         "{} {} {}: {}",
         format!("File {}", cur_file_num).cyan(),
         "of".cyan(),
         format!("{}", num_files).cyan(),
         file_name
     );
-    println!("{}", DIVIDER.cyan());
+    eprintln!("{}", DIVIDER.cyan());
+}
+
+/// One line per file deduplicated with `--parallel-files`, printed as it finishes. Errors are
+/// printed in full once all files are done.
+pub fn print_file_summary(
+    file_name: &str,
+    cur_file_num: usize,
+    num_files: usize,
+    result: &Result<GroupReport, Error>,
+    elapsed: Duration,
+) {
+    let file = format!("File {} of {}", cur_file_num, num_files).cyan();
+    match result {
+        Ok(report) => eprintln!(
+            "{file}: {file_name}: {} reads in, {} reads out, {} groups ({:.1}s)",
+            report.num_reads_input_file.to_formatted_string(&Locale::en),
+            report
+                .num_reads_output_file
+                .to_formatted_string(&Locale::en),
+            report.num_passing_groups.to_formatted_string(&Locale::en),
+            elapsed.as_secs_f64()
+        ),
+        Err(_) => eprintln!("{file}: {file_name}: {}", "FAILED".red()),
+    }
 }
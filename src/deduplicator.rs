@@ -1,8 +1,9 @@
 use crate::group_report::GroupReport;
 use crate::processor::UmiHistogram;
-use crate::read_picker::{correct_errors, get_counts, push_all_reads};
-use crate::read_store::{ReadStore, SeqMap};
+use crate::read_picker::{correct_errors, get_counts, push_all_reads, take_duplicates};
+use crate::read_store::{ReadStore, Retain, SeqMap};
 use crate::record::SequenceRecord;
+use crate::rejects::RejectReason;
 use crate::IndexMap;
 use indexmap::IndexSet;
 
@@ -17,18 +18,29 @@ const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
 
 const UMI_TAG_LEN: usize = 8;
 
+/// The report of a batch of groups, their output reads, and with `keep_rejects`, the reads left
+/// out.
+pub type TaggedReads<T> = (Option<GroupReport>, Vec<T>, Vec<(T, RejectReason)>);
+
 // this struct serves to
 // 1. for a given UMI group, pull all associated reads and
 // 2. deduplicate by sequence majority or
 // 3. output all reads in group
 //
 // remaining reads will be assigned a group-specific "UG" tag.
+//
+// with keep_rejects, reads left out are returned as well, tagged with the group they belonged to.
 pub struct GroupHandler {
     pub seed: u64,
     pub group_only: bool,
     pub min_depth: usize,
+    pub keep_rejects: bool,
 }
 
+// rejected groups are tagged from a separate sequence, so that tags of output groups stay the same
+// whether or not rejects are kept
+const REJECT_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
 pub fn generate_tag(
     rng: &mut StdRng,
     used_tags: &mut HashSet<[u8; UMI_TAG_LEN]>,
@@ -62,13 +74,17 @@ impl GroupHandler {
         final_umis: impl Iterator<Item = IndexSet<smol_str::SmolStr>>,
         umis_records: &mut IndexMap<smol_str::SmolStr, (i32, SeqMap<T>)>,
         counts: UmiHistogram,
-    ) -> Result<(Option<GroupReport>, Vec<T>), Error> {
+    ) -> Result<TaggedReads<T>, Error> {
         // for each UMI within a group, assign the same tag
 
         let mut rng = StdRng::seed_from_u64(self.seed);
         let mut output_list: Vec<T> = Vec::new();
         let mut used_tags: HashSet<[u8; UMI_TAG_LEN]> = HashSet::with_capacity(output_list.len());
 
+        let mut reject_rng = StdRng::seed_from_u64(self.seed ^ REJECT_SEED);
+        let mut reject_tags: HashSet<[u8; UMI_TAG_LEN]> = HashSet::new();
+        let mut rejects: Vec<(T, RejectReason)> = Vec::new();
+
         // either group reads, or group and deduplicate
         let read_processor = match self.group_only {
             true => push_all_reads,
@@ -94,48 +110,72 @@ impl GroupHandler {
                 // check if number of reads per group is new minimum or maximum
                 if num_reads_in_group < group_report.min_reads_per_group {
                     group_report.min_reads_per_group = num_reads_in_group;
-                    group_report.min_reads_group = ug_tag;
+                    group_report.min_reads_group = String::from_utf8_lossy(&ug_tag).into();
                 }
 
                 if num_reads_in_group > group_report.max_reads_per_group {
                     group_report.max_reads_per_group = num_reads_in_group;
-                    group_report.max_reads_group = ug_tag;
+                    group_report.max_reads_group = String::from_utf8_lossy(&ug_tag).into();
                 }
 
                 // since the group has enough reads to be used, count it in the report
                 group_report.num_passing_groups += 1;
 
                 let top_group = top_umi.get_index(0).unwrap();
-
-                let mut top_umi = top_umi.iter();
-                let (_, mut seq_map) = umis_records
-                    .swap_remove(top_umi.next().unwrap())
-                    .context("FATAL: Attempted to remove UMI that was already sent for output")?;
-
-                // get all the reads across all the umis in the group
-
-                for group in top_umi {
-                    seq_map.combine(umis_records.swap_remove(group).unwrap().1, self.group_only);
-                }
+                let mut seq_map = self.gather_reads(&top_umi, umis_records)?;
 
                 // tag final reads and send for writing to output bam
                 let mut to_write = read_processor(&mut seq_map);
 
-                // TODO: figure out how to mark groups for FASTQ records
                 to_write.iter_mut().for_each(|read| {
-                    read.mark_group(top_group.as_bytes(), &ug_tag);
+                    read.mark_group(top_group.as_bytes(), &ug_tag, num_reads_in_group);
 
                     group_report.num_reads_output_file += 1;
                 });
 
                 output_list.extend(to_write);
+
+                if self.keep_rejects {
+                    rejects.extend(take_duplicates(&mut seq_map).into_iter().map(|mut read| {
+                        read.mark_group(top_group.as_bytes(), &ug_tag, num_reads_in_group);
+                        (read, RejectReason::Duplicate)
+                    }));
+                }
+            } else if self.keep_rejects {
+                let ug_tag = generate_tag(&mut reject_rng, &mut reject_tags);
+                let top_group = top_umi.get_index(0).unwrap();
+                let mut seq_map = self.gather_reads(&top_umi, umis_records)?;
+
+                rejects.extend(take_duplicates(&mut seq_map).into_iter().map(|mut read| {
+                    read.mark_group(top_group.as_bytes(), &ug_tag, num_reads_in_group);
+                    (read, RejectReason::BelowMinDepth)
+                }));
             }
         }
 
-        if group_report.is_blank() {
-            Ok((None, output_list))
-        } else {
-            Ok((Some(group_report), output_list))
+        let group_report = match group_report.is_blank() {
+            true => None,
+            false => Some(group_report),
+        };
+        Ok((group_report, output_list, rejects))
+    }
+
+    // get all the reads across all the umis in the group
+    fn gather_reads<T: SequenceRecord>(
+        &self,
+        top_umi: &IndexSet<smol_str::SmolStr>,
+        umis_records: &mut IndexMap<smol_str::SmolStr, (i32, SeqMap<T>)>,
+    ) -> Result<SeqMap<T>, Error> {
+        let mut top_umi = top_umi.iter();
+        let (_, mut seq_map) = umis_records
+            .swap_remove(top_umi.next().unwrap())
+            .context("FATAL: Attempted to remove UMI that was already sent for output")?;
+
+        let retain = Retain::new(self.group_only, self.keep_rejects);
+        for group in top_umi {
+            seq_map.combine(umis_records.swap_remove(group).unwrap().1, retain);
         }
+
+        Ok(seq_map)
     }
 }
@@ -1,7 +1,8 @@
 use crate::{
     io::{
         fastq_extract_io::FastqIO,
-        fastqio::{IntakeOrdered, ReadPair},
+        fastqio::{is_stdio, IntakeOrdered, ReadPair},
+        samplesheet::read_samplesheet,
     },
     ExtractArgs, FastqQualEncoding, PHRED33, PHRED64, SOLEXA,
 };
@@ -9,6 +10,8 @@ use crate::{
 use anyhow::{Context, Error};
 use bio::io::fastq::Record;
 use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::fs::create_dir_all;
+use std::path::PathBuf;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum ExtractBaseType {
@@ -457,7 +460,7 @@ impl ExtractionWorker {
                 reads_processed += 1;
             }
 
-            println! {"Reads processed: {reads_processed}"};
+            eprintln! {"Reads processed: {reads_processed}"};
 
             Ok(())
         });
@@ -547,14 +550,10 @@ impl ExtractionWorkerPool {
 
 pub fn run_extract(args: &ExtractArgs) -> Result<(), Error> {
     let threads = determine_thread_scheme_from_args(args);
+    let in1 = args.in1.as_ref().context("No FASTQ input given with -i")?;
 
-    let mut fio = FastqIO::new_from_inputs(
-        &args.in1,
-        &args.in2,
-        &args.out1,
-        &args.out2,
-        args.batch_size,
-    )?;
+    let mut fio =
+        FastqIO::new_from_inputs(in1, &args.in2, &args.out1, &args.out2, args.batch_size)?;
     fio.start(threads.compress)?;
 
     let ecache = ExtractionCache::new_from_args(args)?;
@@ -577,6 +576,53 @@ pub fn run_extract(args: &ExtractArgs) -> Result<(), Error> {
     Ok(())
 }
 
+/// Extract each FASTQ sample of a sample sheet in turn, writing outputs named after the sample to
+/// the directory given with -o.
+pub fn run_extract_samples(args: &ExtractArgs, samplesheet: &str) -> Result<(), Error> {
+    let outdir = &args.out1;
+    if is_stdio(outdir) {
+        anyhow::bail!("--samplesheet writes to a directory given with -o, not to stdout")
+    }
+    create_dir_all(outdir)
+        .with_context(|| format!("Unable to create output directory {}", outdir.display()))?;
+
+    for sample in read_samplesheet(samplesheet)? {
+        let Some(r1) = &sample.r1 else {
+            eprintln!("Skipping sample {}: no FASTQ input", sample.name);
+            continue;
+        };
+
+        let mut sample_args = args.clone();
+        sample_args.samplesheet = None;
+        sample_args.in1 = Some(PathBuf::from(r1));
+        sample_args.in2 = sample.r2.as_ref().map(PathBuf::from);
+
+        (sample_args.out1, sample_args.out2) = match &sample.r2 {
+            Some(_) => (
+                outdir.join(format!("{}_R1.fastq.gz", sample.name)),
+                Some(outdir.join(format!("{}_R2.fastq.gz", sample.name))),
+            ),
+            None => (outdir.join(format!("{}.fastq.gz", sample.name)), None),
+        };
+
+        if let Some(pattern) = sample.get_str("pattern") {
+            sample_args.pattern1 = Some(pattern.to_string());
+        }
+        if let Some(pattern) = sample.get_str("pattern2") {
+            sample_args.pattern2 = Some(pattern.to_string());
+        }
+        if let Some(separator) = sample.get("separator")? {
+            sample_args.umi_separator = separator;
+        }
+
+        eprintln!("Extracting sample {}", sample.name);
+        run_extract(&sample_args)
+            .with_context(|| format!("Failed to extract sample {}", sample.name))?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
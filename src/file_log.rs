@@ -0,0 +1,137 @@
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+thread_local! {
+    // the log of the file this thread works on, if files are deduplicated in parallel
+    static FILE_LOG: RefCell<Option<FileLog>> = const { RefCell::new(None) };
+}
+
+/// Log of one input file, written when files are deduplicated in parallel (`--parallel-files`).
+/// Log records and status messages of threads working on the file go here, rather than to the
+/// shared log and the console.
+#[derive(Clone)]
+pub struct FileLog(Arc<Mutex<File>>);
+
+impl FileLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self(Arc::new(Mutex::new(File::create(path)?))))
+    }
+
+    /// Route log records and status messages of the current thread to this log.
+    pub fn enter(&self) {
+        FILE_LOG.with(|log| *log.borrow_mut() = Some(self.clone()));
+    }
+
+    fn current() -> Option<Self> {
+        FILE_LOG.with(|log| log.borrow().clone())
+    }
+}
+
+/// Log sink passed to [simple_logging]: records go to the log of the file the logging thread
+/// works on, if any, or else to the shared log.
+pub struct RoutedLog(File);
+
+impl RoutedLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self(File::create(path)?))
+    }
+}
+
+impl Write for RoutedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match FileLog::current() {
+            Some(FileLog(file)) => file.lock().write(buf),
+            None => self.0.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match FileLog::current() {
+            Some(FileLog(file)) => file.lock().flush(),
+            None => self.0.flush(),
+        }
+    }
+}
+
+/// Print a status message to stderr, or, if the current thread works on a file deduplicated in
+/// parallel, write it (without colors) to the file's log. Use through [crate::status].
+pub fn print_status(args: fmt::Arguments) {
+    match FileLog::current() {
+        Some(FileLog(file)) => {
+            writeln!(file.lock(), "{}", strip_colors(&args.to_string())).ok();
+        }
+        None => eprintln!("{args}"),
+    }
+}
+
+/// `eprintln!` for messages about the file being processed. See [print_status].
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::file_log::print_status(format_args!($($arg)*))
+    };
+}
+
+/// Spawn a thread that logs to the same file log as the current thread.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let log = FileLog::current();
+    thread::spawn(move || {
+        if let Some(log) = log {
+            log.enter();
+        }
+        f()
+    })
+}
+
+// remove ANSI color codes, as written by [colored]
+fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                chars.by_ref().find(|c| *c == 'm');
+            }
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_file_log_routing() {
+        let dir = TempDir::new("file_log");
+        let path = dir.join("a.log");
+
+        let log = FileLog::create(&path).unwrap();
+        spawn(move || {
+            log.enter();
+            // threads spawned from a file's thread log to the same file
+            spawn(|| status!("{}: {}", "\x1b[36mOutput reads\x1b[0m", 36))
+                .join()
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Output reads: 36\n"
+        );
+    }
+}
@@ -1,38 +1,69 @@
+use crate::io::regions::Region;
+use crate::read_filter::FilterCounts;
+use crate::read_groups::ReadGroups;
+use anyhow::{Context, Error};
 use colored::Colorize;
 use num_format::{Locale, ToFormattedString};
+use smol_str::SmolStr;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::path;
 
 const LOCALE: Locale = Locale::en;
 
+const REPORT_COLUMNS: &str = concat!(
+    "num_reads_input_file\t",
+    "num_reads_output_file\t",
+    "num_total_barcodes\t",
+    "num_total_groups\t",
+    "num_passing_groups\t",
+    "min_reads_group\t",
+    "min_reads_per_group\t",
+    "max_reads_group\t",
+    "max_reads_per_group\t",
+    "num_filtered_required_flags\t",
+    "num_filtered_excluded_flags\t",
+    "num_filtered_mapq\t",
+    "num_filtered_secondary\t",
+    "num_filtered_supplementary\t",
+    "num_following_primary\t",
+    "num_followers_output\t",
+    "num_unmapped_reads\n"
+);
+
 // This report contains details like UMIs in/out, reads in/out, and other details, and is updated
 // after the deduplication of each batch.
 pub struct GroupReport {
     pub min_reads_per_group: i64,
     pub max_reads_per_group: i64,
-    pub min_reads_group: [u8; 8],
-    pub max_reads_group: [u8; 8],
+    pub min_reads_group: SmolStr,
+    pub max_reads_group: SmolStr,
     pub num_passing_groups: i64,
     pub num_groups: i64,
     pub num_umis: i64,
     pub num_reads_input_file: i64,
     pub num_reads_output_file: i64,
+    // reads kept out of grouping by read filters (BAM only)
+    pub filtered: FilterCounts,
+    // unmapped reads without a reference, handled as given by --unmapped (BAM only)
+    pub num_unmapped: i64,
 }
 
 impl GroupReport {
     pub fn new() -> Self {
         GroupReport {
             min_reads_per_group: i64::MAX,
-            min_reads_group: *b"NONENONE",
+            min_reads_group: SmolStr::new_static("NONENONE"),
             max_reads_per_group: 0,
-            max_reads_group: *b"NONENONE",
+            max_reads_group: SmolStr::new_static("NONENONE"),
             num_passing_groups: 0,
             num_groups: 0,
             num_umis: 0,
             num_reads_input_file: 0,
             num_reads_output_file: 0,
+            filtered: FilterCounts::default(),
+            num_unmapped: 0,
         }
     }
 
@@ -42,15 +73,15 @@ impl GroupReport {
     }
 
     // after a batch has been processed, check to see if fields need to be udpated
-    pub fn update(&mut self, other_report: GroupReport, num_umis: i32) {
+    pub fn update(&mut self, other_report: &GroupReport, num_umis: i32) {
         if other_report.max_reads_per_group > self.max_reads_per_group {
             self.max_reads_per_group = other_report.max_reads_per_group;
-            self.max_reads_group = other_report.max_reads_group;
+            self.max_reads_group = other_report.max_reads_group.clone();
         }
 
         if other_report.min_reads_per_group < self.min_reads_per_group {
             self.min_reads_per_group = other_report.min_reads_per_group;
-            self.min_reads_group = other_report.min_reads_group;
+            self.min_reads_group = other_report.min_reads_group.clone();
         }
 
         // count the number of UMI groups used in consensus
@@ -62,65 +93,183 @@ impl GroupReport {
         self.num_reads_output_file += other_report.num_reads_output_file;
     }
 
+    // combine the report of another set of batches, e.g. those of a region, into this one
+    pub fn merge(&mut self, other: &GroupReport) {
+        if other.max_reads_per_group > self.max_reads_per_group {
+            self.max_reads_per_group = other.max_reads_per_group;
+            self.max_reads_group = other.max_reads_group.clone();
+        }
+
+        if other.min_reads_per_group < self.min_reads_per_group {
+            self.min_reads_per_group = other.min_reads_per_group;
+            self.min_reads_group = other.min_reads_group.clone();
+        }
+
+        self.num_passing_groups += other.num_passing_groups;
+        self.num_groups += other.num_groups;
+        self.num_umis += other.num_umis;
+        self.num_reads_input_file += other.num_reads_input_file;
+        self.num_reads_output_file += other.num_reads_output_file;
+        self.filtered.merge(&other.filtered);
+        self.num_unmapped += other.num_unmapped;
+    }
+
     // once deduplication of the file is complete, only list UMIs that were observed more than
     // once.
     pub fn write_to_report_file(&mut self, output_file: &str) {
-        let outdir = path::Path::parent(path::Path::new(output_file))
-            .unwrap_or(path::Path::new("."))
-            .to_str()
-            .unwrap_or(".");
+        let mut report_f = open_report_file(output_file, "rumina_report");
 
-        let outname = path::Path::new(output_file)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap_or("1.")
-            .split_once(".")
-            .unwrap_or(("1", ""))
-            .0;
+        let _ = report_f.write(REPORT_COLUMNS.as_bytes());
+        let _ = report_f.write(self.tsv_row().as_bytes());
+    }
+
+    /// Read back the report written next to an output file by [GroupReport::write_to_report_file].
+    pub fn read_report_file(output_file: &str) -> Result<Self, Error> {
+        let report_file = report_file_name(output_file, "rumina_report");
+        let text = fs::read_to_string(&report_file)
+            .with_context(|| format!("Failed to read report {report_file}"))?;
+        text.lines()
+            .nth(1)
+            .and_then(GroupReport::from_tsv_row)
+            .with_context(|| format!("Invalid report {report_file}"))
+    }
 
-        let report_file = format!("{}/{}_rumina_report.tsv", outdir, outname);
+    /// Read back a report written by [GroupReport::tsv_row], e.g. from a checkpoint.
+    pub fn from_tsv_row(row: &str) -> Option<Self> {
+        let fields: Vec<&str> = row.trim_end().split('\t').collect();
+        if fields.len() != REPORT_COLUMNS.split('\t').count() {
+            return None;
+        }
+        let num = |i: usize| fields[i].parse::<i64>().ok();
+        let group = |i: usize| Some(SmolStr::from(fields[i]));
 
-        let _ = File::create(&report_file);
+        let mut report = GroupReport {
+            num_reads_input_file: num(0)?,
+            num_reads_output_file: num(1)?,
+            num_umis: num(2)?,
+            num_groups: num(3)?,
+            num_passing_groups: num(4)?,
+            min_reads_group: group(5)?,
+            min_reads_per_group: num(6)?,
+            max_reads_group: group(7)?,
+            max_reads_per_group: num(8)?,
+            filtered: FilterCounts {
+                required_flags: num(9)?,
+                excluded_flags: num(10)?,
+                mapq: num(11)?,
+                secondary: num(12)?,
+                supplementary: num(13)?,
+                following_primary: num(14)?,
+                followers_output: num(15)?,
+            },
+            num_unmapped: num(16)?,
+        };
+        // blank reports are written with a minimum of 0
+        if report.is_blank() {
+            report.min_reads_per_group = i64::MAX;
+        }
+        Some(report)
+    }
 
-        let mut report_f = OpenOptions::new()
-            .append(true)
-            .open(report_file)
-            .expect("unable to open minmax file");
+    pub fn tsv_row(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.num_reads_input_file,
+            self.num_reads_output_file,
+            self.num_umis,
+            self.num_groups,
+            self.num_passing_groups,
+            self.min_reads_group,
+            // regions may hold no groups at all
+            if self.is_blank() {
+                0
+            } else {
+                self.min_reads_per_group
+            },
+            self.max_reads_group,
+            self.max_reads_per_group,
+            self.filtered.required_flags,
+            self.filtered.excluded_flags,
+            self.filtered.mapq,
+            self.filtered.secondary,
+            self.filtered.supplementary,
+            self.filtered.following_primary,
+            self.filtered.followers_output,
+            self.num_unmapped,
+        )
+    }
+}
 
-        let _ = report_f.write(
-            concat!(
-                "num_reads_input_file\t",
-                "num_reads_output_file\t",
-                "num_total_barcodes\t",
-                "num_total_groups\t",
-                "num_passing_groups\t",
-                "min_reads_group\t",
-                "min_reads_per_group\t",
-                "max_reads_group\t",
-                "max_reads_per_group\n"
-            )
-            .as_bytes(),
-        );
+/// Write one report row per region, with BED coordinates, next to the report of the whole file.
+pub fn write_region_report_file(output_file: &str, regions: &[Region], reports: &[GroupReport]) {
+    let mut report_f = open_report_file(output_file, "rumina_region_report");
 
+    let _ = report_f.write(format!("region\tcontig\tstart\tend\t{REPORT_COLUMNS}").as_bytes());
+    for (region, report) in regions.iter().zip(reports) {
         let _ = report_f.write(
             format!(
-                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
-                self.num_reads_input_file,
-                self.num_reads_output_file,
-                self.num_umis,
-                self.num_groups,
-                self.num_passing_groups,
-                String::from_utf8(self.min_reads_group.to_vec()).unwrap(),
-                self.min_reads_per_group,
-                String::from_utf8(self.max_reads_group.to_vec()).unwrap(),
-                self.max_reads_per_group,
+                "{}\t{}\t{}\t{}\t{}",
+                region.name,
+                region.contig,
+                region.start,
+                region.end,
+                report.tsv_row()
             )
             .as_bytes(),
         );
     }
 }
 
+/// Write one report row per read group, or per library, with `--per-read-group`. Input reads are
+/// those grouped in the pool, which is named along with its kind (`read_group` or `library`). Reads
+/// without a known read group are reported as `none`, if any.
+pub fn write_read_group_report_file(
+    output_file: &str,
+    read_groups: &ReadGroups,
+    mut reports: Vec<GroupReport>,
+) {
+    reports.resize_with(read_groups.num_pools(), GroupReport::new);
+    let mut report_f = open_report_file(output_file, "rumina_read_group_report");
+
+    let _ = report_f.write(format!("read_group\tpool\t{REPORT_COLUMNS}").as_bytes());
+    for (pool, ((kind, name), report)) in read_groups.names.iter().zip(&reports).enumerate() {
+        if pool == 0 && report.num_reads_input_file == 0 {
+            continue;
+        }
+        let _ = report_f.write(format!("{}\t{}\t{}", name, kind, report.tsv_row()).as_bytes());
+    }
+}
+
+// reports are named after the output file, minus its extensions
+fn report_file_name(output_file: &str, suffix: &str) -> String {
+    let outdir = path::Path::parent(path::Path::new(output_file))
+        .unwrap_or(path::Path::new("."))
+        .to_str()
+        .unwrap_or(".");
+
+    let outname = path::Path::new(output_file)
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap_or("1.")
+        .split_once(".")
+        .unwrap_or(("1", ""))
+        .0;
+
+    format!("{}/{}_{}.tsv", outdir, outname, suffix)
+}
+
+fn open_report_file(output_file: &str, suffix: &str) -> File {
+    let report_file = report_file_name(output_file, suffix);
+
+    let _ = File::create(&report_file);
+
+    OpenOptions::new()
+        .append(true)
+        .open(report_file)
+        .expect("unable to open minmax file")
+}
+
 // printed after file completion
 impl fmt::Debug for GroupReport {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -169,6 +318,35 @@ impl fmt::Display for GroupReport {
             self.num_reads_input_file.to_formatted_string(&LOCALE),
             "Output reads".cyan(),
             self.num_reads_output_file.to_formatted_string(&LOCALE)
-        )
+        )?;
+
+        let filtered = &self.filtered;
+        if filtered.num_discarded() > 0 || filtered.following_primary > 0 {
+            write!(
+                f,
+                "\n{}: {} (flags: {}, MAPQ: {}, secondary: {}, supplementary: {})\n\
+                {}: {} of {}",
+                "Reads filtered".cyan(),
+                filtered.num_discarded().to_formatted_string(&LOCALE),
+                (filtered.required_flags + filtered.excluded_flags).to_formatted_string(&LOCALE),
+                filtered.mapq.to_formatted_string(&LOCALE),
+                filtered.secondary.to_formatted_string(&LOCALE),
+                filtered.supplementary.to_formatted_string(&LOCALE),
+                "Alignments output with their primary".cyan(),
+                filtered.followers_output.to_formatted_string(&LOCALE),
+                filtered.following_primary.to_formatted_string(&LOCALE),
+            )?;
+        }
+
+        if self.num_unmapped > 0 {
+            write!(
+                f,
+                "\n{}: {}",
+                "Unmapped reads without a reference".cyan(),
+                self.num_unmapped.to_formatted_string(&LOCALE)
+            )?;
+        }
+
+        Ok(())
     }
 }
@@ -1,5 +1,11 @@
+pub mod bam_input;
 pub mod bam_io;
 pub mod bam_reader;
+pub mod bam_sorter;
+pub mod program;
+pub mod regions;
+pub mod samplesheet;
+pub mod shards;
 
 pub mod fastq_dedup_io;
 pub mod fastq_extract_io;
@@ -0,0 +1,260 @@
+use crate::cli::dedup_args::DEFAULT_MAX_MEMORY_MB;
+use crate::cli::{AlignmentFormat, DedupArgs};
+use crate::io::bam_sorter::sort_bam_to;
+use crate::io::fastqio::STDIO_PATH;
+use crate::spill::SpillConfig;
+use crate::status;
+use crate::utils::{_index_bam, index_bam_to};
+use anyhow::{Context, Error};
+use rust_htslib::bam::{self, IndexedReader};
+use std::fs::remove_file;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// An alignment input, ready to be read by position: sorted, and indexed. Inputs that aren't are
+/// prepared before reading (see [BamInput::prepare]); files made for this are removed once the
+/// input is dropped.
+#[derive(Debug)]
+pub struct BamInput {
+    pub path: String,
+    // the input as given, of which `path` may be a sorted copy
+    pub source: String,
+    // the index, if not next to the input
+    pub index: Option<String>,
+    temp_files: Vec<String>,
+}
+
+impl BamInput {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            source: path.to_string(),
+            index: None,
+            temp_files: Vec::new(),
+        }
+    }
+
+    /// Check that an alignment input can be read, and make it readable by position if needed:
+    /// - inputs with an index are read as they are, as are streams from stdin (`-`)
+    /// - sorted inputs without one are indexed; the index is written next to the input, or to the
+    ///   output directory if that fails (e.g. if the input's directory is read-only)
+    /// - unsorted inputs (and SAM, which can't be indexed) are sorted to a temporary copy in
+    ///   `--tmpdir` or the output directory, and indexed
+    pub fn prepare(args: &DedupArgs, path: &str) -> Result<Self, Error> {
+        // stdin can only be read once, so its reference is checked as it is opened for reading
+        if path == STDIO_PATH {
+            return Ok(Self::new(path));
+        }
+
+        let mut reader = bam::Reader::from_path(path)
+            .with_context(|| format!("Failed to open alignment input {path}"))?;
+        if let Some(reference) = &args.reference {
+            reader
+                .set_reference(reference)
+                .with_context(|| format!("Failed to load reference {reference}"))?;
+        }
+        drop(reader);
+
+        if has_index(path) && IndexedReader::from_path(path).is_ok() {
+            return Ok(Self::new(path));
+        }
+
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let format = AlignmentFormat::from_path(path).unwrap_or(AlignmentFormat::Bam);
+        let mut input = Self::new(path);
+        // output to stdout has no directory to prepare input in
+        let outdir = match args.outdir == STDIO_PATH {
+            true => std::env::temp_dir(),
+            false => PathBuf::from(&args.outdir),
+        };
+
+        if format != AlignmentFormat::Sam {
+            status!("Input {name} has no index. Indexing...");
+            if _index_bam(path, args.threads, args.csi).is_ok() {
+                return Ok(input);
+            }
+            let idx_stem = outdir.join(&name).to_string_lossy().to_string();
+            if let Ok(index) = index_bam_to(path, &idx_stem, args.threads, args.csi) {
+                status!("Could not write an index next to {name}; wrote {index} instead");
+                input.temp_files.push(index.clone());
+                input.index = Some(index);
+                return Ok(input);
+            }
+        }
+
+        // e.g. unsorted, or not BGZF-compressed. SAM can't be indexed, so is sorted to BAM
+        status!("Input {name} can't be indexed as it is. Sorting a copy...");
+        let out_format = match format {
+            AlignmentFormat::Sam => AlignmentFormat::Bam,
+            format => format,
+        };
+        let tmpdir = args.tmpdir.as_ref().map_or(outdir, PathBuf::from);
+        // named after the process and the full input path too, so that runs sharing a --tmpdir, or
+        // inputs of the same name in different directories, don't sort to the same copy
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let sorted = tmpdir
+            .join(format!(
+                "{}_SORTED_{}_{:x}.{}",
+                Path::new(&name).with_extension("").to_string_lossy(),
+                std::process::id(),
+                hasher.finish(),
+                out_format.extension()
+            ))
+            .to_string_lossy()
+            .to_string();
+        input.temp_files.push(sorted.clone());
+        let config = SpillConfig {
+            tmpdir,
+            max_memory: args.max_memory.unwrap_or(DEFAULT_MAX_MEMORY_MB) * 1024 * 1024,
+        };
+        let context =
+            || format!("Input {name} is neither indexed nor sorted, and could not be sorted");
+        sort_bam_to(
+            path,
+            &sorted,
+            out_format,
+            args.threads,
+            &config,
+            args.reference.as_deref(),
+        )
+        .with_context(context)?;
+        let index = _index_bam(&sorted, args.threads, args.csi).with_context(context)?;
+        input.temp_files.push(index);
+
+        input.path = sorted;
+        Ok(input)
+    }
+}
+
+// whether an index file lies next to an input, where htslib looks for it. Checked before opening
+// the input with its index, which logs an error if there is none.
+fn has_index(path: &str) -> bool {
+    let stem = Path::new(path).with_extension("");
+    ["bai", "csi", "crai"].iter().any(|ext| {
+        Path::new(&format!("{path}.{ext}")).is_file() || stem.with_extension(ext).is_file()
+    })
+}
+
+impl Drop for BamInput {
+    fn drop(&mut self) {
+        for file in &self.temp_files {
+            remove_file(file).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mapped_read, test_header, write_bam, write_indexed_bam, TempDir};
+    use crate::utils::make_bam_reader;
+    use rust_htslib::bam::{Read, Record};
+    use std::fs;
+
+    fn reads(positions: &[i64]) -> Vec<Record> {
+        positions
+            .iter()
+            .enumerate()
+            .map(|(i, pos)| mapped_read(&format!("read{i}"), 0, *pos, "10M", false))
+            .collect()
+    }
+
+    // prepare an input with an output directory of its own, returning the positions of its reads
+    // as read back by position
+    fn prepare(dir: &TempDir, input: &Path) -> (BamInput, Vec<i64>) {
+        let outdir = dir.join("out");
+        fs::create_dir_all(&outdir).unwrap();
+        let args = DedupArgs::for_test(input.to_str().unwrap(), outdir.to_str().unwrap());
+
+        let input = BamInput::prepare(&args, input.to_str().unwrap()).unwrap();
+        let (_, mut reader) =
+            make_bam_reader(&input.path, input.index.as_deref(), 1, None).unwrap();
+        reader.fetch("chr1").unwrap();
+        let positions = reader.records().map(|read| read.unwrap().pos()).collect();
+        (input, positions)
+    }
+
+    #[test]
+    fn test_indexed_input_read_as_is() {
+        let dir = TempDir::new("bam_input_indexed");
+        let path = dir.join("input.bam");
+        write_indexed_bam(&path, &test_header(), reads(&[10, 20]));
+
+        let (input, positions) = prepare(&dir, &path);
+        assert_eq!(input.path, path.to_str().unwrap());
+        assert_eq!(input.index, None);
+        assert!(input.temp_files.is_empty());
+        assert_eq!(positions, [10, 20]);
+    }
+
+    #[test]
+    fn test_sorted_input_indexed_next_to_it() {
+        let dir = TempDir::new("bam_input_sorted");
+        let path = dir.join("input.bam");
+        write_bam(&path, &test_header(), reads(&[10, 20]));
+
+        let (input, positions) = prepare(&dir, &path);
+        assert_eq!(input.path, path.to_str().unwrap());
+        assert_eq!(input.index, None);
+        assert_eq!(positions, [10, 20]);
+
+        // an index made next to the input is kept for later runs
+        drop(input);
+        assert!(dir.join("input.bam.bai").is_file());
+    }
+
+    #[test]
+    fn test_sorted_input_indexed_to_outdir() {
+        let dir = TempDir::new("bam_input_read_only");
+        let path = dir.join("input.bam");
+        write_bam(&path, &test_header(), reads(&[10, 20]));
+        // no index can be written next to the input, as if its directory were read-only
+        fs::create_dir(dir.join("input.bam.bai")).unwrap();
+
+        let (input, positions) = prepare(&dir, &path);
+        let index = dir.join("out").join("input.bam.bai");
+        assert_eq!(input.path, path.to_str().unwrap());
+        assert_eq!(input.index.as_deref(), index.to_str());
+        assert_eq!(positions, [10, 20]);
+
+        drop(input);
+        assert!(!index.exists());
+    }
+
+    #[test]
+    fn test_unsorted_input_sorted_to_temp_copy() {
+        let dir = TempDir::new("bam_input_unsorted");
+        let path = dir.join("input.bam");
+        write_bam(&path, &test_header(), reads(&[30, 10, 20]));
+
+        let (input, positions) = prepare(&dir, &path);
+        let sorted = PathBuf::from(&input.path);
+        assert_eq!(sorted.parent(), Some(dir.join("out").as_path()));
+        assert!(input.path.ends_with(".bam"));
+        assert_eq!(positions, [10, 20, 30]);
+
+        drop(input);
+        assert!(!sorted.exists());
+        assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_sam_input_sorted_to_bam() {
+        let dir = TempDir::new("bam_input_sam");
+        let path = dir.join("input.sam");
+        let mut writer = bam::Writer::from_path(&path, &test_header(), bam::Format::Sam).unwrap();
+        for read in reads(&[10, 20]) {
+            writer.write(&read).unwrap();
+        }
+        drop(writer);
+
+        let (input, positions) = prepare(&dir, &path);
+        assert!(input.path.ends_with(".bam"));
+        assert_ne!(input.path, path.to_str().unwrap());
+        assert_eq!(positions, [10, 20]);
+    }
+}
@@ -1,87 +1,445 @@
-use crate::cli::DedupArgs;
+use crate::checkpoint::Checkpoint;
+use crate::cli::dedup_args::DEFAULT_MAX_MEMORY_MB;
+use crate::cli::{AlignmentFormat, DedupArgs, OutputSort, UnmappedReads};
+use crate::io::bam_input::BamInput;
+use crate::io::bam_reader::WindowLimit;
+use crate::io::bam_sorter::{coordinate_key, with_sort_order, BamSorter};
+use crate::io::fastqio::STDIO_PATH;
+use crate::io::program::{command_line, with_program};
+use crate::io::regions::parse_regions;
+use crate::io::shards::shard_regions;
 use crate::io::{FileIO, WindowedBamReader};
+use crate::read_filter::ReadFilter;
 use crate::record::BamRecord;
-use crate::utils::{make_bam_reader, make_bam_writer};
+use crate::rejects::{write_reject, RejectReason};
+use crate::spill::SpillConfig;
+use crate::utils::{make_bam_reader, make_bam_writer, Window};
+use anyhow::Error;
 use indexmap::IndexSet;
 use log::info;
 use rayon::prelude::ParallelSliceMut;
-use rust_htslib::bam::{IndexedReader, Read, Writer};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{HeaderView, IndexedReader, Read, Writer};
 use std::cmp;
 
 pub struct BamIO {
     pub windowed_reader: WindowedBamReader,
-    pub mate_reader: Option<IndexedReader>,
-    pub writer: Writer,
+    pub output: BamOutput,
     pub num_threads: usize,
     pub _window_size: Option<i64>,
     pub _separator: String,
 }
 
+/// The writing half of [BamIO], which retrieves mates of output reads if needed. It is kept apart
+/// from the windowed reader so that reading and writing can happen on separate threads; the
+/// window of the reads being written is therefore tracked here as well.
+///
+/// With a [BamSorter], reads are sorted on their way out rather than written as they come, so
+/// output is sorted even if written out of order (e.g. mates retrieved with `--paired`), or in an
+/// order other than by coordinate.
+///
+/// Secondary and supplementary alignments following their primary (see [ReadFilter]) are held in
+/// a sorter of their own until all primaries are written, and then added to the output if theirs
+/// was, with its group tags. Written primaries are noted in the same sorter, as bare records
+/// carrying their group tags, so that both are sorted together by read name and spilled alike.
+///
+/// Unmapped reads without a reference are written last, to their own file if one is given.
+/// Rejected reads (see `--rejects-out`) are written unsorted to a file of their own.
+///
+/// With a [Checkpoint], reads are written to its part files, which are concatenated into the
+/// output file once all are written.
+pub struct BamOutput {
+    pub writer: Writer,
+    unmapped: Option<Writer>,
+    rejects: Option<Writer>,
+    pub mate_reader: Option<IndexedReader>,
+    pub order: OutputSort,
+    pub filter: ReadFilter,
+    sorter: Option<BamSorter>,
+    followers: Option<BamSorter>,
+    target_lens: Vec<u64>,
+    pub checkpoint: Option<Checkpoint>,
+    pub cur_ref: u32,
+    pub cur_window: Window,
+}
+
 impl BamIO {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        infile_name: &str,
+        input: &BamInput,
         outfile_name: &str,
         retrieve_r2s: bool,
         num_threads: usize,
         strict_threads: bool,
         _window_size: Option<i64>,
+        window_limit: WindowLimit,
+        order: OutputSort,
+        sort_config: Option<SpillConfig>,
+        filter: ReadFilter,
+        unmapped_file: Option<&str>,
+        rejects_file: Option<&str>,
+        format: AlignmentFormat,
+        reference: Option<&str>,
+        header_comments: &[String],
         _separator: String,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let num_threads = match strict_threads {
             true => num_threads,
             false => num_cpus::get(),
         };
-        let windowed_reader = WindowedBamReader::new(infile_name, num_threads, _window_size);
-        let writer = make_bam_writer(
-            outfile_name,
-            windowed_reader.raw_header.clone(),
+        let windowed_reader = WindowedBamReader::new(
+            &input.path,
+            input.index.as_deref(),
             num_threads,
+            _window_size,
+            window_limit,
+            reference,
+        )?;
+        // output records how it was made
+        let command_line = command_line();
+        let header = with_program(
+            &with_sort_order(&windowed_reader.raw_header, order),
+            &command_line,
+            header_comments,
         );
+
+        let writer = make_bam_writer(outfile_name, header.clone(), num_threads, format, reference);
+        let unmapped = unmapped_file.map(|unmapped_file| {
+            make_bam_writer(
+                unmapped_file,
+                header.clone(),
+                num_threads,
+                format,
+                reference,
+            )
+        });
+        let rejects = rejects_file.map(|rejects_file| {
+            make_bam_writer(
+                rejects_file,
+                with_program(
+                    &with_sort_order(&windowed_reader.raw_header, OutputSort::Unsorted),
+                    &command_line,
+                    header_comments,
+                ),
+                num_threads,
+                AlignmentFormat::from_path(rejects_file).unwrap_or(AlignmentFormat::Bam),
+                reference,
+            )
+        });
         let mate_reader = match retrieve_r2s {
-            true => Some(make_bam_reader(infile_name, num_threads).1),
+            true => Some(
+                make_bam_reader(&input.path, input.index.as_deref(), num_threads, reference)?.1,
+            ),
             false => None,
         };
 
-        Self {
-            windowed_reader,
-            mate_reader,
+        let name = outfile_name.replace('/', "_");
+        let followers = match (&sort_config, filter.follows_primary()) {
+            (Some(config), true) => Some(BamSorter::new(
+                config,
+                &format!("{name}_followers"),
+                header.clone(),
+                OutputSort::Queryname,
+            )?),
+            _ => None,
+        };
+        let sorter = match sort_config {
+            Some(config) => Some(BamSorter::new(&config, &name, header, order)?),
+            None => None,
+        };
+
+        let output = BamOutput {
             writer,
+            unmapped,
+            rejects,
+            mate_reader,
+            order,
+            filter,
+            sorter,
+            followers,
+            target_lens: windowed_reader.target_lens.clone(),
+            checkpoint: None,
+            cur_ref: 0,
+            cur_window: Window { start: 0, end: 0 },
+        };
+
+        Ok(Self {
+            windowed_reader,
+            output,
             num_threads,
             _window_size,
             _separator,
-        }
+        })
     }
 
-    pub fn init_from_args(args: &DedupArgs, infile_path: &str, outfile_path: &str) -> Self {
-        Self::new(
-            infile_path,
+    pub fn init_from_args(
+        args: &DedupArgs,
+        input: &BamInput,
+        outfile_path: &str,
+    ) -> Result<Self, Error> {
+        // only coordinate order can be kept while writing window by window. With spilling,
+        // partitions of a window are written in no particular order as each is grouped, so output
+        // is sorted on its way out then as well. Sorted runs are spilled to --tmpdir if given,
+        // otherwise next to the output (or to the system temporary directory, if output goes to
+        // stdout). Alignments following their primary are added once all reads are grouped, so
+        // they are sorted in as well.
+        let to_stdout = outfile_path == STDIO_PATH;
+        let filter = ReadFilter::init_from_args(args);
+        // the output format follows the output file's extension, if it has one
+        let format = AlignmentFormat::from_path(outfile_path)
+            .or(args.output_format)
+            .unwrap_or(AlignmentFormat::Bam);
+        let needs_sorter = filter.follows_primary()
+            || match args.output_sort {
+                // unsorted output is sorted again before indexing, but a stream can't be
+                OutputSort::Coordinate => {
+                    args.ensure_sorted || args.tmpdir.is_some() || (to_stdout && args.paired)
+                }
+                OutputSort::Queryname | OutputSort::TemplateCoordinate => true,
+                OutputSort::Unsorted => false,
+            };
+        let sort_config = needs_sorter.then(|| SpillConfig {
+            tmpdir: match (&args.tmpdir, to_stdout) {
+                (Some(tmpdir), _) => tmpdir.into(),
+                (None, true) => std::env::temp_dir(),
+                (None, false) => args.outdir.as_str().into(),
+            },
+            max_memory: args.max_memory.unwrap_or(DEFAULT_MAX_MEMORY_MB) * 1024 * 1024,
+        });
+
+        // e.g. sample_RUMINA.bam -> sample_RUMINA_unmapped.bam
+        let unmapped_file = (args.unmapped_reads() == UnmappedReads::Separate).then(|| {
+            let stem = outfile_path
+                .strip_suffix(&format!(".{}", format.extension()))
+                .unwrap_or(outfile_path);
+            format!("{stem}_unmapped.{}", format.extension())
+        });
+
+        // merge-shards checks that shards were made from the same input with the same options
+        let header_comments = match args.shard {
+            Some(_) => args.fingerprint(&input.source),
+            None => args.header_comments(),
+        };
+        let mut bam_io = Self::new(
+            input,
             outfile_path,
             args.paired,
             args.threads,
             args.strict_threads,
             args.split_window,
+            WindowLimit {
+                max_reads: args.max_reads_per_window,
+                max_memory: args.max_memory.map(|mb| mb * 1024 * 1024),
+                max_clip: args.max_clip,
+            },
+            args.output_sort,
+            sort_config,
+            filter,
+            unmapped_file.as_deref(),
+            args.rejects_out.as_deref(),
+            format,
+            args.reference.as_deref(),
+            &header_comments,
             args.separator.clone(),
-        )
+        )?;
+
+        if args.checkpoint || args.resume {
+            bam_io.output.checkpoint = Some(Checkpoint::open(
+                outfile_path,
+                bam_io.windowed_reader.raw_header.clone(),
+                bam_io.num_threads,
+                &args.fingerprint(&input.source),
+                args.resume,
+            )?);
+        }
+
+        if !args.region.is_empty() || args.regions_bed.is_some() {
+            let header = HeaderView::from_header(&bam_io.windowed_reader.raw_header);
+            let regions = parse_regions(&args.region, args.regions_bed.as_deref(), &header)?;
+            bam_io.windowed_reader.set_regions(regions)?;
+        }
+
+        if let Some(shard) = args.shard {
+            let header = HeaderView::from_header(&bam_io.windowed_reader.raw_header);
+            let read_counts = bam_io.windowed_reader.mapped_counts();
+            let regions = shard_regions(shard, &header, &read_counts)?;
+            info!("Shard {shard}: {} region(s)", regions.len());
+            bam_io.windowed_reader.set_regions(regions)?;
+        }
+
+        Ok(bam_io)
+    }
+}
+
+impl BamOutput {
+    /// Whether reads are sorted on their way out, so they may be written in any order.
+    pub fn sorts(&self) -> bool {
+        self.sorter.is_some()
+    }
+
+    /// Hold secondary and supplementary alignments until it is known whether their primary is
+    /// output.
+    pub fn hold(&mut self, followers: Vec<BamRecord>) -> Result<(), Error> {
+        if let Some(held) = &mut self.followers {
+            for read in followers {
+                held.push(read)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write out any reads still held by the sorter, after adding held alignments whose primary
+    /// was written. Returns the number of those alignments. The writer is dropped once this
+    /// returns.
+    pub fn finish(mut self) -> Result<i64, Error> {
+        let mut followers_output = 0;
+
+        if let (Some(held), Some(sorter)) = (self.followers.take(), &mut self.sorter) {
+            // alignments of one read come together, with a note of its primary if written
+            let mut read_id: Option<(Vec<u8>, bool)> = None;
+            let mut primary: Option<BamRecord> = None;
+            let mut following: Vec<BamRecord> = Vec::new();
+
+            held.merge(|read| {
+                let id = (read.qname().to_vec(), read.is_last_in_template());
+                if read_id.as_ref() != Some(&id) {
+                    followers_output +=
+                        add_followers(sorter, primary.take().as_ref(), &mut following)?;
+                    read_id = Some(id);
+                }
+
+                match read.is_secondary() || read.is_supplementary() {
+                    true => following.push(read),
+                    false => primary = Some(read),
+                }
+                Ok(())
+            })?;
+            followers_output += add_followers(sorter, primary.as_ref(), &mut following)?;
+        }
+
+        if let Some(sorter) = self.sorter.take() {
+            let count = sorter.finish(&mut self.writer)?;
+            info!("Written {count} sorted reads!")
+        }
+
+        self.close_part()?;
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.concatenate(&mut self.writer)?;
+        }
+
+        Ok(followers_output)
+    }
+
+    /// With a [Checkpoint], called as writing moves on to another reference: close the part
+    /// being written if it's full, and open another if none is.
+    pub fn next_part(&mut self) -> Result<(), Error> {
+        if self
+            .checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.part_is_full())
+        {
+            self.close_part()?;
+        }
+
+        if let Some(checkpoint) = &mut self.checkpoint {
+            if checkpoint.output.is_none() {
+                let part = checkpoint.open_part();
+                checkpoint.output = Some(std::mem::replace(&mut self.writer, part));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// With a [Checkpoint], close the part being written, if any, as holding references up to
+    /// the current one.
+    fn close_part(&mut self) -> Result<(), Error> {
+        if let Some(checkpoint) = &mut self.checkpoint {
+            if let Some(output) = checkpoint.output.take() {
+                // the part's writer is dropped, and so closed, before it is recorded
+                drop(std::mem::replace(&mut self.writer, output));
+                checkpoint.record_part(self.cur_ref)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // note a written primary alignment, for alignments following it: a record with its name,
+    // flags and group tags (BX, UG), if tagged.
+    fn mark_written(&mut self, read: &BamRecord) -> Result<(), Error> {
+        if read.is_secondary() || read.is_supplementary() {
+            return Ok(());
+        }
+
+        if let Some(held) = &mut self.followers {
+            let mut note = BamRecord::new();
+            note.set(read.qname(), None, &[], &[]);
+            note.set_flags(read.flags());
+            if let (Ok(Aux::String(bx)), Ok(Aux::String(ug))) = (read.aux(b"BX"), read.aux(b"UG")) {
+                note.push_aux(b"BX", Aux::String(bx))?;
+                note.push_aux(b"UG", Aux::String(ug))?;
+            }
+            held.push(note)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write unmapped reads without a reference unchanged: to their own file with `--unmapped
+    /// separate`, otherwise along with the rest of the output.
+    pub fn write_unmapped(&mut self, reads: Vec<BamRecord>) -> Result<(), Error> {
+        for read in reads {
+            match (&mut self.unmapped, &mut self.sorter) {
+                (Some(writer), _) => writer.write(&read)?,
+                (None, Some(sorter)) => sorter.push(read)?,
+                (None, None) => self.writer.write(&read)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write rejected reads, tagged with the reason, if rejects are kept.
+    pub fn write_rejects(&mut self, reads: Vec<(BamRecord, RejectReason)>) -> Result<(), Error> {
+        if let Some(writer) = &mut self.rejects {
+            for (read, reason) in reads {
+                write_reject(writer, read, reason)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hand over the writer of rejected reads, e.g. for pairs found discordant when merging.
+    pub fn take_rejects(&mut self) -> Option<Writer> {
+        self.rejects.take()
     }
 
     pub fn retrieve_r2s(&mut self, ids: IndexSet<&[u8]>) -> Option<Vec<BamRecord>> {
         let ref_len = self
-            .windowed_reader
-            .meta_header
-            .target_len(self.windowed_reader.cur_ref)
+            .target_lens
+            .get(self.cur_ref as usize)
+            .copied()
             .unwrap_or(u64::MAX);
 
         if let Some(mate_reader) = &mut self.mate_reader {
             mate_reader
                 .fetch((
-                    self.windowed_reader.cur_ref,
-                    cmp::min(0, self.windowed_reader.cur_window.start - 100),
-                    cmp::max(self.windowed_reader.cur_window.end + 100, ref_len as i64),
+                    self.cur_ref,
+                    cmp::min(0, self.cur_window.start - 100),
+                    cmp::max(self.cur_window.end + 100, ref_len as i64),
                 ))
                 .unwrap();
 
             let mut mates: Vec<BamRecord> = Vec::with_capacity(ids.len());
             for read in mate_reader.records().flatten() {
+                // mates set aside by their alignment policy are dropped or follow their primary
+                if self.filter.is_set_aside(&read) {
+                    continue;
+                }
+
                 if ids.contains(read.qname()) && read.is_last_in_template() {
                     mates.push(read);
                 }
@@ -94,8 +452,8 @@ impl BamIO {
     }
 }
 
-impl FileIO<BamRecord> for BamIO {
-    fn write_reads(&mut self, outreads: &mut Vec<BamRecord>) {
+impl FileIO<BamRecord> for BamOutput {
+    fn write_reads(&mut self, outreads: &mut Vec<BamRecord>) -> Result<(), Error> {
         let mut count = 0;
         let mut mates: Option<Vec<BamRecord>> = None;
 
@@ -114,12 +472,116 @@ impl FileIO<BamRecord> for BamIO {
                 outreads.extend(mates);
             }
 
-            outreads.par_sort_by(|ra, rb| ra.pos().cmp(&rb.pos()));
-            outreads.drain(..).for_each(|read| {
-                self.writer.write(&read).unwrap();
+            if self.order != OutputSort::Unsorted {
+                outreads.par_sort_by_key(coordinate_key);
+            }
+            for read in outreads.drain(..) {
+                self.mark_written(&read)?;
+                match &mut self.sorter {
+                    Some(sorter) => sorter.push(read)?,
+                    None => self.writer.write(&read)?,
+                }
                 count += 1;
-            });
+            }
+        }
+        info!("Written {count} reads!");
+        Ok(())
+    }
+}
+
+// add alignments following a primary to the output if it was written, with its group tags if
+// it was tagged. Returns the number added.
+fn add_followers(
+    sorter: &mut BamSorter,
+    primary: Option<&BamRecord>,
+    following: &mut Vec<BamRecord>,
+) -> Result<i64, Error> {
+    let Some(primary) = primary else {
+        following.clear();
+        return Ok(0);
+    };
+
+    let count = following.len() as i64;
+    for mut read in following.drain(..) {
+        if let (Ok(Aux::String(bx)), Ok(Aux::String(ug))) = (primary.aux(b"BX"), primary.aux(b"UG"))
+        {
+            read.remove_aux(b"BX").ok();
+            read.remove_aux(b"UG").ok();
+            read.push_aux(b"BX", Aux::String(bx))?;
+            read.push_aux(b"UG", Aux::String(ug))?;
+        }
+        sorter.push(read)?;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{AlignmentPolicy, DedupArgs};
+    use crate::process::{bam_process::BamFileProcess, file_process::FileProcess};
+    use crate::testing::{mapped_read, test_header, write_indexed_bam, TempDir};
+    use crate::utils::RecordFile;
+    use rust_htslib::bam::{self, record::Aux, Read};
+
+    #[test]
+    fn test_followers_take_primary_tags() {
+        let dir = TempDir::new("followers");
+        let input = dir.join("followers.bam");
+
+        // (name, start, flags). a1 and a2 are duplicates, so only one of their supplementary
+        // alignments is output.
+        let reads = [
+            ("a2_AAAA", 50, 0x800),
+            ("a1_AAAA", 100, 0),
+            ("a2_AAAA", 100, 0),
+            ("b1_CCCC", 300, 0),
+            ("a1_AAAA", 600, 0x800),
+            ("b1_CCCC", 700, 0x800),
+        ];
+        let reads = reads.iter().map(|(name, start, flags)| {
+            let mut read = mapped_read(name, 0, *start, "30M", false);
+            read.set_flags(*flags);
+            read
+        });
+        write_indexed_bam(&input, &test_header(), reads);
+
+        let mut args = DedupArgs::for_test(input.to_str().unwrap(), dir.to_str().unwrap());
+        args.supplementary = AlignmentPolicy::FollowPrimary;
+        args.singletons = true;
+        let file = RecordFile {
+            fname: "followers.bam".to_string(),
+            fpath: input.to_str().unwrap().to_string(),
+            mate_path: None,
+            sample: None,
+        };
+        BamFileProcess::init_from_args(&args, &file)
+            .and_then(|process| process.process())
+            .unwrap();
+
+        let tag = |read: &bam::Record| match read.aux(b"UG") {
+            Ok(Aux::String(ug)) => ug.to_string(),
+            _ => String::new(),
+        };
+        let output: Vec<(String, i64, bool, String)> =
+            bam::Reader::from_path(dir.join("followers_RUMINA.bam"))
+                .unwrap()
+                .records()
+                .map(|read| {
+                    let read = read.unwrap();
+                    let name = String::from_utf8(read.qname().to_vec()).unwrap();
+                    (name, read.pos(), read.is_supplementary(), tag(&read))
+                })
+                .collect();
+
+        let primaries: Vec<_> = output.iter().filter(|read| !read.2).collect();
+        let following: Vec<_> = output.iter().filter(|read| read.2).collect();
+        assert_eq!(primaries.len(), 2);
+        assert_eq!(following.len(), 2);
+        for read in following {
+            let primary = primaries.iter().find(|p| p.0 == read.0).unwrap();
+            assert!(!read.3.is_empty());
+            assert_eq!(read.3, primary.3);
         }
-        info!("Written {count} reads!")
     }
 }
@@ -1,100 +1,964 @@
-use crate::record::BamRecord;
+use crate::cli::dedup_args::DEFAULT_MAX_CLIP;
+use crate::io::fastqio::STDIO_PATH;
+use crate::io::regions::Region;
+use crate::record::{BamRecord, SequenceRecord};
+use crate::spill::MEMORY_OVERHEAD;
 use crate::utils::{get_windows, make_bam_reader, Window};
-use anyhow::{Context, Error};
-use rust_htslib::bam::{Header, HeaderView, IndexedReader, Read};
+use anyhow::{bail, Context, Error};
+use rust_htslib::bam::{self, FetchDefinition, Header, IndexedReader, Read};
 
 // we use these values to mark when the bam reader hasn't loaded the first reference/window.
 const UNINIT_U32: u32 = u32::MAX - 1;
 const UNINIT_USIZE: usize = usize::MAX - 1;
 const UNINIT_I64: i64 = i64::MAX - 1;
 
+/// Limits on how many reads a window may hold. A window reaching either limit is cut short at the
+/// next change in read start position, and the rest of it is yielded as another window.
+///
+/// `max_clip` is the longest leading soft clip expected of a forward read, which bounds how far
+/// past the end of a window its reads may start.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowLimit {
+    pub max_reads: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub max_clip: i64,
+}
+
+impl Default for WindowLimit {
+    fn default() -> Self {
+        Self {
+            max_reads: None,
+            max_memory: None,
+            max_clip: DEFAULT_MAX_CLIP,
+        }
+    }
+}
+
+impl WindowLimit {
+    fn is_reached(&self, num_reads: u64, memory: u64) -> bool {
+        self.max_reads.is_some_and(|max| num_reads >= max)
+            || self.max_memory.is_some_and(|max| memory >= max)
+    }
+}
+
+/// Where a [WindowedBamReader] reads records from.
+enum AlignmentSource {
+    Indexed(IndexedReader),
+    // a coordinate-sorted stream, read front to back. References are found as the tid of read
+    // records changes, rather than fetched.
+    Stream(bam::Reader),
+}
+
+impl AlignmentSource {
+    fn read(&mut self, record: &mut BamRecord) -> Option<Result<(), rust_htslib::errors::Error>> {
+        match self {
+            Self::Indexed(reader) => reader.read(record),
+            Self::Stream(reader) => reader.read(record),
+        }
+    }
+}
+
 /// A wrapper around [rust_htslib::bam::IndexedReader] that aims to yield coordinates of bam records in a memory
 /// efficient manner. For every reference in a supplied bam file, the reader iteratively yields all reads
 /// mapped to a range of reference coordinates, until all coordinates of all references have been
 /// traversed.
+///
+/// Reads are assigned to windows by the position they are grouped at (see
+/// [crate::record::SequenceRecord::get_pos_key]) rather than their leftmost coordinate, so that
+/// reads of one group always share a window, and windowed output matches unwindowed output. Reverse
+/// reads are grouped at their end and may start windows earlier; they are carried over to the
+/// window holding their group position. Forward reads are grouped at their start minus leading soft
+/// clips, so reading continues past the end of a window by [WindowLimit::max_clip] bases. A read
+/// clipped further than that may be grouped in a window already yielded, which is an error.
+///
+/// Given `-` as input, records are streamed from stdin instead, which must be coordinate-sorted.
+/// Only references holding reads are visited, and unmapped reads without a reference are skipped.
+///
+/// Given [Region]s, only reads of families grouped within them are yielded, one region at a time,
+/// rather than all reads of each reference.
 pub struct WindowedBamReader {
-    reader: IndexedReader,
+    reader: AlignmentSource,
+    // streams only: the first record of the next reference, read while reading the last.
+    next_ref_record: Option<BamRecord>,
+    // streams only: the leftmost coordinate of the last record read from the current reference.
+    last_pos: i64,
     window_size: Option<i64>,
+    window_limit: WindowLimit,
+    // reads already read, but grouped at a position past the current window.
+    carry: Vec<BamRecord>,
+    // the first record not yet read into any window.
+    pending: Option<BamRecord>,
+    regions: Option<Vec<Region>>,
+    pub cur_region: Option<usize>,
+    // reads grouped before this position belong to an earlier region.
+    min_group_pos: i64,
     pub raw_header: Header,
-    pub meta_header: HeaderView,
-    pub windows: Vec<Vec<Window>>,
+    // lengths of the references, by tid. Kept instead of the header view so the reader can be
+    // sent to another thread.
+    pub target_lens: Vec<u64>,
+    pub windows: Vec<Window>,
     pub cur_window: Window,
     cur_window_idx: usize,
     pub cur_ref: u32,
 }
 
 impl WindowedBamReader {
-    pub fn new(file_name: &str, num_threads: usize, window_size: Option<i64>) -> Self {
-        let (raw_header, reader) = make_bam_reader(file_name, num_threads);
-        let meta_header = reader.header().clone();
+    pub fn new(
+        file_name: &str,
+        index: Option<&str>,
+        num_threads: usize,
+        window_size: Option<i64>,
+        window_limit: WindowLimit,
+        reference: Option<&str>,
+    ) -> Result<Self, Error> {
+        let reader = match file_name == STDIO_PATH {
+            true => {
+                let mut reader = bam::Reader::from_stdin().context("Failed to read from stdin")?;
+                reader.set_threads(num_threads).unwrap();
+                if let Some(reference) = reference {
+                    reader
+                        .set_reference(reference)
+                        .with_context(|| format!("Failed to load reference {reference}"))?;
+                }
+                AlignmentSource::Stream(reader)
+            }
+            false => {
+                let (_, reader) = make_bam_reader(file_name, index, num_threads, reference)?;
+                AlignmentSource::Indexed(reader)
+            }
+        };
+
+        Ok(Self::from_source(reader, window_size, window_limit))
+    }
+
+    fn from_source(
+        reader: AlignmentSource,
+        window_size: Option<i64>,
+        window_limit: WindowLimit,
+    ) -> Self {
+        let header_view = match &reader {
+            AlignmentSource::Indexed(reader) => reader.header(),
+            AlignmentSource::Stream(reader) => reader.header(),
+        };
+        let target_lens = (0..header_view.target_count())
+            .map(|tid| header_view.target_len(tid).unwrap_or(0))
+            .collect();
+        let raw_header = Header::from_template(header_view);
         let cur_ref = UNINIT_U32;
 
         Self {
             reader,
+            next_ref_record: None,
+            last_pos: i64::MIN,
             window_size,
+            window_limit,
+            carry: Vec::new(),
+            pending: None,
+            regions: None,
+            cur_region: None,
+            min_group_pos: i64::MIN,
             raw_header,
-            meta_header,
+            target_lens,
             windows: vec![],
             cur_window: Window {
                 start: UNINIT_I64,
                 end: UNINIT_I64,
             },
-            cur_window_idx: usize::MAX,
+            cur_window_idx: UNINIT_USIZE,
             cur_ref,
         }
     }
 
-    /// Yield all records in the given coordinate window.
-    pub fn window_records(&mut self) -> impl Iterator<Item = BamRecord> + '_ {
-        self.reader.records().flatten().take_while(|record| {
-            record.pos() >= self.cur_window.start && record.pos() < self.cur_window.end
-        })
+    /// Yield all records grouped at a position in the current window. If the window reaches its
+    /// [WindowLimit], it is cut short at the start position of the next record read.
+    ///
+    /// Fails if a record read is grouped before the current window, in one already yielded, if a
+    /// record can't be read, or if a streamed input turns out not to be coordinate-sorted.
+    pub fn window_records(&mut self) -> Result<impl Iterator<Item = BamRecord>, Error> {
+        let mut records = std::mem::take(&mut self.carry);
+        let mut num_reads = records.len() as u64;
+        let mut memory: u64 = records.iter().map(estimate_memory).sum();
+        let mut last_pos = None;
+        let first_window = self.windows.first().map(|window| window.start);
+
+        loop {
+            let record = match self.pending.take() {
+                Some(record) => record,
+                None => match self.read_record()? {
+                    Some(record) => record,
+                    None => break,
+                },
+            };
+
+            // reads starting this far past the window can't be grouped at a position within it,
+            // unless clipped further than expected.
+            if record.pos()
+                >= self
+                    .cur_window
+                    .end
+                    .saturating_add(self.window_limit.max_clip)
+            {
+                self.pending = Some(record);
+                break;
+            }
+
+            let group_pos = record.get_pos_key(false).0;
+            if group_pos < self.cur_window.start && first_window != Some(self.cur_window.start) {
+                bail!(
+                    "Read {} is grouped at {group_pos}, before the window it was read in, which \
+                    starts at {}: its leading soft clip is longer than --max-clip {}. Rerun with a \
+                    larger --max-clip",
+                    String::from_utf8_lossy(record.qname()),
+                    self.cur_window.start,
+                    self.window_limit.max_clip,
+                )
+            }
+
+            // reads of a region are fetched --max-clip bases around it (see `next_region`), so reads
+            // clipped further at their 5' end may have been missed.
+            if let (Some(regions), Some(idx)) = (&self.regions, self.cur_region) {
+                let clip = five_prime_clip(&record);
+                if clip > self.window_limit.max_clip {
+                    bail!(
+                        "Read {} has a {clip} base soft clip at its 5' end, longer than --max-clip \
+                        {}: reads of region {} clipped as far may have been missed. Rerun with a \
+                        larger --max-clip",
+                        String::from_utf8_lossy(record.qname()),
+                        self.window_limit.max_clip,
+                        regions[idx].name,
+                    )
+                }
+            }
+
+            if group_pos < self.min_group_pos {
+                continue;
+            }
+
+            let cut = record.pos() > self.cur_window.start
+                && record.pos() < self.cur_window.end
+                && last_pos != Some(record.pos())
+                && self.window_limit.is_reached(num_reads, memory);
+
+            if cut {
+                self.cur_window.end = record.pos();
+            }
+
+            last_pos = Some(record.pos());
+            num_reads += 1;
+            memory += estimate_memory(&record);
+            records.push(record);
+        }
+
+        let end = self.cur_window.end;
+        let (window, carry): (Vec<BamRecord>, Vec<BamRecord>) = records
+            .into_iter()
+            .partition(|record| record.get_pos_key(false).0 < end);
+        self.carry = carry;
+
+        Ok(window.into_iter())
+    }
+
+    /// The leftmost coordinate of any read not yet yielded. Output reads starting before it can be
+    /// written while keeping output sorted.
+    pub fn min_unyielded_pos(&self) -> i64 {
+        self.carry
+            .iter()
+            .chain(self.pending.iter())
+            .map(|record| record.pos())
+            .min()
+            .unwrap_or(i64::MAX)
+    }
+
+    // read the next record of the current reference.
+    fn read_record(&mut self) -> Result<Option<BamRecord>, Error> {
+        let Some(record) = self.read_any_record()? else {
+            return Ok(None);
+        };
+
+        // a stream has moved on to the next reference
+        if record.tid() != self.cur_ref as i32 {
+            self.next_ref_record = Some(record);
+            return Ok(None);
+        }
+
+        // indexed inputs are sorted, but a stream is taken on trust
+        if let AlignmentSource::Stream(_) = self.reader {
+            if record.pos() < self.last_pos {
+                bail!(
+                    "Input stream is not coordinate-sorted: position {} follows position {} on tid {}",
+                    record.pos(),
+                    self.last_pos,
+                    self.cur_ref
+                )
+            }
+            self.last_pos = record.pos();
+        }
+
+        Ok(Some(record))
+    }
+
+    fn read_any_record(&mut self) -> Result<Option<BamRecord>, Error> {
+        let mut record = BamRecord::new();
+        match self.reader.read(&mut record) {
+            Some(result) => {
+                result.context("BAM reader: failed to read a record")?;
+                Ok(Some(record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Only yield reads of families grouped within the given regions, which must be sorted by
+    /// coordinate (see [crate::io::regions::parse_regions]). Requires an indexed input.
+    pub fn set_regions(&mut self, regions: Vec<Region>) -> Result<(), Error> {
+        if let AlignmentSource::Stream(_) = self.reader {
+            anyhow::bail!("Regions can only be read from an indexed input file")
+        }
+
+        self.regions = Some(regions);
+        Ok(())
+    }
+
+    pub fn regions(&self) -> Option<&[Region]> {
+        self.regions.as_deref()
+    }
+
+    /// Mapped reads of each reference, by tid, from the index. Without one (or for formats whose
+    /// index holds no counts, if counting fails), reference lengths stand in for read counts.
+    pub fn mapped_counts(&mut self) -> Vec<u64> {
+        let stats = match &mut self.reader {
+            AlignmentSource::Indexed(reader) => reader.index_stats().ok(),
+            AlignmentSource::Stream(_) => None,
+        };
+
+        match stats {
+            Some(stats) => stats
+                .iter()
+                .filter(|(tid, ..)| *tid >= 0)
+                .map(|&(_, _, mapped, _)| mapped)
+                .collect(),
+            None => self.target_lens.clone(),
+        }
     }
 
     /// Set the inner reader to fetch records from the next reference if it exists, and
-    /// generate a new set of coordinate windows for read yielding.
+    /// generate a new set of coordinate windows for read yielding. Given regions, the next region
+    /// is fetched instead.
     pub fn next_reference(&mut self) -> Result<bool, Error> {
+        if self.regions.is_some() {
+            return self.next_region();
+        }
+
+        let prev_ref = self.cur_ref;
         self.cur_ref = match self.cur_ref {
             UNINIT_U32 => 0,
             _ => self.cur_ref + 1,
         };
 
-        self.reader
-            .fetch((self.cur_ref, 0, u32::MAX))
-            .with_context(|| format!("BAM reader: failed to fetch tid {}", self.cur_ref))?;
+        let mut first_record = None;
+        match &mut self.reader {
+            AlignmentSource::Indexed(reader) => reader
+                .fetch((self.cur_ref, 0, u32::MAX))
+                .with_context(|| format!("BAM reader: failed to fetch tid {}", self.cur_ref))?,
+            AlignmentSource::Stream(_) => {
+                // skip over whatever is left of the last reference
+                let mut next = self.next_ref_record.take();
+                if next.is_none() {
+                    next = loop {
+                        match self.read_any_record()? {
+                            Some(record) if record.tid() == prev_ref as i32 => continue,
+                            record => break record,
+                        }
+                    };
+                }
 
-        if self.cur_ref >= self.meta_header.target_count() {
+                match next {
+                    Some(record) if record.tid() >= 0 => {
+                        if prev_ref != UNINIT_U32 && record.tid() < prev_ref as i32 {
+                            bail!(
+                                "Input stream is not coordinate-sorted: tid {} follows tid {}",
+                                record.tid(),
+                                prev_ref
+                            )
+                        }
+                        self.cur_ref = record.tid() as u32;
+                        self.last_pos = record.pos();
+                        first_record = Some(record);
+                    }
+                    // unmapped reads come last in coordinate order
+                    unmapped => {
+                        self.next_ref_record = unmapped;
+                        self.cur_ref = self.target_lens.len() as u32;
+                    }
+                }
+            }
+        }
+
+        if self.cur_ref as usize >= self.target_lens.len() {
             Ok(false)
         } else {
-            let windows = get_windows(
+            let mut windows = get_windows(
                 self.window_size,
-                self.meta_header.target_len(self.cur_ref).unwrap() as i64,
-            )
-            .chunks(3)
-            .map(|chunk| chunk.to_vec())
-            .collect::<Vec<Vec<Window>>>();
+                self.target_lens[self.cur_ref as usize] as i64,
+            );
+
+            // reverse reads may be grouped past the end of the reference
+            if let Some(last) = windows.last_mut() {
+                last.end = i64::MAX;
+            }
 
             self.windows = windows;
-            self.cur_window_idx = usize::MAX;
+            self.cur_window_idx = UNINIT_USIZE;
+            self.carry.clear();
+            self.pending = first_record;
             Ok(true)
         }
     }
 
-    /// Advance to the next coordinate window for the given reference. If windows are set to be
-    /// processed in chunks, set the current window used for read iteration to span the entire
-    /// chunk.
+    /// Yield unmapped reads without a reference, which come last in coordinate order. Only
+    /// meaningful once all references have been read.
+    pub fn unmapped_records(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<BamRecord, Error>> + '_, Error> {
+        let first = match &mut self.reader {
+            AlignmentSource::Indexed(reader) => {
+                reader
+                    .fetch(FetchDefinition::Unmapped)
+                    .context("BAM reader: failed to fetch unmapped reads")?;
+                None
+            }
+            AlignmentSource::Stream(_) => self.next_ref_record.take(),
+        };
+
+        Ok(first
+            .into_iter()
+            .map(Ok)
+            .chain(std::iter::from_fn(|| self.read_any_record().transpose()))
+            .filter(|record| record.as_ref().map_or(true, |record| record.tid() < 0)))
+    }
+
+    fn next_region(&mut self) -> Result<bool, Error> {
+        let idx = self.cur_region.map_or(0, |idx| idx + 1);
+        let Some(region) = self.regions.as_ref().and_then(|regions| regions.get(idx)) else {
+            return Ok(false);
+        };
+        let owned = region.owned.clone();
+        self.cur_region = Some(idx);
+        self.cur_ref = region.tid;
+
+        // reads of families grouped in the region may start outside of it by their 5' soft clip:
+        // reverse reads end before it by their trailing clip, forward reads start after it by their
+        // leading one. Fetching --max-clip bases around it finds any clipped no further, and
+        // `window_records` fails on any clipped further.
+        let pad = self.window_limit.max_clip + 1;
+        if let AlignmentSource::Indexed(reader) = &mut self.reader {
+            reader
+                .fetch((region.tid, (owned.start - pad).max(0), owned.end + pad))
+                .with_context(|| format!("BAM reader: failed to fetch region {}", region.name))?;
+        }
+
+        // windows are laid over the part of the region owned by it, which may be empty
+        self.windows = get_windows(self.window_size, owned.end - owned.start)
+            .into_iter()
+            .map(|window| Window {
+                start: window.start + owned.start,
+                end: window.end.saturating_add(owned.start).min(owned.end),
+            })
+            .filter(|window| window.start < window.end)
+            .collect();
+        self.min_group_pos = owned.start;
+
+        // regions reaching the ends of their reference take families grouped past them, as
+        // whole references do
+        if owned.start == 0 {
+            self.min_group_pos = i64::MIN;
+        }
+        if owned.end >= self.target_lens[region.tid as usize] as i64 {
+            if let Some(last) = self.windows.last_mut() {
+                last.end = i64::MAX;
+            }
+        }
+        self.cur_window_idx = UNINIT_USIZE;
+        self.carry.clear();
+        self.pending = None;
+        Ok(true)
+    }
+
+    /// Advance to the next coordinate window for the given reference. If the last window was cut
+    /// short by the [WindowLimit], continue it from where it was cut instead.
     pub fn next_window(&mut self) -> bool {
+        if let Some(window) = self.windows.get(self.cur_window_idx) {
+            if self.cur_window.end < window.end {
+                self.cur_window.start = self.cur_window.end;
+                self.cur_window.end = window.end;
+                return true;
+            }
+        }
+
         self.cur_window_idx = match self.cur_window_idx {
             UNINIT_USIZE => 0,
             _ => self.cur_window_idx + 1,
         };
 
-        if let Some(window_chunk) = self.windows.get(self.cur_window_idx) {
-            self.cur_window.start = window_chunk.first().unwrap().start;
-            self.cur_window.end = window_chunk.last().unwrap().end;
+        if let Some(window) = self.windows.get(self.cur_window_idx) {
+            self.cur_window.start = window.start;
+            self.cur_window.end = window.end;
             true
         } else {
             false
         }
     }
 }
+
+// soft-clipped bases before the first sequenced base of a read, which it is grouped by.
+fn five_prime_clip(record: &BamRecord) -> i64 {
+    match record.is_reverse() {
+        true => record.cigar().trailing_softclips(),
+        false => record.cigar().leading_softclips(),
+    }
+}
+
+// approximate memory taken up by a record once pulled for grouping.
+fn estimate_memory(record: &BamRecord) -> u64 {
+    (record.inner().l_data as u64 + std::mem::size_of::<BamRecord>() as u64) * MEMORY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mapped_read, test_header, write_bam, write_indexed_bam, TempDir};
+    use rust_htslib::bam;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    // (name, start, cigar, reverse). Reads starting before 0 are unmapped, without a reference.
+    type TestRead<'a> = (&'a str, i64, &'a str, bool);
+
+    fn write_test_bam(path: &Path, reads: &[TestRead]) {
+        write_indexed_bam(path, &test_header(), test_reads(reads));
+    }
+
+    fn write_unindexed_bam(path: &Path, reads: &[TestRead]) {
+        write_bam(path, &test_header(), test_reads(reads));
+    }
+
+    fn test_reads<'a>(reads: &'a [TestRead]) -> impl Iterator<Item = bam::Record> + 'a {
+        reads.iter().map(|(name, start, cigar, reverse)| {
+            let mut read = mapped_read(name, 0, *start, cigar, *reverse);
+            if *start < 0 {
+                let (seq, qual) = (read.seq().as_bytes(), read.qual().to_vec());
+                read.set(name.as_bytes(), None, &seq, &qual);
+                read.set_unmapped();
+                read.set_tid(-1);
+                read.set_pos(-1);
+            }
+            read
+        })
+    }
+
+    // map each read to the window it was yielded in.
+    fn read_windows(path: &Path, window_size: Option<i64>, limit: WindowLimit) -> Vec<Vec<String>> {
+        let reader =
+            WindowedBamReader::new(path.to_str().unwrap(), None, 1, window_size, limit, None)
+                .unwrap();
+        yield_windows(reader)
+    }
+
+    fn yield_windows(mut reader: WindowedBamReader) -> Vec<Vec<String>> {
+        let mut windows = Vec::new();
+
+        while reader.next_reference().unwrap() {
+            while reader.next_window() {
+                let window: Vec<String> = reader
+                    .window_records()
+                    .unwrap()
+                    .map(|r| String::from_utf8(r.qname().to_vec()).unwrap())
+                    .collect();
+                if !window.is_empty() {
+                    windows.push(window);
+                }
+            }
+        }
+
+        windows
+    }
+
+    #[test]
+    fn test_unsorted_stream_fails() {
+        let dir = TempDir::new("unsorted");
+        let input = dir.join("unsorted.bam");
+
+        let reads = [
+            ("r1", 10, "30M", false),
+            ("r2", 500, "30M", false),
+            ("r3", 20, "30M", false),
+        ];
+        write_unindexed_bam(&input, &reads);
+
+        let stream = AlignmentSource::Stream(bam::Reader::from_path(&input).unwrap());
+        let mut reader = WindowedBamReader::from_source(stream, None, WindowLimit::default());
+        assert!(reader.next_reference().unwrap());
+        assert!(reader.next_window());
+        let err = reader.window_records().err().unwrap();
+
+        assert!(err.to_string().contains("not coordinate-sorted"));
+    }
+
+    #[test]
+    fn test_unmapped_records() {
+        let dir = TempDir::new("unmapped");
+        let input = dir.join("unmapped.bam");
+
+        let reads = [
+            ("m1", 10, "30M", false),
+            ("m2", 500, "30M", true),
+            ("u1", -1, "30M", false),
+            ("u2", -1, "20M", false),
+        ];
+        write_test_bam(&input, &reads);
+
+        let mut reader = WindowedBamReader::new(
+            input.to_str().unwrap(),
+            None,
+            1,
+            Some(100),
+            WindowLimit::default(),
+            None,
+        )
+        .unwrap();
+        let mut mapped: Vec<String> = Vec::new();
+        while reader.next_reference().unwrap() {
+            while reader.next_window() {
+                mapped.extend(
+                    reader
+                        .window_records()
+                        .unwrap()
+                        .map(|r| String::from_utf8(r.qname().to_vec()).unwrap()),
+                );
+            }
+        }
+        let unmapped: Vec<String> = reader
+            .unmapped_records()
+            .unwrap()
+            .map(|r| String::from_utf8(r.unwrap().qname().to_vec()).unwrap())
+            .collect();
+
+        // unmapped reads without a reference are only yielded when asked for
+        assert_eq!(mapped, ["m1", "m2"]);
+        assert_eq!(unmapped, ["u1", "u2"]);
+    }
+
+    #[test]
+    fn test_windows_keep_groups_together() {
+        let dir = TempDir::new("windows");
+        let input = dir.join("windows.bam");
+
+        // reads are named after the position they are grouped at
+        let reads = [
+            // reverse reads grouped at their end, starting on both sides of 100
+            ("g120_1", 60, "60M", true),
+            ("g120_2", 90, "30M", true),
+            ("g120_3", 105, "15M", true),
+            // forward reads grouped at their start minus soft clips, on both sides of 200
+            ("g195_1", 195, "30M", false),
+            ("g195_2", 203, "8S22M", false),
+            ("g195_3", 210, "15S15M", false),
+            ("g210_1", 210, "30M", false),
+            // reverse reads grouped past the end of the reference
+            ("g1005_1", 960, "40M5S", true),
+            ("g1005_2", 970, "30M5S", true),
+        ];
+        write_test_bam(&input, &reads);
+
+        let unwindowed = read_windows(&input, None, WindowLimit::default());
+        assert_eq!(unwindowed.len(), 1);
+        assert_eq!(unwindowed[0].len(), reads.len());
+
+        let configs = [
+            (Some(100), WindowLimit::default()),
+            (Some(7), WindowLimit::default()),
+            (
+                None,
+                // reading less far ahead, so that reads are left to cut later windows short
+                WindowLimit {
+                    max_reads: Some(1),
+                    max_memory: None,
+                    max_clip: 20,
+                },
+            ),
+        ];
+
+        for (window_size, limit) in configs {
+            let windows = read_windows(&input, window_size, limit);
+            assert!(windows.len() > 1);
+
+            // every read is yielded exactly once, and reads of one group share a window
+            let mut window_of: HashMap<String, usize> = HashMap::new();
+            for (i, window) in windows.iter().enumerate() {
+                for name in window {
+                    assert!(window_of.insert(name.clone(), i).is_none());
+                }
+            }
+            assert_eq!(window_of.len(), reads.len());
+
+            for (name, i) in &window_of {
+                let group = name.split_once('_').unwrap().0;
+                for (other, j) in &window_of {
+                    if other.starts_with(&format!("{group}_")) {
+                        assert_eq!(i, j, "{name} and {other} split with {window_size:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_clip_past_max_clip_fails() {
+        let dir = TempDir::new("max_clip");
+        let input = dir.join("max_clip.bam");
+
+        // the second read starts well past the first window, but is grouped within it
+        write_test_bam(
+            &input,
+            &[("a_1", 50, "30M", false), ("a_2", 150, "120S30M", false)],
+        );
+
+        let limit = WindowLimit {
+            max_clip: 20,
+            ..WindowLimit::default()
+        };
+        let mut reader =
+            WindowedBamReader::new(input.to_str().unwrap(), None, 1, Some(100), limit, None)
+                .unwrap();
+        assert!(reader.next_reference().unwrap());
+        assert!(reader.next_window());
+        assert_eq!(reader.window_records().unwrap().count(), 1);
+        assert!(reader.next_window());
+        assert!(reader.window_records().is_err());
+
+        // reading far enough ahead keeps the two together
+        assert_eq!(
+            read_windows(&input, Some(100), WindowLimit::default()),
+            [["a_1", "a_2"]]
+        );
+    }
+
+    // position, name, flags, sequence and tags of an output record
+    type OutputRecord = (i64, Vec<u8>, u16, Vec<u8>, String);
+
+    // deduplicate a BAM file, returning its output records sorted by position and name
+    fn dedup_records(
+        input: &Path,
+        outdir: &Path,
+        configure: impl Fn(&mut crate::cli::DedupArgs),
+    ) -> Vec<OutputRecord> {
+        use crate::cli::DedupArgs;
+        use crate::process::{bam_process::BamFileProcess, file_process::FileProcess};
+        use crate::utils::RecordFile;
+
+        let mut args = DedupArgs::for_test(input.to_str().unwrap(), outdir.to_str().unwrap());
+        configure(&mut args);
+        let fname = input.file_name().unwrap().to_str().unwrap();
+        let file = RecordFile {
+            fname: fname.to_string(),
+            fpath: input.to_str().unwrap().to_string(),
+            mate_path: None,
+            sample: None,
+        };
+        std::fs::create_dir_all(outdir).unwrap();
+        BamFileProcess::init_from_args(&args, &file)
+            .and_then(|process| process.process())
+            .unwrap();
+
+        let outfile = outdir.join(fname.replace(".bam", "_RUMINA.bam"));
+        let mut reader = bam::Reader::from_path(outfile).unwrap();
+        let mut records: Vec<_> = reader
+            .records()
+            .map(|read| {
+                let read = read.unwrap();
+                let tags = read
+                    .aux_iter()
+                    .map(|aux| {
+                        let (tag, value) = aux.unwrap();
+                        format!("{}:{value:?}", std::str::from_utf8(tag).unwrap())
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                (
+                    read.pos(),
+                    read.qname().to_vec(),
+                    read.flags(),
+                    read.seq().as_bytes(),
+                    tags,
+                )
+            })
+            .collect();
+        records.sort();
+        records
+    }
+
+    // families of reads sharing a UMI (give or take an error) and group position, strand alike.
+    // Reads of a family differ in length and clipping, so that those of reverse families start
+    // far apart, and forward families take a read clipped further than any read before it.
+    fn write_families(path: &Path) {
+        let mut reads: Vec<(String, i64, String, bool)> = Vec::new();
+        for family in 0..28_i64 {
+            let group_pos = 60 + family * 31;
+            let umi: String = (0..8)
+                .map(|i| b"ACGT"[((family >> (i % 4)) + i) as usize % 4] as char)
+                .collect();
+            let reverse = family % 2 == 0;
+
+            for member in 0..(3 + family % 4) {
+                let len = 20 + (member * 7 + family) % 25;
+                let clip = match (reverse, member) {
+                    (false, 2) => 55,
+                    _ => (member + family) % 3 * 2,
+                };
+                // one read of each family has an error in its UMI
+                let umi = match member {
+                    1 => format!("T{}", &umi[1..]),
+                    _ => umi.clone(),
+                };
+                let name = format!("f{family}m{member}_{umi}");
+                match reverse {
+                    true => {
+                        reads.push((name, group_pos - clip - len, format!("{len}M{clip}S"), true))
+                    }
+                    false => reads.push((
+                        name,
+                        group_pos + clip,
+                        format!("{clip}S{len}M").replace("0S", ""),
+                        false,
+                    )),
+                }
+            }
+        }
+        reads.sort_by_key(|read| read.1);
+
+        let reads: Vec<TestRead> = reads
+            .iter()
+            .map(|(name, start, cigar, reverse)| (name.as_str(), *start, cigar.as_str(), *reverse))
+            .collect();
+        write_test_bam(path, &reads);
+    }
+
+    #[test]
+    fn test_windowed_dedup_matches_unwindowed() {
+        let dir = TempDir::new("windowed_dedup");
+        let packaged = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/SRR2057564_sub_ext.bam");
+        let families = dir.join("families.bam");
+        write_families(&families);
+
+        // windows small enough to cut through families of the generated reads; those of the
+        // packaged BAM span whole mouse chromosomes, so it is split coarsely.
+        let windowing: [(&Path, &[i64]); 2] = [
+            (&families, &[7, 20, 31, 100]),
+            (&packaged, &[1_000_000, 10_000_000]),
+        ];
+
+        for (input, split_windows) in windowing {
+            for only_group in [false, true] {
+                let unwindowed = dedup_records(input, &dir.join("unwindowed"), |args| {
+                    args.only_group = only_group;
+                });
+                assert!(!unwindowed.is_empty());
+
+                // windows cut short at every change in read start position
+                let windowed = dedup_records(input, &dir.join("windowed"), |args| {
+                    args.only_group = only_group;
+                    args.max_reads_per_window = Some(1);
+                });
+                assert_eq!(windowed, unwindowed, "{input:?}, only_group: {only_group}");
+
+                for split_window in split_windows {
+                    let windowed = dedup_records(input, &dir.join("windowed"), |args| {
+                        args.only_group = only_group;
+                        args.split_window = Some(*split_window);
+                    });
+                    assert_eq!(
+                        windowed, unwindowed,
+                        "{input:?}, only_group: {only_group}, split window: {split_window}"
+                    );
+                }
+
+                // partitions spilled to disk within a small memory budget are written as they
+                // are grouped, and sorted on their way out
+                let spilled = dedup_records(input, &dir.join("spilled"), |args| {
+                    args.only_group = only_group;
+                    args.tmpdir = Some(dir.to_str().unwrap().to_string());
+                    args.max_memory = Some(1);
+                });
+                assert_eq!(
+                    spilled, unwindowed,
+                    "{input:?}, only_group: {only_group}, spilled"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_regions_keep_groups_together() {
+        let dir = TempDir::new("region_reads");
+        let input = dir.join("regions.bed.bam");
+
+        let reads = [
+            // grouped within the region, but starting before it
+            ("g120_1", 60, "60M", true),
+            ("g90_1", 90, "30M", false),
+            ("g120_2", 105, "15M", true),
+            // grouped within the region, but starting after it
+            ("g195_1", 195, "30M", false),
+            ("g195_2", 210, "15S15M", false),
+            ("g210_1", 210, "30M", false),
+        ];
+        write_test_bam(&input, &reads);
+
+        for window_size in [None, Some(7)] {
+            let mut reader = WindowedBamReader::new(
+                input.to_str().unwrap(),
+                None,
+                1,
+                window_size,
+                WindowLimit::default(),
+                None,
+            )
+            .unwrap();
+            let header = bam::HeaderView::from_header(&reader.raw_header);
+            let regions =
+                crate::io::regions::parse_regions(&["chr1:101-200".to_string()], None, &header)
+                    .unwrap();
+            reader.set_regions(regions).unwrap();
+
+            let mut names: Vec<String> = yield_windows(reader).into_iter().flatten().collect();
+            names.sort();
+            assert_eq!(names, vec!["g120_1", "g120_2", "g195_1", "g195_2"]);
+        }
+    }
+
+    #[test]
+    fn test_region_clip_past_max_clip_fails() {
+        let dir = TempDir::new("region_clip");
+        let input = dir.join("region_clip.bam");
+
+        // grouped at 110, within the region, but ending 30 bases before it by its trailing clip
+        write_test_bam(&input, &[("r_1", 60, "20M30S", true)]);
+
+        let limit = WindowLimit {
+            max_clip: 20,
+            ..WindowLimit::default()
+        };
+        let mut reader =
+            WindowedBamReader::new(input.to_str().unwrap(), None, 1, None, limit, None).unwrap();
+        let header = bam::HeaderView::from_header(&reader.raw_header);
+        let regions =
+            crate::io::regions::parse_regions(&["chr1:101-200".to_string()], None, &header)
+                .unwrap();
+        reader.set_regions(regions).unwrap();
+        assert!(reader.next_reference().unwrap());
+        assert!(reader.next_window());
+        let err = reader.window_records().err().unwrap();
+
+        assert!(err.to_string().contains("--max-clip"));
+    }
+}
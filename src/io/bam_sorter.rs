@@ -0,0 +1,510 @@
+use crate::cli::{AlignmentFormat, OutputSort};
+use crate::record::BamRecord;
+use crate::spill::{PartitionReads, SpillConfig, Spillable, MEMORY_OVERHEAD};
+use crate::utils::make_bam_writer;
+use anyhow::{Context, Error};
+use rayon::slice::ParallelSliceMut;
+use rust_htslib::bam::record::{Aux, CigarString, CigarStringView};
+use rust_htslib::bam::{self, Header, HeaderView, Read, Writer};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The order reads are sorted in, matching `samtools sort`: by reference, then leftmost
+/// coordinate, then strand. Unmapped reads without a reference come last.
+pub fn coordinate_key(read: &BamRecord) -> (u32, i64, bool) {
+    (read.tid() as u32, read.pos(), read.is_reverse())
+}
+
+/// Keys reads are sorted by for an [OutputSort]. All reads of one sort share a variant.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortKey {
+    Coordinate(u32, i64, bool),
+    // read name, then R1 before R2
+    Queryname(Vec<u8>, bool),
+    TemplateCoordinate(TemplateKey),
+}
+
+/// Mirrors the key of `samtools sort -M template-coordinate`: the unclipped 5' ends of a read and
+/// its mate, lower end first, then the UMI group (the `UG` tag, in place of `MI`) and read name.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TemplateKey {
+    tid1: u32,
+    tid2: u32,
+    pos1: i64,
+    pos2: i64,
+    neg1: bool,
+    neg2: bool,
+    group: Vec<u8>,
+    qname: Vec<u8>,
+    is_upper: bool,
+}
+
+pub fn sort_key(order: OutputSort, read: &BamRecord) -> SortKey {
+    match order {
+        // unsorted output is never sorted, but coordinate order is as good as any
+        OutputSort::Coordinate | OutputSort::Unsorted => {
+            let (tid, pos, reverse) = coordinate_key(read);
+            SortKey::Coordinate(tid, pos, reverse)
+        }
+        OutputSort::Queryname => {
+            SortKey::Queryname(read.qname().to_vec(), read.is_last_in_template())
+        }
+        OutputSort::TemplateCoordinate => SortKey::TemplateCoordinate(template_key(read)),
+    }
+}
+
+// the reference position of the first sequenced base, counting clipped bases.
+fn unclipped_five_prime(cigar: &CigarStringView, reverse: bool) -> i64 {
+    match reverse {
+        true => cigar.end_pos() + cigar.trailing_softclips() + cigar.trailing_hardclips() - 1,
+        false => cigar.pos() - cigar.leading_softclips() - cigar.leading_hardclips(),
+    }
+}
+
+fn template_key(read: &BamRecord) -> TemplateKey {
+    let own = (
+        read.tid() as u32,
+        unclipped_five_prime(&read.cigar(), read.is_reverse()),
+        read.is_reverse(),
+    );
+
+    // without a mate cigar (MC tag), the mate is assumed unclipped and as long as this read.
+    let mate = match read.is_paired() && !read.is_mate_unmapped() {
+        true => {
+            let mate_cigar = match read.aux(b"MC") {
+                Ok(Aux::String(mc)) => CigarString::try_from(mc).ok(),
+                _ => None,
+            }
+            .map(|cigar| cigar.into_view(read.mpos()));
+
+            let pos = match mate_cigar {
+                Some(cigar) => unclipped_five_prime(&cigar, read.is_mate_reverse()),
+                None if read.is_mate_reverse() => read.mpos() + read.seq_len() as i64 - 1,
+                None => read.mpos(),
+            };
+            (read.mtid() as u32, pos, read.is_mate_reverse())
+        }
+        false => own,
+    };
+
+    let is_upper = own > mate || (own == mate && read.is_last_in_template());
+    let (lower, upper) = if own <= mate {
+        (own, mate)
+    } else {
+        (mate, own)
+    };
+
+    let group = match read.aux(b"UG") {
+        Ok(Aux::String(ug)) => ug.as_bytes().to_vec(),
+        _ => Vec::new(),
+    };
+
+    TemplateKey {
+        tid1: lower.0,
+        tid2: upper.0,
+        pos1: lower.1,
+        pos2: upper.1,
+        neg1: lower.2,
+        neg2: upper.2,
+        group,
+        qname: read.qname().to_vec(),
+        is_upper,
+    }
+}
+
+/// Copy a header, replacing its `@HD` line with one declaring the given sort order. The format
+/// version (`VN`) is kept if present.
+pub fn with_sort_order(header: &Header, order: OutputSort) -> Header {
+    let sort_tags = match order {
+        OutputSort::Coordinate => "SO:coordinate",
+        OutputSort::Queryname => "SO:queryname\tSS:queryname:lexicographical",
+        OutputSort::TemplateCoordinate => "SO:unsorted\tGO:query\tSS:unsorted:template-coordinate",
+        OutputSort::Unsorted => "SO:unsorted",
+    };
+
+    let text = String::from_utf8_lossy(&header.to_bytes()).to_string();
+    let version = text
+        .lines()
+        .filter(|line| line.starts_with("@HD"))
+        .flat_map(|line| line.split('\t'))
+        .find_map(|tag| tag.strip_prefix("VN:"))
+        .unwrap_or("1.6")
+        .to_string();
+
+    let mut lines = vec![format!("@HD\tVN:{version}\t{sort_tags}")];
+    lines.extend(
+        text.lines()
+            .filter(|line| !line.starts_with("@HD") && !line.is_empty())
+            .map(String::from),
+    );
+
+    Header::from_template(&HeaderView::from_bytes(lines.join("\n").as_bytes()))
+}
+
+/// Sorts reads by [sort_key] within a memory budget. Reads are buffered in memory, and each
+/// full buffer is sorted and spilled to a run file in a temporary directory. Finishing merges the
+/// runs into the output, first merging them [MAX_MERGE_RUNS] at a time into longer runs if there
+/// are too many to open at once. The temporary directory is removed once the [BamSorter] is
+/// dropped.
+pub struct BamSorter {
+    dir: PathBuf,
+    header: Header,
+    order: OutputSort,
+    max_memory: u64,
+    buffer: Vec<BamRecord>,
+    buffer_memory: u64,
+    runs: Vec<PathBuf>,
+    // how many run files are spilled or merged so far, to name the next one
+    run_count: usize,
+    fan_in: usize,
+}
+
+/// The most run files merged at once, bounding how many files are open while merging.
+pub const MAX_MERGE_RUNS: usize = 64;
+
+impl BamSorter {
+    pub fn new(
+        config: &SpillConfig,
+        name: &str,
+        header: Header,
+        order: OutputSort,
+    ) -> Result<Self, Error> {
+        let dir = config
+            .tmpdir
+            .join(format!("rumina_sort_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create temporary directory {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            header,
+            order,
+            max_memory: config.max_memory,
+            buffer: Vec::new(),
+            buffer_memory: 0,
+            runs: Vec::new(),
+            run_count: 0,
+            fan_in: MAX_MERGE_RUNS,
+        })
+    }
+
+    pub fn push(&mut self, read: BamRecord) -> Result<(), Error> {
+        self.buffer_memory += read.inner().l_data as u64 * MEMORY_OVERHEAD;
+        self.buffer.push(read);
+
+        if self.buffer_memory >= self.max_memory {
+            self.spill_run()?;
+        }
+
+        Ok(())
+    }
+
+    fn next_run_path(&mut self) -> PathBuf {
+        self.run_count += 1;
+        self.dir.join(format!("run_{}", self.run_count))
+    }
+
+    fn spill_run(&mut self) -> Result<(), Error> {
+        let path = self.next_run_path();
+        let mut writer = BamRecord::create_partition(&path, &self.header)?;
+
+        let order = self.order;
+        self.buffer
+            .par_sort_by_cached_key(|read| sort_key(order, read));
+        for read in self.buffer.drain(..) {
+            writer.push(read)?;
+        }
+        writer.flush()?;
+
+        self.buffer_memory = 0;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Write all reads pushed so far to `writer` in sorted order, returning how many were written.
+    pub fn finish(self, writer: &mut Writer) -> Result<u64, Error> {
+        self.merge(|read| writer.write(&read).map_err(Error::from))
+    }
+
+    /// Pass all reads pushed so far to `emit` in sorted order, returning how many were passed.
+    pub fn merge(mut self, emit: impl FnMut(BamRecord) -> Result<(), Error>) -> Result<u64, Error> {
+        // the in-memory buffer is merged along with the runs, so leave room for it.
+        while self.runs.len() >= self.fan_in {
+            self.merge_runs()?;
+        }
+
+        let order = self.order;
+        self.buffer
+            .par_sort_by_cached_key(|read| sort_key(order, read));
+        let buffer = std::mem::take(&mut self.buffer);
+
+        let mut sources: Vec<PartitionReads<BamRecord>> = Vec::with_capacity(self.runs.len() + 1);
+        for run in &self.runs {
+            sources.push(BamRecord::read_partition(run)?);
+        }
+        sources.push(Box::new(buffer.into_iter().map(Ok)));
+
+        merge_sources(sources, self.order, emit)
+    }
+
+    // merge the first `fan_in` runs into one run in their place. Merging neighbouring runs keeps
+    // the runs in input order, so the final merge stays stable.
+    fn merge_runs(&mut self) -> Result<(), Error> {
+        let path = self.next_run_path();
+        let mut writer = BamRecord::create_partition(&path, &self.header)?;
+
+        let inputs: Vec<PathBuf> = self.runs.drain(..self.fan_in).collect();
+        let mut sources: Vec<PartitionReads<BamRecord>> = Vec::with_capacity(inputs.len());
+        for run in &inputs {
+            sources.push(BamRecord::read_partition(run)?);
+        }
+        merge_sources(sources, self.order, |read| writer.push(read))?;
+        writer.flush()?;
+
+        for run in inputs {
+            fs::remove_file(run).ok();
+        }
+        self.runs.insert(0, path);
+        Ok(())
+    }
+}
+
+// merge sources sorted in `order` into `emit`, returning how many reads were passed.
+fn merge_sources(
+    mut sources: Vec<PartitionReads<BamRecord>>,
+    order: OutputSort,
+    mut emit: impl FnMut(BamRecord) -> Result<(), Error>,
+) -> Result<u64, Error> {
+    // the next read of each source. Ties are broken by source index; runs are spilled in
+    // input order, so equal reads keep their input order, as with a stable sort.
+    let mut heads: Vec<Option<BamRecord>> = Vec::with_capacity(sources.len());
+    let mut heap = BinaryHeap::with_capacity(sources.len());
+    for (idx, source) in sources.iter_mut().enumerate() {
+        let head = source.next().transpose()?;
+        if let Some(read) = &head {
+            heap.push(Reverse((sort_key(order, read), idx)));
+        }
+        heads.push(head);
+    }
+
+    let mut count = 0;
+    while let Some(Reverse((_, idx))) = heap.pop() {
+        let read = heads[idx].take().unwrap();
+        emit(read)?;
+        count += 1;
+
+        heads[idx] = sources[idx].next().transpose()?;
+        if let Some(next) = &heads[idx] {
+            heap.push(Reverse((sort_key(order, next), idx)));
+        }
+    }
+
+    Ok(count)
+}
+
+impl Drop for BamSorter {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Sort a BAM (or SAM/CRAM) file by coordinate in place, spilling to `config.tmpdir` if its reads
+/// don't fit in `config.max_memory`.
+pub fn sort_bam(
+    bam_name: &str,
+    num_threads: usize,
+    config: &SpillConfig,
+    reference: Option<&str>,
+) -> Result<(), Error> {
+    let tempname = format!("{bam_name}_PRE_SORT");
+    let format = AlignmentFormat::from_path(bam_name).unwrap_or(AlignmentFormat::Bam);
+
+    sort_bam_to(bam_name, &tempname, format, num_threads, config, reference)?;
+    fs::rename(tempname, bam_name)?;
+    Ok(())
+}
+
+/// Write a copy of a BAM (or SAM/CRAM) file sorted by coordinate to `out_name`, in `format`.
+pub fn sort_bam_to(
+    bam_name: &str,
+    out_name: &str,
+    format: AlignmentFormat,
+    num_threads: usize,
+    config: &SpillConfig,
+    reference: Option<&str>,
+) -> Result<(), Error> {
+    let mut reader = bam::Reader::from_path(bam_name)?;
+    reader.set_threads(num_threads)?;
+    if let Some(reference) = reference {
+        reader.set_reference(reference)?;
+    }
+    let header = Header::from_template(reader.header());
+
+    let name = Path::new(bam_name)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut sorter = BamSorter::new(config, &name, header.clone(), OutputSort::Coordinate)?;
+
+    let mut record = BamRecord::new();
+    while let Some(res) = reader.read(&mut record) {
+        res.with_context(|| format!("Failed to read {bam_name} for sorting"))?;
+        sorter.push(record.clone())?;
+    }
+
+    let mut writer = make_bam_writer(out_name, header, num_threads, format, reference);
+    sorter.finish(&mut writer)?;
+    drop(writer);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{header_with, mapped_read, write_bam, TempDir};
+
+    fn record(tid: i32, pos: i64, name: &str) -> BamRecord {
+        mapped_read(name, tid, pos, "4M", false)
+    }
+
+    fn header() -> Header {
+        header_with(&[("chr1", 1000), ("chr2", 1000)])
+    }
+
+    #[test]
+    fn test_sorter_merges_runs() {
+        let header = header();
+        let tmpdir = TempDir::new("sorter");
+        let config = SpillConfig {
+            tmpdir: tmpdir.to_path_buf(),
+            // spill a run after every read
+            max_memory: 1,
+        };
+
+        let mut sorter =
+            BamSorter::new(&config, "test", header.clone(), OutputSort::Coordinate).unwrap();
+        let inputs = [(1, 5), (0, 500), (1, 2), (0, 3), (0, 500), (0, 40)];
+        for (i, (tid, pos)) in inputs.iter().enumerate() {
+            sorter
+                .push(record(*tid, *pos, &format!("read{i}")))
+                .unwrap();
+        }
+        assert_eq!(sorter.runs.len(), inputs.len());
+
+        let outfile = tmpdir.join("sorted.bam");
+        let mut writer = Writer::from_path(&outfile, &header, bam::Format::Bam).unwrap();
+        assert_eq!(sorter.finish(&mut writer).unwrap(), inputs.len() as u64);
+        drop(writer);
+
+        let sorted: Vec<(i32, i64, Vec<u8>)> = bam::Reader::from_path(&outfile)
+            .unwrap()
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.tid(), r.pos(), r.qname().to_vec())
+            })
+            .collect();
+
+        let expected: Vec<(i32, i64, Vec<u8>)> = [
+            (0, 3, "read3"),
+            (0, 40, "read5"),
+            (0, 500, "read1"),
+            (0, 500, "read4"),
+            (1, 2, "read2"),
+            (1, 5, "read0"),
+        ]
+        .iter()
+        .map(|(tid, pos, name)| (*tid, *pos, name.as_bytes().to_vec()))
+        .collect();
+
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_sorter_merges_runs_in_passes() {
+        let header = header();
+        let tmpdir = TempDir::new("sorter_passes");
+        let config = SpillConfig {
+            tmpdir: tmpdir.to_path_buf(),
+            max_memory: 1,
+        };
+
+        let mut sorter =
+            BamSorter::new(&config, "test", header.clone(), OutputSort::Coordinate).unwrap();
+        // merge at most 3 runs at a time, so 10 runs take several passes
+        sorter.fan_in = 3;
+        let inputs: Vec<(i32, i64)> = (0..10).map(|i: i32| (i % 2, (i as i64 * 7) % 4)).collect();
+        for (i, (tid, pos)) in inputs.iter().enumerate() {
+            sorter
+                .push(record(*tid, *pos, &format!("read{i}")))
+                .unwrap();
+        }
+
+        let outfile = tmpdir.join("sorted.bam");
+        let mut writer = Writer::from_path(&outfile, &header, bam::Format::Bam).unwrap();
+        assert_eq!(sorter.finish(&mut writer).unwrap(), inputs.len() as u64);
+        drop(writer);
+
+        let sorted: Vec<(i32, i64, Vec<u8>)> = bam::Reader::from_path(&outfile)
+            .unwrap()
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.tid(), r.pos(), r.qname().to_vec())
+            })
+            .collect();
+
+        // equal reads keep their input order
+        let mut expected: Vec<(i32, i64, Vec<u8>)> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, (tid, pos))| (*tid, *pos, format!("read{i}").into_bytes()))
+            .collect();
+        expected.sort_by_key(|(tid, pos, _)| (*tid, *pos));
+
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_with_sort_order_replaces_hd() {
+        let mut header = header();
+        let mut hd = bam::header::HeaderRecord::new(b"HD");
+        hd.push_tag(b"VN", "1.4").push_tag(b"SO", "unsorted");
+        header.push_record(&hd);
+
+        let sorted = with_sort_order(&header, OutputSort::TemplateCoordinate);
+        let text = String::from_utf8(sorted.to_bytes()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "@HD\tVN:1.4\tSO:unsorted\tGO:query\tSS:unsorted:template-coordinate"
+        );
+        assert_eq!(lines.iter().filter(|l| l.starts_with("@HD")).count(), 1);
+        assert_eq!(lines.iter().filter(|l| l.starts_with("@SQ")).count(), 2);
+    }
+
+    #[test]
+    fn test_sort_bam_makes_indexable() {
+        let header = header();
+        let tmpdir = TempDir::new("sort_bam");
+        let bam_name = tmpdir.join("unsorted.bam").to_string_lossy().to_string();
+
+        let reads = [(1, 5), (0, 500), (0, 3)]
+            .iter()
+            .enumerate()
+            .map(|(i, (tid, pos))| record(*tid, *pos, &format!("read{i}")));
+        write_bam(Path::new(&bam_name), &header, reads);
+        assert!(crate::utils::_index_bam(&bam_name, 1, false).is_err());
+
+        let config = SpillConfig {
+            tmpdir: tmpdir.to_path_buf(),
+            max_memory: 1,
+        };
+        sort_bam(&bam_name, 1, &config, None).unwrap();
+        assert!(crate::utils::_index_bam(&bam_name, 1, false).is_ok());
+    }
+}
@@ -1,25 +1,33 @@
 use crate::cli::DedupArgs;
+use crate::io::fastq_output::FastqOutput;
 use crate::io::fastqio::{FastqInput, WritesFastqRecords};
 use crate::io::FileIO;
-use crate::record::FastqRecord;
+use crate::record::{FastqPair, FastqRecord};
+use crate::utils::RecordFile;
 use anyhow::Error;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use super::fastq_writer::create_writer_from_file;
 
 pub struct FastqIO {
     pub reader: Option<FastqInput>,
-    pub _mate_reader: Option<FastqInput>,
+    pub mate_reader: Option<FastqInput>,
     pub writer: Box<dyn WritesFastqRecords>,
+    pub mate_writer: Option<Box<dyn WritesFastqRecords>>,
+    pub unmerged_writer: Option<FastqOutput>,
+    pub num_threads: usize,
     pub _separator: String,
 }
 
 impl FastqIO {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         infile_name: &str,
+        mate_infile_name: Option<&str>,
         outfile_name: &str,
-        retrieve_r2s: bool,
+        mate_outfile_name: Option<&str>,
+        unmerged_outfiles: Option<(PathBuf, PathBuf)>,
         num_threads: usize,
         strict_threads: bool,
         _separator: String,
@@ -31,30 +39,54 @@ impl FastqIO {
         };
         let reader = Some(FastqInput::from_file(Path::new(infile_name))?);
 
-        let _mate_reader = match retrieve_r2s {
-            true => Some(FastqInput::from_file(Path::new(infile_name))?),
-            false => None,
+        let mate_reader = match mate_infile_name {
+            Some(mate_infile_name) => Some(FastqInput::from_file(Path::new(mate_infile_name))?),
+            None => None,
         };
 
         let writer = create_writer_from_file(Path::new(outfile_name), num_threads)?;
 
+        let mate_writer = match mate_outfile_name {
+            Some(mate_outfile_name) => Some(create_writer_from_file(
+                Path::new(mate_outfile_name),
+                num_threads,
+            )?),
+            None => None,
+        };
+
+        let unmerged_writer = match unmerged_outfiles {
+            Some((out1, out2)) => Some(FastqOutput::init_from_outputs(
+                &out1,
+                &Some(out2),
+                1e4 as usize,
+            )?),
+            None => None,
+        };
+
         Ok(Self {
             reader,
-            _mate_reader,
+            mate_reader,
             writer,
+            mate_writer,
+            unmerged_writer,
+            num_threads,
             _separator,
         })
     }
 
     pub fn init_from_args(
         args: &DedupArgs,
-        infile_path: &str,
+        infile: &RecordFile,
         outfile_path: &str,
+        mate_outfile_path: Option<&str>,
+        unmerged_outfiles: Option<(PathBuf, PathBuf)>,
     ) -> Result<Self, Error> {
         Self::new(
-            infile_path,
+            &infile.fpath,
+            infile.mate_path.as_deref(),
             outfile_path,
-            args.paired,
+            mate_outfile_path,
+            unmerged_outfiles,
             args.threads,
             args.strict_threads,
             args.separator.clone(),
@@ -63,9 +95,25 @@ impl FastqIO {
 }
 
 impl FileIO<FastqRecord> for FastqIO {
-    fn write_reads(&mut self, outreads: &mut Vec<FastqRecord>) {
+    fn write_reads(&mut self, outreads: &mut Vec<FastqRecord>) -> Result<(), Error> {
         for read in outreads.drain(..) {
-            self.writer.write_record(read).unwrap()
+            self.writer.write_record(read)?;
         }
+
+        Ok(())
+    }
+}
+
+impl FileIO<FastqPair> for FastqIO {
+    fn write_reads(&mut self, outreads: &mut Vec<FastqPair>) -> Result<(), Error> {
+        let mate_writer = self.mate_writer.as_mut().expect("R2 writer uninitialized");
+
+        for pair in outreads.drain(..) {
+            let (r1, r2) = pair.into_mates();
+            self.writer.write_record(r1)?;
+            mate_writer.write_record(r2)?;
+        }
+
+        Ok(())
     }
 }
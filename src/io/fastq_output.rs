@@ -1,3 +1,4 @@
+use crate::file_log;
 use crate::io::fastq_writer::ChanneledFastqWriter;
 
 use anyhow::{Context, Error};
@@ -88,7 +89,7 @@ impl FastqOutput {
         let write_threshold =
             std::cmp::min((self.batch_size as f32 * 0.9) as usize, MIN_WRITE_THRESHOLD);
 
-        self.join_handle = Some(std::thread::spawn(move || -> Result<(), Error> {
+        self.join_handle = Some(file_log::spawn(move || -> Result<(), Error> {
             let mut buffer: Vec<IntakeOrdered> = Vec::with_capacity(batch_size);
             let mut next_expected_order = 0;
 
@@ -96,7 +97,7 @@ impl FastqOutput {
                 buffer.push(r);
 
                 if buffer.len() >= write_threshold {
-                    buffer.sort_by(|a, b| a.order.cmp(&b.order));
+                    buffer.sort_by_key(|a| a.order);
 
                     let mut processable_count = 0;
                     for item in &buffer {
@@ -121,7 +122,7 @@ impl FastqOutput {
                 }
             }
 
-            buffer.sort_by(|a, b| a.order.cmp(&b.order));
+            buffer.sort_by_key(|a| a.order);
 
             for i in buffer.drain(..) {
                 if let Some(r1) = i.pair.r1 {
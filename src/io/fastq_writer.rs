@@ -1,5 +1,7 @@
+use crate::file_log;
 use crate::io::fastqio::{
-    fastq_create_writer_compressed, fastq_create_writer_decompressed, WritesFastqRecords,
+    fastq_create_writer_compressed, fastq_create_writer_decompressed, is_gzip_path,
+    WritesFastqRecords,
 };
 use anyhow::{Context, Error};
 use bio::io::fastq::Record;
@@ -25,12 +27,7 @@ pub fn create_writer_from_file(
     infile: &Path,
     threads: usize,
 ) -> Result<Box<dyn WritesFastqRecords>, Error> {
-    let is_gzip = match infile.to_str().unwrap().rsplit_once(".") {
-        None => anyhow::bail!("Unable to detect file format!"),
-        Some((_pre, end)) => end == "gz",
-    };
-
-    let writer: Box<dyn WritesFastqRecords> = if is_gzip {
+    let writer: Box<dyn WritesFastqRecords> = if is_gzip_path(infile)? {
         Box::new(fastq_create_writer_compressed(infile, threads)?)
     } else {
         Box::new(fastq_create_writer_decompressed(infile, threads)?)
@@ -50,16 +47,13 @@ impl InnerFastqWriter {
         let chunk_size = self.chunk_size;
         let outfile = self.outfile.clone();
 
-        let is_gzip = match self.outfile.to_str().unwrap().rsplit_once(".") {
-            None => anyhow::bail!("Unable to detect file format!"),
-            Some((_pre, end)) => end == "gz",
-        };
+        let is_gzip = is_gzip_path(&self.outfile)?;
 
         let (s, r): (Sender<Record>, Receiver<Record>) = unbounded();
 
         Ok((
             s,
-            std::thread::spawn(move || -> Result<u32, Error> {
+            file_log::spawn(move || -> Result<u32, Error> {
                 let mut writer: Box<dyn WritesFastqRecords>;
                 if is_gzip {
                     writer = Box::new(
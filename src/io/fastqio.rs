@@ -4,7 +4,7 @@ use bio::io::fastq::{Reader, Record, Records, Writer};
 use flate2::read::GzDecoder;
 use std::{
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
 };
 
@@ -28,22 +28,38 @@ pub trait FastqRecordIterator {
     fn next_record(&mut self) -> Option<Result<FastqRecord, Error>>;
 }
 
-/// for reading plaintext fastq files
-impl FastqRecordIterator for Records<BufReader<File>> {
+/// for reading plaintext or gzipped fastq files, or either from stdin
+impl<R: Read> FastqRecordIterator for Records<BufReader<R>> {
     fn next_record(&mut self) -> Option<Result<FastqRecord, Error>> {
         self.next().map(|e| e.map_err(anyhow::Error::msg))
     }
 }
 
-/// For reading gzipped-fastq files
-impl FastqRecordIterator for Records<BufReader<GzDecoder<File>>> {
-    fn next_record(&mut self) -> Option<Result<FastqRecord, Error>> {
-        self.next().map(|e| e.map_err(anyhow::Error::msg))
-    }
+pub type CompressedFastqWriter = Box<dyn ZWriter>;
+pub type DecompressedFastqWriter = Writer<Box<dyn Write + Send>>;
+
+/// The path standing in for stdin as an input, or stdout as an output.
+pub const STDIO_PATH: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
 }
 
-pub type CompressedFastqWriter = Box<dyn ZWriter>;
-pub type DecompressedFastqWriter = Writer<File>;
+/// Whether a fastq path is gzipped, judging by its extension. Output to stdout is plaintext.
+pub fn is_gzip_path(path: &Path) -> Result<bool, Error> {
+    if is_stdio(path) {
+        return Ok(false);
+    }
+
+    match path
+        .to_str()
+        .context("failed to convert file name to string")?
+        .rsplit_once(".")
+    {
+        None => anyhow::bail!("Unable to detect file format!"),
+        Some((_pre, end)) => Ok(end == "gz"),
+    }
+}
 
 /// an interface for writing compressed and plaintext fastq records to file.
 pub trait WritesFastqRecords {
@@ -94,12 +110,19 @@ fn fastq_create_reader_decompressed(infile: PathBuf) -> Result<Records<BufReader
     Ok(reader.records())
 }
 
+// open a file for writing, or stdout.
+fn create_output(outfile: &Path) -> Result<Box<dyn Write + Send>, Error> {
+    match is_stdio(outfile) {
+        true => Ok(Box::new(stdout())),
+        false => Ok(Box::new(File::create(outfile)?)),
+    }
+}
+
 pub fn fastq_create_writer_compressed(
     outfile: &Path,
     threads: usize,
 ) -> Result<CompressedFastqWriter, Error> {
-    let fhandle = File::create(outfile)?;
-    let bufwriter = BufWriter::new(fhandle);
+    let bufwriter = BufWriter::new(create_output(outfile)?);
 
     let writer = ZBuilder::<Gzip, _>::new()
         .num_threads(4)
@@ -111,8 +134,7 @@ pub fn fastq_create_writer_decompressed(
     outfile: &Path,
     _threads: usize,
 ) -> Result<DecompressedFastqWriter, Error> {
-    let fhandle = File::create(outfile)?;
-    let bufwriter = BufWriter::new(fhandle);
+    let bufwriter = BufWriter::new(create_output(outfile)?);
     let writer = Writer::from_bufwriter(bufwriter);
 
     Ok(writer)
@@ -129,17 +151,12 @@ pub struct FastqInput {
 impl FastqInput {
     /// Create an input record iterator, detecting whether or not records need to be decompressed.
     pub fn from_file(infile: &Path) -> Result<Self, Error> {
-        // Create reader
-        let is_gzip = match infile
-            .to_str()
-            .context("failed to convert file name to string")?
-            .rsplit_once(".")
-        {
-            None => anyhow::bail!("Unable to detect file format!"),
-            Some((_pre, end)) => end == "gz",
-        };
+        if is_stdio(infile) {
+            return Self::from_stdin();
+        }
 
-        match is_gzip {
+        // Create reader
+        match is_gzip_path(infile)? {
             true => {
                 let records = fastq_create_reader_compressed(infile.to_path_buf())?;
                 Ok(Self {
@@ -156,4 +173,24 @@ impl FastqInput {
             }
         }
     }
+
+    /// Read records from stdin. Without an extension to go by, gzipped input is recognized by its
+    /// magic bytes.
+    fn from_stdin() -> Result<Self, Error> {
+        let mut input = BufReader::new(stdin());
+        let is_gzip = input
+            .fill_buf()
+            .context("Failed to read from stdin")?
+            .starts_with(&[0x1f, 0x8b]);
+
+        let records: Box<dyn FastqRecordIterator> = match is_gzip {
+            true => Box::new(Reader::new(GzDecoder::new(input)).records()),
+            false => Box::new(Reader::new(input).records()),
+        };
+
+        Ok(Self {
+            records,
+            _infile: PathBuf::from(STDIO_PATH),
+        })
+    }
 }
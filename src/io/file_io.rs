@@ -1,19 +1,41 @@
-use crate::cli::print_file_info;
-use crate::cli::DedupArgs;
+use crate::cli::{print_file_info, print_file_summary};
+use crate::cli::{AlignmentFormat, DedupArgs, GroupingMethod};
+use crate::file_log::FileLog;
+use crate::group_report::GroupReport;
+use crate::io::fastqio::STDIO_PATH;
+use crate::io::samplesheet::{read_samplesheet, Sample};
 use crate::process::{BamFileProcess, FastQFileProcess, FileProcess};
 use crate::record::SequenceRecord;
-use crate::utils::{identify_file_type, FileType};
+use crate::utils::{identify_file_type, FileType, RecordFile};
 use anyhow::{Context, Error, Result};
+use clap::ValueEnum;
+use crossbeam::channel::unbounded;
+use rayon::ThreadPoolBuilder;
 use std::fs::read_dir;
 use std::path::Path;
+use std::thread;
+use std::time::Instant;
 
 pub trait FileIO<T: SequenceRecord> {
-    fn write_reads(&mut self, outreads: &mut Vec<T>);
+    fn write_reads(&mut self, outreads: &mut Vec<T>) -> Result<(), Error>;
 }
 
-pub fn gather_files(input_file: &str) -> Result<Vec<FileType>, anyhow::Error> {
+pub fn gather_files(
+    input_file: &str,
+    mate_file: Option<&String>,
+) -> Result<Vec<FileType>, anyhow::Error> {
     let inpath = Path::new(input_file);
 
+    // a stream from stdin is always read as alignments
+    if input_file == STDIO_PATH {
+        return Ok(vec![FileType::BamFile(RecordFile {
+            fname: STDIO_PATH.to_string(),
+            fpath: STDIO_PATH.to_string(),
+            mate_path: None,
+            sample: None,
+        })]);
+    }
+
     if inpath.is_dir() {
         let entries = read_dir(inpath)
             .with_context(|| format!("Failed to read directory: {}", input_file))?;
@@ -24,8 +46,12 @@ pub fn gather_files(input_file: &str) -> Result<Vec<FileType>, anyhow::Error> {
                 let entry = entry.ok()?;
                 let path = entry.path();
 
-                // Ignore BAM indexes
-                if path.to_string_lossy().ends_with(".bai") {
+                // Ignore BAM/CRAM indexes
+                let path_str = path.to_string_lossy();
+                if [".bai", ".csi", ".crai"]
+                    .iter()
+                    .any(|ext| path_str.ends_with(ext))
+                {
                     return None;
                 }
 
@@ -33,11 +59,11 @@ pub fn gather_files(input_file: &str) -> Result<Vec<FileType>, anyhow::Error> {
                     if let Some(ftype) = identify_file_type(&path) {
                         Some(ftype)
                     } else {
-                        println! {"Skipping file {:?}; unrecognized extension", entry.file_name()};
+                        eprintln! {"Skipping file {:?}; unrecognized extension", entry.file_name()};
                         None
                     }
                 } else {
-                    println! {"Skipping folder {:?}", entry.file_name()};
+                    eprintln! {"Skipping folder {:?}", entry.file_name()};
                     None
                 }
             })
@@ -47,38 +73,194 @@ pub fn gather_files(input_file: &str) -> Result<Vec<FileType>, anyhow::Error> {
             return Err(anyhow::anyhow!("File does not exist: {}", input_file));
         }
 
-        Ok(std::iter::once(
-            identify_file_type(inpath)
-                .ok_or_else(|| anyhow::anyhow!("Unrecognized file extension: {}", input_file)),
-        )
-        .flatten()
-        .collect())
+        let mut ftype = identify_file_type(inpath)
+            .ok_or_else(|| anyhow::anyhow!("Unrecognized file extension: {}", input_file))?;
+
+        if let (FileType::FastqFile(f), Some(mate_file)) = (&mut ftype, mate_file) {
+            if !Path::new(mate_file).exists() {
+                return Err(anyhow::anyhow!("File does not exist: {}", mate_file));
+            }
+            f.mate_path = Some(mate_file.to_string());
+        }
+
+        Ok(vec![ftype])
     }
 }
 
-pub fn process_all(args: &DedupArgs, file_map: Vec<FileType>) -> Vec<Result<(), Error>> {
+/// Collect the samples of a sample sheet, each with its own options: those of the command line,
+/// with the sample's overrides.
+pub fn gather_samples(
+    args: &DedupArgs,
+    samplesheet: &str,
+) -> Result<Vec<(FileType, DedupArgs)>, Error> {
+    read_samplesheet(samplesheet)?
+        .into_iter()
+        .map(|sample| {
+            let sample_args = sample_args(args, &sample)
+                .with_context(|| format!("Invalid options for sample {}", sample.name))?;
+
+            let (fpath, ext) = match (&sample.r1, &sample.bam) {
+                (Some(r1), _) => (r1.clone(), ".fastq.gz".to_string()),
+                (None, Some(bam)) => {
+                    let format = AlignmentFormat::from_path(bam).with_context(|| {
+                        format!("Unrecognized extension of {bam}, sample {}", sample.name)
+                    })?;
+                    (bam.clone(), format!(".{}", format.extension()))
+                }
+                (None, None) => unreachable!("samples have an r1 or a bam"),
+            };
+
+            // paired outputs are named after the sample, with _R1 and _R2
+            let fname = match sample.r2 {
+                Some(_) => format!("{}_R1{ext}", sample.name),
+                None => format!("{}{ext}", sample.name),
+            };
+            let record_file = RecordFile {
+                fname,
+                fpath,
+                mate_path: sample.r2.clone(),
+                sample: Some(sample.name.clone()),
+            };
+
+            let file = match sample.r1 {
+                Some(_) => FileType::FastqFile(record_file),
+                None => FileType::BamFile(record_file),
+            };
+            Ok((file, sample_args))
+        })
+        .collect()
+}
+
+// options of the command line, with those a sample overrides
+fn sample_args(args: &DedupArgs, sample: &Sample) -> Result<DedupArgs, Error> {
+    let mut sample_args = args.clone();
+    sample_args.samplesheet = None;
+    sample_args.input = sample.r1.clone().or(sample.bam.clone());
+    sample_args.input2 = sample.r2.clone();
+
+    if let Some(separator) = sample.get_str("separator") {
+        sample_args.separator = separator.to_string();
+    }
+    if let Some(method) = sample.get_str("grouping_method") {
+        sample_args.grouping_method = <GroupingMethod as ValueEnum>::from_str(method, true)
+            .map_err(|_| anyhow::anyhow!("Invalid grouping_method {method}"))?;
+    }
+    if let Some(min_depth) = sample.get("min_depth")? {
+        sample_args.min_cluster_depth = min_depth;
+        sample_args.singletons = false;
+    }
+    if let Some(max_edit) = sample.get("max_edit")? {
+        sample_args.max_edit = max_edit;
+    }
+    if let Some(percentage) = sample.get("percentage")? {
+        sample_args.percentage = percentage;
+    }
+
+    sample_args.validate()?;
+    Ok(sample_args)
+}
+
+/// Deduplicate each file with its options.
+pub fn process_all(
+    args: &DedupArgs,
+    file_map: Vec<(FileType, DedupArgs)>,
+) -> Vec<Result<(), Error>> {
     let num_files = file_map.len();
+    let parallel_files = args.parallel_files.min(num_files);
+    if parallel_files > 1 {
+        return process_parallel(file_map, parallel_files);
+    }
+
     let mut results = vec![];
 
-    for (i, file) in file_map.into_iter().enumerate() {
-        let p = match file {
-            FileType::BamFile(f) => {
-                print_file_info(&f.fname, i + 1, num_files);
-                BamFileProcess::init_from_args(args, &f.fpath, &f.fname)
-                    .and_then(|process| process.process())
-                    .with_context(|| format!("Failed to process file {}", &f.fname))
-            }
+    for (i, (file, file_args)) in file_map.into_iter().enumerate() {
+        print_file_info(&file_name(&file).to_string(), i + 1, num_files);
+        results.push(process_file(&file_args, file).map(|_| ()));
+    }
 
-            FileType::FastqFile(f) => {
-                print_file_info(&f.fname, i + 1, num_files);
-                FastQFileProcess::init_from_args(args, &f.fpath, &f.fname)
-                    .and_then(|process| process.process())
-                    .with_context(|| format!("Failed to process file {}", &f.fname))
-            }
-        };
+    results
+}
+
+/// Deduplicate files `parallel_files` at a time, in order as earlier ones finish. Each file gets
+/// an equal share of the threads, for its thread pool and for htslib alike, and logs to its own
+/// file (see [FileLog]), leaving a summary line per file on the console.
+fn process_parallel(
+    file_map: Vec<(FileType, DedupArgs)>,
+    parallel_files: usize,
+) -> Vec<Result<(), Error>> {
+    let num_files = file_map.len();
 
-        results.push(p);
+    let (file_s, file_r) = unbounded();
+    for (i, (file, mut file_args)) in file_map.into_iter().enumerate() {
+        file_args.threads = (file_args.threads / parallel_files).max(1);
+        // otherwise htslib would take all cores for each file
+        file_args.strict_threads = true;
+        file_args.progress = false;
+        file_s
+            .send((i, file, file_args))
+            .expect("File queue disconnected");
     }
+    drop(file_s);
 
-    results
+    let mut results: Vec<(usize, Result<(), Error>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel_files)
+            .map(|_| {
+                scope.spawn(|| {
+                    file_r
+                        .iter()
+                        .map(|(i, file, file_args)| {
+                            let fname = file_name(&file).to_string();
+                            let start = Instant::now();
+                            let result = process_logged(&file_args, file);
+                            print_file_summary(&fname, i + 1, num_files, &result, start.elapsed());
+                            (i, result.map(|_| ()))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("File worker panicked"))
+            .collect()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Deduplicate a file in a thread pool of its own, whose threads log to the file's log.
+fn process_logged(args: &DedupArgs, file: FileType) -> Result<GroupReport, Error> {
+    // named after the whole file name, so that e.g. a.bam and a.fastq.gz log apart
+    let fname = file_name(&file).to_string();
+    let log_path = Path::new(&args.outdir).join(format!("{fname}_rumina_group.log"));
+
+    let log = FileLog::create(&log_path)
+        .with_context(|| format!("Unable to create log file {}", log_path.display()))?;
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .start_handler(move |_| log.enter())
+        .build()
+        .context("Thread pool building failed")?;
+
+    pool.install(|| process_file(args, file))
+}
+
+fn process_file(args: &DedupArgs, file: FileType) -> Result<GroupReport, Error> {
+    match file {
+        FileType::BamFile(f) => BamFileProcess::init_from_args(args, &f)
+            .and_then(|process| process.process())
+            .with_context(|| format!("Failed to process file {}", &f.fname)),
+
+        FileType::FastqFile(f) => FastQFileProcess::init_from_args(args, &f)
+            .and_then(|process| process.process())
+            .with_context(|| format!("Failed to process file {}", &f.fname)),
+    }
+}
+
+fn file_name(file: &FileType) -> &str {
+    match file {
+        FileType::BamFile(f) | FileType::FastqFile(f) => &f.fname,
+    }
 }
@@ -0,0 +1,122 @@
+use rust_htslib::bam::{Header, HeaderView};
+use std::collections::HashSet;
+
+/// Name of rumina in `@PG` lines, and the ID of its `@PG` line unless the input already has one.
+const PROGRAM_NAME: &str = "rumina";
+
+/// The command line rumina was run with, with arguments containing whitespace quoted.
+pub fn command_line() -> String {
+    std::env::args()
+        .map(|arg| match arg.contains(char::is_whitespace) {
+            true => format!("'{arg}'"),
+            false => arg,
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Copy a header, appending a `@PG` line for this run of rumina and a `@CO` line for each
+/// comment. The `@PG` line is chained (`PP`) to the last program to process the input, and given
+/// an ID of its own if rumina has processed the input before, as `samtools` does.
+pub fn with_program(header: &Header, command_line: &str, comments: &[String]) -> Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).to_string();
+    let mut lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+
+    let tag = |line: &str, tag: &str| {
+        line.split('\t')
+            .find_map(|field| field.strip_prefix(tag))
+            .map(String::from)
+    };
+    let programs: Vec<&String> = lines
+        .iter()
+        .filter(|line| line.starts_with("@PG"))
+        .collect();
+    let ids: Vec<String> = programs
+        .iter()
+        .filter_map(|line| tag(line, "ID:"))
+        .collect();
+    let parents: HashSet<String> = programs
+        .iter()
+        .filter_map(|line| tag(line, "PP:"))
+        .collect();
+
+    // the last program no other program follows
+    let previous = ids.iter().rev().find(|id| !parents.contains(*id));
+
+    let mut id = PROGRAM_NAME.to_string();
+    let mut suffix = 0;
+    while ids.contains(&id) {
+        suffix += 1;
+        id = format!("{PROGRAM_NAME}.{suffix}");
+    }
+
+    let mut program = format!(
+        "@PG\tID:{id}\tPN:{PROGRAM_NAME}\tVN:{}",
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some(previous) = previous {
+        program.push_str(&format!("\tPP:{previous}"));
+    }
+    // header fields can't span lines or hold tabs
+    program.push_str(&format!("\tCL:{}", command_line.replace(['\t', '\n'], " ")));
+
+    lines.push(program);
+    lines.extend(
+        comments
+            .iter()
+            .map(|comment| format!("@CO\t{}", comment.replace('\n', " "))),
+    );
+
+    Header::from_template(&HeaderView::from_bytes(lines.join("\n").as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program_lines(header: &Header) -> Vec<String> {
+        String::from_utf8(header.to_bytes())
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with("@PG") || line.starts_with("@CO"))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_with_program() {
+        let input = Header::from_template(&HeaderView::from_bytes(
+            b"@HD\tVN:1.6\tSO:coordinate\n\
+            @SQ\tSN:chr1\tLN:1000\n\
+            @PG\tID:bwa\tPN:bwa\tCL:bwa mem\n\
+            @PG\tID:samtools\tPN:samtools\tPP:bwa\tCL:samtools sort",
+        ));
+
+        let once = with_program(&input, "rumina dedup\t-i x.bam", &["grouping".to_string()]);
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            program_lines(&once)[2..],
+            [
+                format!(
+                    "@PG\tID:rumina\tPN:rumina\tVN:{version}\tPP:samtools\tCL:rumina dedup -i x.bam"
+                ),
+                "@CO\tgrouping".to_string(),
+            ]
+        );
+
+        // a second run chains to the first
+        let twice = with_program(&once, "rumina dedup", &[]);
+        assert_eq!(
+            program_lines(&twice).last().unwrap(),
+            &format!("@PG\tID:rumina.1\tPN:rumina\tVN:{version}\tPP:rumina\tCL:rumina dedup")
+        );
+
+        // without a previous program, the line has no PP
+        let bare = Header::from_template(&HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000"));
+        assert!(!program_lines(&with_program(&bare, "rumina", &[]))[0].contains("PP:"));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::utils::Window;
+use anyhow::{Context, Error};
+use rust_htslib::bam::HeaderView;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// An interval of a reference to deduplicate, given with `--region` or `--regions-bed`.
+/// Coordinates are 0-based and half-open, as in BED.
+///
+/// A UMI family belongs to a region if it is grouped at a position within it (see
+/// [crate::record::SequenceRecord::get_pos_key]), even if some of its reads start outside.
+/// Families grouped where regions overlap belong to the region starting first, so that no family
+/// is output twice; `owned` is the part of the region left to it.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub contig: String,
+    pub tid: u32,
+    pub start: i64,
+    pub end: i64,
+    pub owned: Window,
+}
+
+impl Region {
+    pub(crate) fn new(
+        name: Option<String>,
+        contig: &str,
+        start: i64,
+        end: i64,
+        header: &HeaderView,
+    ) -> Result<Self, Error> {
+        let tid = header
+            .tid(contig.as_bytes())
+            .with_context(|| format!("Region reference {contig} is not in the input's header"))?;
+
+        // regions running past the end of a reference are cut short
+        let end = end.min(header.target_len(tid).unwrap_or(u64::MAX) as i64);
+        if start < 0 || start >= end {
+            anyhow::bail!("Region {contig}:{}-{end} is empty", start + 1)
+        }
+
+        Ok(Self {
+            name: name.unwrap_or_else(|| format!("{contig}:{}-{end}", start + 1)),
+            contig: contig.to_string(),
+            tid,
+            start,
+            end,
+            owned: Window { start, end },
+        })
+    }
+}
+
+/// Collect the regions given with `--region` and `--regions-bed`, sorted by coordinate.
+pub fn parse_regions(
+    regions: &[String],
+    bed: Option<&str>,
+    header: &HeaderView,
+) -> Result<Vec<Region>, Error> {
+    let mut parsed = regions
+        .iter()
+        .map(|region| parse_region(region, header))
+        .collect::<Result<Vec<Region>, Error>>()?;
+
+    if let Some(bed) = bed {
+        parsed.extend(read_bed(bed, header)?);
+    }
+
+    parsed.sort_by_key(|region| (region.tid, region.start, region.end));
+
+    // hand the overlap of two regions to the first
+    let mut prev: Option<(u32, i64)> = None;
+    for region in parsed.iter_mut() {
+        if let Some((tid, end)) = prev.filter(|(tid, _)| *tid == region.tid) {
+            region.owned.start = region.owned.start.max(end).min(region.end);
+            prev = Some((tid, end.max(region.end)));
+        } else {
+            prev = Some((region.tid, region.end));
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Parse a samtools-style region: `chr`, `chr:start` or `chr:start-end`, 1-based and inclusive.
+fn parse_region(region: &str, header: &HeaderView) -> Result<Region, Error> {
+    // references may themselves contain colons, so try the whole string first
+    if header.tid(region.as_bytes()).is_some() {
+        return Region::new(Some(region.to_string()), region, 0, i64::MAX, header);
+    }
+
+    let (contig, range) = region
+        .rsplit_once(':')
+        .with_context(|| format!("Invalid region {region}: expected chr:start-end"))?;
+
+    let parse_pos = |pos: &str| {
+        pos.replace(',', "")
+            .parse::<i64>()
+            .with_context(|| format!("Invalid region {region}: {pos} is not a position"))
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_pos(start)?, parse_pos(end)?),
+        None => (parse_pos(range)?, i64::MAX),
+    };
+
+    Region::new(Some(region.to_string()), contig, start - 1, end, header)
+}
+
+/// Read regions from a BED file, named after the fourth column if it exists.
+fn read_bed(path: &str, header: &HeaderView) -> Result<Vec<Region>, Error> {
+    let file = File::open(path).with_context(|| format!("Failed to open BED file {path}"))?;
+    let mut regions = Vec::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read BED file {path}"))?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            anyhow::bail!("{path}, line {}: expected at least 3 columns", i + 1)
+        }
+
+        let parse_pos = |pos: &str| {
+            pos.trim()
+                .parse::<i64>()
+                .with_context(|| format!("{path}, line {}: {pos} is not a position", i + 1))
+        };
+        let name = fields.get(3).map(|name| name.trim().to_string());
+
+        let region = Region::new(
+            name,
+            fields[0],
+            parse_pos(fields[1])?,
+            parse_pos(fields[2])?,
+            header,
+        )
+        .with_context(|| format!("{path}, line {}", i + 1))?;
+        regions.push(region);
+    }
+
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{header_with, TempDir};
+
+    fn test_header() -> HeaderView {
+        HeaderView::from_header(&header_with(&[("chr1", 2000), ("chr2", 500)]))
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        let header = test_header();
+        let dir = TempDir::new("regions");
+        let bed = dir.join("regions.bed");
+        std::fs::write(
+            &bed,
+            "track name=amplicons\nchr1\t150\t300\tamp2\nchr1\t100\t200\tamp1\nchr1\t160\t180\n",
+        )
+        .unwrap();
+
+        let regions = parse_regions(
+            &["chr2".to_string(), "chr1:1,001-3000".to_string()],
+            bed.to_str(),
+            &header,
+        )
+        .unwrap();
+
+        let summary: Vec<(&str, u32, i64, i64, i64, i64)> = regions
+            .iter()
+            .map(|r| {
+                (
+                    r.name.as_str(),
+                    r.tid,
+                    r.start,
+                    r.end,
+                    r.owned.start,
+                    r.owned.end,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("amp1", 0, 100, 200, 100, 200),
+                // overlaps are owned by the region starting first
+                ("amp2", 0, 150, 300, 200, 300),
+                ("chr1:161-180", 0, 160, 180, 180, 180),
+                ("chr1:1,001-3000", 0, 1000, 2000, 1000, 2000),
+                ("chr2", 1, 0, 500, 0, 500),
+            ]
+        );
+
+        // regions past the end of a reference are empty
+        assert!(parse_regions(&["chr1:2001-3000".to_string()], None, &header).is_err());
+        assert!(parse_regions(&["chr3:1-10".to_string()], None, &header).is_err());
+    }
+}
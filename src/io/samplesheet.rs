@@ -0,0 +1,196 @@
+use anyhow::{Context, Error};
+use indexmap::IndexMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Columns naming a sample's input files.
+const FILE_COLUMNS: [&str; 3] = ["r1", "r2", "bam"];
+
+/// Columns overriding an option for one sample. `pattern` and `pattern2` apply to `extract`,
+/// `grouping_method`, `min_depth`, `max_edit` and `percentage` to `dedup`, and `separator` to both.
+const OVERRIDE_COLUMNS: [&str; 7] = [
+    "separator",
+    "pattern",
+    "pattern2",
+    "grouping_method",
+    "min_depth",
+    "max_edit",
+    "percentage",
+];
+
+/// A sample of a sample sheet given with `--samplesheet`: its input files (FASTQ R1, with an
+/// optional R2, or an alignment file), and options it overrides. Outputs are named after the
+/// sample rather than the input files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub r1: Option<String>,
+    pub r2: Option<String>,
+    pub bam: Option<String>,
+    overrides: IndexMap<String, String>,
+}
+
+impl Sample {
+    /// The value of an override column for this sample, if given.
+    pub fn get<T: FromStr>(&self, column: &str) -> Result<Option<T>, Error> {
+        self.overrides
+            .get(column)
+            .map(|value| {
+                value.parse::<T>().map_err(|_| {
+                    anyhow::anyhow!("Invalid {column} '{value}' for sample {}", self.name)
+                })
+            })
+            .transpose()
+    }
+
+    pub fn get_str(&self, column: &str) -> Option<&str> {
+        self.overrides.get(column).map(String::as_str)
+    }
+}
+
+/// Read a sample sheet: a CSV file with a header row naming its columns. `sample` is required,
+/// as is either `r1` or `bam` for each sample; other columns may be left out, or left empty for
+/// samples they don't apply to. Lines starting with `#` are ignored. File paths are relative to the
+/// current directory.
+pub fn read_samplesheet(path: &str) -> Result<Vec<Sample>, Error> {
+    let file = File::open(path).with_context(|| format!("Failed to open sample sheet {path}"))?;
+    parse_samplesheet(file).with_context(|| format!("Invalid sample sheet {path}"))
+}
+
+fn parse_samplesheet(reader: impl Read) -> Result<Vec<Sample>, Error> {
+    let mut lines = BufReader::new(reader)
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| {
+            line.as_ref().map_or(true, |line| {
+                !line.trim().is_empty() && !line.starts_with('#')
+            })
+        });
+
+    let columns: Vec<String> = match lines.next() {
+        Some((_, line)) => split_row(&line?),
+        None => anyhow::bail!("No header row"),
+    };
+    for column in &columns {
+        if column != "sample"
+            && !FILE_COLUMNS.contains(&column.as_str())
+            && !OVERRIDE_COLUMNS.contains(&column.as_str())
+        {
+            anyhow::bail!(
+                "Unknown column {column}; expected sample, {}, or {}",
+                FILE_COLUMNS.join(", "),
+                OVERRIDE_COLUMNS.join(", ")
+            )
+        }
+    }
+    if !columns.iter().any(|column| column == "sample") {
+        anyhow::bail!("No sample column")
+    }
+
+    let mut samples = Vec::new();
+    let mut names = HashSet::new();
+    for (line_num, line) in lines {
+        let values = split_row(&line?);
+        if values.len() != columns.len() {
+            anyhow::bail!(
+                "Line {line_num} has {} fields, but the header has {}",
+                values.len(),
+                columns.len()
+            )
+        }
+
+        let mut fields: IndexMap<String, String> = columns
+            .iter()
+            .cloned()
+            .zip(values)
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+
+        let name = fields
+            .swap_remove("sample")
+            .with_context(|| format!("Line {line_num} has no sample name"))?;
+        // outputs are named after samples
+        if name.contains(['/', '\\']) {
+            anyhow::bail!("Sample name {name} on line {line_num} can't hold a path separator")
+        }
+        if !names.insert(name.clone()) {
+            anyhow::bail!("Sample {name} is listed more than once")
+        }
+
+        let r1 = fields.swap_remove("r1");
+        let r2 = fields.swap_remove("r2");
+        let bam = fields.swap_remove("bam");
+        match (&r1, &r2, &bam) {
+            (Some(_), _, None) | (None, None, Some(_)) => (),
+            (None, Some(_), _) => anyhow::bail!("Sample {name} has an r2, but no r1"),
+            (None, None, None) => anyhow::bail!("Sample {name} has neither an r1 nor a bam"),
+            (Some(_), _, Some(_)) => anyhow::bail!("Sample {name} has both an r1 and a bam"),
+        }
+
+        for path in [&r1, &r2, &bam].into_iter().flatten() {
+            if !Path::new(path).exists() {
+                anyhow::bail!("File {path} of sample {name} does not exist")
+            }
+        }
+
+        samples.push(Sample {
+            name,
+            r1,
+            r2,
+            bam,
+            overrides: fields,
+        });
+    }
+
+    Ok(samples)
+}
+
+// fields are trimmed, and may be quoted
+fn split_row(line: &str) -> Vec<String> {
+    line.split(',')
+        .map(|field| field.trim().trim_matches('"').to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_samplesheet() {
+        let sheet = "\
+            # a plate\n\
+            sample,r1,r2,bam,separator,min_depth\n\
+            A1,Cargo.toml,Cargo.toml,,:,\n\
+            \n\
+            B1,,,\"Cargo.lock\",,1\n";
+
+        let samples = parse_samplesheet(sheet.as_bytes()).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].name, "A1");
+        assert_eq!(samples[0].r2.as_deref(), Some("Cargo.toml"));
+        assert_eq!(samples[0].get_str("separator"), Some(":"));
+        assert_eq!(samples[0].get::<usize>("min_depth").unwrap(), None);
+        assert_eq!(samples[1].bam.as_deref(), Some("Cargo.lock"));
+        assert_eq!(samples[1].get::<usize>("min_depth").unwrap(), Some(1));
+        assert!(samples[1].get::<f32>("percentage").unwrap().is_none());
+
+        let invalid = [
+            "sample,r1,lane\nA1,Cargo.toml,1",
+            "r1\nCargo.toml",
+            "sample,r1\nA1,Cargo.toml\nA1,Cargo.toml",
+            "sample,r1,bam\nA1,Cargo.toml,Cargo.toml",
+            "sample,r2\nA1,Cargo.toml",
+            "sample,r1\nA1,missing.fastq",
+            "sample,r1\nA1,Cargo.toml,extra",
+            "sample,r1\nplate/A1,Cargo.toml",
+        ];
+        for sheet in invalid {
+            assert!(parse_samplesheet(sheet.as_bytes()).is_err(), "{sheet}");
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::io::regions::Region;
+use anyhow::{Context, Error};
+use rust_htslib::bam::HeaderView;
+use std::fmt;
+use std::str::FromStr;
+
+/// One of `count` independent jobs an alignment input is split into with `--shard index/count`,
+/// e.g. to run on several cluster nodes. Shards are combined with `rumina merge-shards`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    // 1-based
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    /// Unmapped reads without a reference are left to the last shard.
+    pub fn takes_unmapped(&self) -> bool {
+        self.index == self.count
+    }
+
+    /// Added to the names of output files, e.g. `sample_RUMINA_shard2of4.bam`.
+    pub fn label(&self) -> String {
+        format!("shard{}of{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (index, count) = s
+            .split_once('/')
+            .and_then(|(index, count)| Some((index.parse().ok()?, count.parse().ok()?)))
+            .with_context(|| format!("Invalid shard {s}: expected i/N, e.g. 2/4"))?;
+
+        if count == 0 || index == 0 || index > count {
+            anyhow::bail!("Invalid shard {s}: i must be between 1 and N")
+        }
+        Ok(Self { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// The regions of the input deduplicated by a shard. References are dealt out to shards so that
+/// each gets about as many reads, going by `read_counts` (per tid, e.g. from index stats): the
+/// reference with the most reads goes to the shard with the fewest so far. References holding
+/// more than a shard's share of reads are first cut into pieces of equal length, assuming reads
+/// are spread evenly along them. Regions are returned sorted by coordinate.
+pub fn shard_regions(
+    shard: Shard,
+    header: &HeaderView,
+    read_counts: &[u64],
+) -> Result<Vec<Region>, Error> {
+    let total: u64 = read_counts.iter().sum();
+    let share = total.div_ceil(shard.count as u64).max(1);
+
+    // (tid, start, end, estimated reads)
+    let mut pieces: Vec<(u32, i64, i64, u64)> = Vec::new();
+    for tid in 0..header.target_count() {
+        let len = header.target_len(tid).unwrap_or(0) as i64;
+        let count = read_counts.get(tid as usize).copied().unwrap_or(0);
+        if len == 0 {
+            continue;
+        }
+
+        let num_pieces = count.div_ceil(share).clamp(1, len as u64) as i64;
+        for piece in 0..num_pieces {
+            pieces.push((
+                tid,
+                len * piece / num_pieces,
+                len * (piece + 1) / num_pieces,
+                count / num_pieces as u64,
+            ));
+        }
+    }
+
+    // ties go to the earlier piece, and the earlier shard, so that all shards deal alike
+    pieces.sort_by_key(|&(tid, start, _, count)| (std::cmp::Reverse(count), tid, start));
+    let mut loads = vec![0; shard.count];
+    let mut regions = Vec::new();
+    for (tid, start, end, count) in pieces {
+        let least = (0..shard.count).min_by_key(|&i| loads[i]).unwrap_or(0);
+        loads[least] += count;
+        if least + 1 == shard.index {
+            let contig = String::from_utf8_lossy(header.tid2name(tid)).to_string();
+            regions.push(Region::new(None, &contig, start, end, header)?);
+        }
+    }
+
+    regions.sort_by_key(|region| (region.tid, region.start));
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_regions() {
+        let header = HeaderView::from_bytes(
+            b"@SQ\tSN:chr1\tLN:1000\n\
+            @SQ\tSN:chr2\tLN:500\n\
+            @SQ\tSN:chr3\tLN:300\n\
+            @SQ\tSN:chr4\tLN:100",
+        );
+        let read_counts = [900, 300, 200, 100];
+
+        assert!("0/2".parse::<Shard>().is_err());
+        assert!("3/2".parse::<Shard>().is_err());
+        assert!("1-2".parse::<Shard>().is_err());
+
+        let shards: Vec<Vec<Region>> = (1..=3)
+            .map(|index| {
+                let shard = format!("{index}/3").parse().unwrap();
+                shard_regions(shard, &header, &read_counts).unwrap()
+            })
+            .collect();
+
+        // chr1 holds more than a third of all reads, so is cut in two
+        let names: Vec<Vec<&str>> = shards
+            .iter()
+            .map(|regions| regions.iter().map(|region| region.name.as_str()).collect())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                vec!["chr1:1-500", "chr4:1-100"],
+                vec!["chr1:501-1000"],
+                vec!["chr2:1-500", "chr3:1-300"]
+            ]
+        );
+    }
+}
@@ -3,8 +3,10 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 use crate::args::{Args, Command};
 use crate::cli::*;
+use crate::file_log::RoutedLog;
 use crate::group_report::GroupReport;
-use crate::io::file_io::{gather_files, process_all};
+use crate::io::fastqio::STDIO_PATH;
+use crate::io::file_io::{gather_files, gather_samples, process_all};
 use crate::test::{run_dedup_tests, run_extract_tests};
 use clap::Parser;
 use colored::Colorize;
@@ -13,31 +15,42 @@ use std::fs::create_dir;
 use std::path::Path;
 
 use anyhow::{Context, Error};
-use extract::run_extract;
+use extract::{run_extract, run_extract_samples};
 use log::LevelFilter;
+use merge_shards::run_merge_shards;
 use rayon::ThreadPoolBuilder;
 
 mod args;
 mod bktree;
+mod checkpoint;
 mod cli;
 mod deduplicator;
 mod extract;
+mod file_log;
 mod group_report;
 mod grouper;
 mod io;
 mod merge;
 mod merge_report;
+mod merge_shards;
 mod ngram;
+mod overlap;
 mod pair_merger;
 mod process;
 mod processor;
 mod progbars;
+mod read_filter;
+mod read_groups;
 mod read_picker;
 mod read_store;
 mod readkey;
 mod realign;
 mod record;
+mod rejects;
+mod spill;
 mod test;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 fn main() -> Result<(), Error> {
@@ -46,8 +59,6 @@ fn main() -> Result<(), Error> {
     match args.command {
         Command::Dedup(args) => {
             args.validate()?;
-            let input_file = &args.input;
-
             print_logo();
             print_init(&args);
 
@@ -56,33 +67,54 @@ fn main() -> Result<(), Error> {
                 .build_global()
                 .with_context(|| "Thread pool building failed")?;
 
-            if !Path::exists(Path::new(&args.outdir)) {
-                create_dir(&args.outdir).with_context(|| {
-                    format!("Unable to create output directory {}", &args.outdir)
-                })?;
-            }
+            // output streamed to stdout has no directory to hold logs
+            if args.outdir != STDIO_PATH {
+                if !Path::exists(Path::new(&args.outdir)) {
+                    create_dir(&args.outdir).with_context(|| {
+                        format!("Unable to create output directory {}", &args.outdir)
+                    })?;
+                }
 
-            simple_logging::log_to_file(
-                Path::new(&args.outdir).join("rumina_group.log"),
-                LevelFilter::Info,
-            )?;
+                simple_logging::log_to(
+                    RoutedLog::create(&Path::new(&args.outdir).join("rumina_group.log"))?,
+                    LevelFilter::Info,
+                );
+            }
 
             if args.merge_pairs.is_some() {
-                simple_logging::log_to_file(
-                    Path::new(&args.outdir).join("rumina_merge.log"),
+                simple_logging::log_to(
+                    RoutedLog::create(&Path::new(&args.outdir).join("rumina_merge.log"))?,
                     LevelFilter::Info,
-                )?;
+                );
             }
 
-            let infiles = gather_files(input_file)?;
-
-            process_all(&args, infiles)
+            let infiles = match (&args.samplesheet, &args.input) {
+                (Some(samplesheet), _) => gather_samples(&args, samplesheet)?,
+                (None, Some(input_file)) => gather_files(input_file, args.input2.as_ref())?
+                    .into_iter()
+                    .map(|file| (file, (*args).clone()))
+                    .collect(),
+                (None, None) => unreachable!("-i is required without --samplesheet"),
+            };
+            let num_files = infiles.len();
+
+            let errors: Vec<Error> = process_all(&args, infiles)
                 .into_iter()
                 .filter_map(|r| r.err())
-                .for_each(|e| eprintln! {"{:?}\n--", e});
+                .collect();
+            errors.iter().for_each(|e| eprintln! {"{:?}\n--", e});
+
+            if !errors.is_empty() {
+                anyhow::bail!("{} of {} input files failed", errors.len(), num_files);
+            }
         }
 
-        Command::Extract(args) => run_extract(&args)?,
+        Command::Extract(args) => match &args.samplesheet {
+            Some(samplesheet) => run_extract_samples(&args, samplesheet)?,
+            None => run_extract(&args)?,
+        },
+
+        Command::MergeShards(args) => run_merge_shards(&args)?,
 
         Command::Test => {
             run_extract_tests();
@@ -9,7 +9,7 @@ use std::sync::Arc;
 
 use crate::merge_report::*;
 use crate::read_store::read_store::ReadsAndCount;
-use crate::realign::{align_to_ref, ReMapper};
+use crate::realign::{align_to_ref, ReMapper, MAX_REALIGN_SHIFT};
 
 pub fn handle_dupes(
     umis_reads: &mut IndexMap<String, ReadsAndCount<BamRecord>>,
@@ -17,6 +17,7 @@ pub fn handle_dupes(
     ref_fasta: &[u8],
     min_overlap_bp: usize,
     sender: crossbeam::channel::Sender<Option<BamRecord>>,
+    discordant: Option<crossbeam::channel::Sender<BamRecord>>,
 ) -> Vec<MergeResult> {
     let results: Arc<Mutex<Vec<MergeResult>>> = Arc::new(Mutex::new(Vec::new()));
 
@@ -48,10 +49,16 @@ pub fn handle_dupes(
                     while !reads.is_empty() {
                         let read = reads.remove(0);
 
-                        let result = find_merges(&read, &mut reads, min_overlap_bp);
+                        let (result, mate) = find_merges(&read, &mut reads, min_overlap_bp);
 
                         match result {
                             MergeResult::Discordant(_) => {
+                                // both reads of a discordant pair are dropped, and passed on
+                                // if asked for
+                                if let Some(discordant) = &discordant {
+                                    discordant.send(read).unwrap();
+                                    discordant.send(mate.unwrap()).unwrap();
+                                }
                                 merge_results.push(result);
                             }
 
@@ -60,10 +67,11 @@ pub fn handle_dupes(
                                 merge_results.push(result);
                             }
                             MergeResult::Merge(merged_bases) => {
-                                let (_start_pos, merged_seq) =
+                                let (start_pos, merged_seq) =
                                     construct_sequence(merged_bases.unwrap());
                                 let merged_read = construct_read(
                                     &read,
+                                    start_pos,
                                     merged_seq,
                                     &mut mapper.clone(),
                                     ref_fasta,
@@ -96,30 +104,27 @@ pub fn is_overlap(read_a: &BamRecord, read_b: &BamRecord) -> bool {
     ras < rbs && rae >= rbs
 }
 
-// for groups of >2 reads, find every overlapping f/r read pair, attempt merge
+// for groups of >2 reads, find every overlapping f/r read pair, attempt merge. The mate is
+// returned if it was removed from the list, i.e. merged or discordant.
 pub fn find_merges(
     read: &BamRecord,
     reads: &mut Vec<BamRecord>,
     min_overlap_bp: usize,
-) -> MergeResult {
+) -> (MergeResult, Option<BamRecord>) {
     for (i, other_read) in reads.iter().enumerate() {
         if is_opp_orientation(read, other_read) && is_overlap(read, other_read) {
             let merge_result = attempt_merge(read, other_read, min_overlap_bp);
 
-            match merge_result {
-                MergeResult::Discordant(_) => {
-                    reads.remove(i);
-                }
-                MergeResult::NoMerge(_) => {}
-                MergeResult::Merge(_) => {
-                    reads.remove(i);
-                }
-            }
-            return merge_result;
+            let mate = match merge_result {
+                MergeResult::Discordant(_) => Some(reads.remove(i)),
+                MergeResult::NoMerge(_) => None,
+                MergeResult::Merge(_) => Some(reads.remove(i)),
+            };
+            return (merge_result, mate);
         }
     }
 
-    MergeResult::NoMerge(())
+    (MergeResult::NoMerge(()), None)
 }
 
 pub fn construct_sequence(mut read_blueprint: IndexMap<i64, u8>) -> (i64, Vec<u8>) {
@@ -139,15 +144,22 @@ pub fn construct_sequence(mut read_blueprint: IndexMap<i64, u8>) -> (i64, Vec<u8
     (*start, new_seq)
 }
 
+// realign a merged sequence near `start_pos`, where its pair aligned, at most MAX_REALIGN_SHIFT
+// bases away.
 pub fn construct_read(
     original_read: &BamRecord,
+    start_pos: i64,
     new_seq: Vec<u8>,
     mapper: &mut ReMapper,
     ref_seq: &[u8],
 ) -> BamRecord {
     let mut new_rec = original_read.clone();
 
-    let (start, _end, cigar) = align_to_ref(mapper, &new_seq, ref_seq);
+    let ref_start = (start_pos - MAX_REALIGN_SHIFT).clamp(0, ref_seq.len() as i64) as usize;
+    let ref_end = (start_pos + new_seq.len() as i64 + MAX_REALIGN_SHIFT)
+        .clamp(ref_start as i64, ref_seq.len() as i64) as usize;
+    let (start, _end, cigar) = align_to_ref(mapper, &new_seq, &ref_seq[ref_start..ref_end]);
+    let start = start + ref_start;
 
     let qname = [new_rec.qname(), b":MERGED"].concat();
     new_rec.set(
@@ -299,7 +311,7 @@ mod tests {
         let mapper: ReMapper = Aligner::new(-5, -1, blosum62, 19, 70);
         let ref_fasta = vec![b'A', b'T', b'C', b'G', b'A', b'T', b'C'];
 
-        let results = handle_dupes(&mut umis_reads, mapper, &ref_fasta, 1, s.clone());
+        let results = handle_dupes(&mut umis_reads, mapper, &ref_fasta, 1, s.clone(), None);
         for res in results {
             merge_report.count(res);
         }
@@ -0,0 +1,391 @@
+use crate::cli::dedup_args::fingerprint_changes;
+use crate::cli::{AlignmentFormat, MergeShardsArgs};
+use crate::group_report::GroupReport;
+use crate::io::bam_sorter::coordinate_key;
+use crate::io::program::{command_line, with_program};
+use crate::io::shards::Shard;
+use crate::record::BamRecord;
+use crate::status;
+use crate::utils::{index_bam, make_bam_writer};
+use anyhow::{Context, Error};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{self, Header, HeaderView, Read};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// the header lines of a [crate::cli::DedupArgs::fingerprint]
+const FINGERPRINT_PREFIXES: [&str; 3] = [
+    "@CO\trumina parameters:",
+    "@CO\trumina options:",
+    "@CO\trumina input:",
+];
+
+// one shard's output, read in coordinate order
+struct ShardInput {
+    path: String,
+    reader: bam::Reader,
+    shard: Shard,
+    report: GroupReport,
+}
+
+/// Combine the outputs of `rumina dedup --shard` into one output: reads are merged in coordinate
+/// order, with their group tags (`UG`) prefixed by their shard's index (e.g. `2-ABCDEFGH`) so that
+/// groups of different shards can't share a tag, and the shards' reports are summed.
+///
+/// Shards must have been deduplicated from the same input with the same options, as told by the
+/// fingerprint in their headers (see [crate::cli::DedupArgs::fingerprint]).
+pub fn run_merge_shards(args: &MergeShardsArgs) -> Result<(), Error> {
+    if args.shards.contains(&args.output) {
+        anyhow::bail!("Output {} is one of the shards", args.output)
+    }
+    let format = AlignmentFormat::from_path(&args.output).unwrap_or(AlignmentFormat::Bam);
+    let has_cram = std::iter::once(&args.output)
+        .chain(&args.shards)
+        .any(|path| AlignmentFormat::from_path(path) == Some(AlignmentFormat::Cram));
+    if has_cram && args.reference.is_none() {
+        anyhow::bail!(
+            "CRAM shards and output require a reference FASTA; supply one with --reference"
+        )
+    }
+
+    let mut inputs = Vec::new();
+    let mut header_text = String::new();
+    let mut parameters: Option<(String, Vec<String>)> = None;
+    for path in &args.shards {
+        let mut reader =
+            bam::Reader::from_path(path).with_context(|| format!("Failed to open shard {path}"))?;
+        reader.set_threads(args.threads)?;
+        if let Some(reference) = &args.reference {
+            reader
+                .set_reference(reference)
+                .with_context(|| format!("Failed to load reference {reference}"))?;
+        }
+
+        let text = String::from_utf8_lossy(reader.header().as_bytes()).to_string();
+        let (shard, fingerprint) = shard_fingerprint(&text)
+            .with_context(|| format!("{path} is not the output of rumina dedup --shard"))?;
+        if sort_order(&text) != Some("coordinate") {
+            anyhow::bail!("Shard {path} is not coordinate-sorted")
+        }
+
+        // shards must be alike but for their shard, and hold the same references
+        let references: Vec<String> = text
+            .lines()
+            .filter(|line| line.starts_with("@SQ"))
+            .map(String::from)
+            .collect();
+        match &parameters {
+            None => {
+                header_text = text.replace(&format!(" shard={shard}"), "");
+                parameters = Some((fingerprint, references));
+            }
+            Some((first_fingerprint, first_references)) => {
+                if fingerprint != *first_fingerprint {
+                    anyhow::bail!(
+                        "Shard {path} was deduplicated from another input or with other options \
+                        than {} ({})",
+                        args.shards[0],
+                        fingerprint_changes(&fingerprint, first_fingerprint).join(", ")
+                    )
+                }
+                if references != *first_references {
+                    anyhow::bail!("Shard {path} has other references than {}", args.shards[0])
+                }
+            }
+        }
+
+        inputs.push(ShardInput {
+            path: path.clone(),
+            reader,
+            shard,
+            report: GroupReport::read_report_file(path)?,
+        });
+    }
+    check_complete(&inputs)?;
+    inputs.sort_by_key(|input| input.shard.index);
+
+    let header = Header::from_template(&HeaderView::from_bytes(header_text.as_bytes()));
+    let header = with_program(&header, &command_line(), &[]);
+    let mut writer = make_bam_writer(
+        &args.output,
+        header,
+        args.threads,
+        format,
+        args.reference.as_deref(),
+    );
+
+    status!("Merging {} shards into {}...", inputs.len(), args.output);
+    // the next read of each shard, by coordinate; ties go to the earlier shard
+    let mut heads: Vec<Option<BamRecord>> = Vec::with_capacity(inputs.len());
+    let mut heap = BinaryHeap::new();
+    for (i, input) in inputs.iter_mut().enumerate() {
+        let head = read_next(input)?;
+        if let Some(record) = &head {
+            heap.push(Reverse((coordinate_key(record), i)));
+        }
+        heads.push(head);
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let mut record = heads[i].take().expect("shard head is queued");
+        let input = &mut inputs[i];
+
+        if let Ok(Aux::String(ug)) = record.aux(b"UG") {
+            let tag = shard_tag(&input.shard, ug);
+            record.remove_aux(b"UG")?;
+            record.push_aux(b"UG", Aux::String(&tag))?;
+        }
+        writer.write(&record)?;
+
+        heads[i] = read_next(input)?;
+        if let Some(record) = &heads[i] {
+            heap.push(Reverse((coordinate_key(record), i)));
+        }
+    }
+    // the writer is dropped before indexing, to avoid a vague htslib warning
+    drop(writer);
+
+    let mut report = GroupReport::new();
+    for input in &mut inputs {
+        // blank reports name no groups
+        if !input.report.is_blank() {
+            for group in [
+                &mut input.report.min_reads_group,
+                &mut input.report.max_reads_group,
+            ] {
+                *group = shard_tag(&input.shard, group).into();
+            }
+        }
+        report.merge(&input.report);
+    }
+    report.write_to_report_file(&args.output);
+    status!("{}\n", report);
+
+    status!("Merging done. Attempting to index...");
+    index_bam(
+        &args.output,
+        args.threads,
+        args.reference.as_deref(),
+        args.csi,
+    )
+    .with_context(|| format!("Failed to index {}", args.output))?;
+
+    Ok(())
+}
+
+fn read_next(input: &mut ShardInput) -> Result<Option<BamRecord>, Error> {
+    let mut record = BamRecord::new();
+    match input.reader.read(&mut record) {
+        Some(res) => {
+            res.with_context(|| format!("Failed to read shard {}", input.path))?;
+            Ok(Some(record))
+        }
+        None => Ok(None),
+    }
+}
+
+// the sort order of a header (`SO`), from its `@HD` line
+fn sort_order(header_text: &str) -> Option<&str> {
+    header_text
+        .lines()
+        .find(|line| line.starts_with("@HD\t"))?
+        .split('\t')
+        .find_map(|field| field.strip_prefix("SO:"))
+}
+
+// the tag of a shard's group in merged output. Tags within a shard are kept apart as they were.
+fn shard_tag(shard: &Shard, tag: &str) -> String {
+    format!("{}-{tag}", shard.index)
+}
+
+// the shard of an output, and the fingerprint of the run that made it, less the shard. Taken from
+// the last lines recorded, in case the input was deduplicated before.
+fn shard_fingerprint(header_text: &str) -> Option<(Shard, String)> {
+    let last = |prefix: &&str| {
+        header_text
+            .lines()
+            .rev()
+            .find(|line| line.starts_with(prefix))
+    };
+    let [parameters, others @ ..] = &FINGERPRINT_PREFIXES;
+
+    let mut shard = None;
+    let parameters: Vec<&str> = last(parameters)?
+        .split(' ')
+        .filter(|field| match field.strip_prefix("shard=") {
+            Some(value) => {
+                shard = value.parse().ok();
+                false
+            }
+            None => true,
+        })
+        .collect();
+
+    let mut fingerprint = vec![parameters.join(" ")];
+    fingerprint.extend(others.iter().filter_map(last).map(String::from));
+    Some((shard?, fingerprint.join("\n")))
+}
+
+// each shard must be given exactly once
+fn check_complete(inputs: &[ShardInput]) -> Result<(), Error> {
+    let count = inputs.first().map_or(0, |input| input.shard.count);
+    let mut seen: Vec<Option<&str>> = vec![None; count];
+    for input in inputs {
+        if input.shard.count != count {
+            anyhow::bail!(
+                "Shard {} is one of {} shards, but {} is one of {count}",
+                input.path,
+                input.shard.count,
+                inputs[0].path
+            )
+        }
+        if let Some(other) = seen[input.shard.index - 1].replace(&input.path) {
+            anyhow::bail!("{other} and {} are both shard {}", input.path, input.shard)
+        }
+    }
+
+    let missing: Vec<String> = (1..=count)
+        .filter(|index| seen[index - 1].is_none())
+        .map(|index| format!("{index}/{count}"))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("Missing shard(s) {}", missing.join(", "))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mapped_read, write_bam, TempDir};
+    use std::path::Path;
+
+    #[test]
+    fn test_shard_fingerprint() {
+        let header = |shard: &str, min_mapq: u8| {
+            format!(
+                "@HD\tVN:1.6\tSO:coordinate\n\
+                @CO\trumina parameters: grouping_method=raw min_depth=3\n\
+                @CO\trumina parameters: grouping_method=directional min_depth=1 shard={shard}\n\
+                @CO\trumina options: min_mapq={min_mapq} paired=false\n\
+                @CO\trumina input: path=/data/a.bam size=100 mtime=5\n"
+            )
+        };
+        let (shard, fingerprint) = shard_fingerprint(&header("2/4", 0)).unwrap();
+        assert_eq!(shard, Shard { index: 2, count: 4 });
+        assert_eq!(
+            fingerprint,
+            "@CO\trumina parameters: grouping_method=directional min_depth=1\n\
+            @CO\trumina options: min_mapq=0 paired=false\n\
+            @CO\trumina input: path=/data/a.bam size=100 mtime=5"
+        );
+        assert!(shard_fingerprint("@CO\trumina parameters: grouping_method=raw").is_none());
+        assert_eq!(sort_order(&header("2/4", 0)), Some("coordinate"));
+        assert_eq!(
+            sort_order("@HD\tVN:1.6\tSO:unsorted\n@CO\tSO:coordinate"),
+            Some("unsorted")
+        );
+
+        // shards are alike but for their shard, unless an option differs
+        let (_, other) = shard_fingerprint(&header("3/4", 0)).unwrap();
+        assert_eq!(other, fingerprint);
+        let (_, filtered) = shard_fingerprint(&header("3/4", 20)).unwrap();
+        assert_eq!(
+            fingerprint_changes(&filtered, &fingerprint),
+            ["min_mapq=20"]
+        );
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        let dir = TempDir::new("merge_shards");
+        let shard_path = |index: usize| {
+            dir.join(format!("a_RUMINA_shard{index}of2.bam"))
+                .to_string_lossy()
+                .to_string()
+        };
+        // shards of a run, each holding a group tagged alike, at positions of their own
+        let write_shard = |index: usize, min_mapq: u8, positions: &[i64]| {
+            let header = Header::from_template(&HeaderView::from_bytes(
+                format!(
+                    "@HD\tVN:1.6\tSO:coordinate\n\
+                    @SQ\tSN:chr1\tLN:1000\n\
+                    @CO\trumina parameters: grouping_method=directional shard={index}/2\n\
+                    @CO\trumina options: min_mapq={min_mapq}\n\
+                    @CO\trumina input: path=/data/a.bam size=100 mtime=5"
+                )
+                .as_bytes(),
+            ));
+            let reads = positions.iter().map(|pos| {
+                let mut read = mapped_read(&format!("read{index}_{pos}"), 0, *pos, "10M", false);
+                read.push_aux(b"UG", Aux::String("ABCDEFGH")).unwrap();
+                read
+            });
+            write_bam(Path::new(&shard_path(index)), &header, reads);
+
+            let mut report = GroupReport::new();
+            report.max_reads_per_group = positions.len() as i64;
+            report.max_reads_group = "ABCDEFGH".into();
+            report.write_to_report_file(&shard_path(index));
+        };
+        let output = dir.join("a_RUMINA.bam").to_string_lossy().to_string();
+        let merge = |shards: &[usize]| {
+            run_merge_shards(&MergeShardsArgs {
+                shards: shards.iter().map(|index| shard_path(*index)).collect(),
+                output: output.clone(),
+                reference: None,
+                csi: false,
+                threads: 1,
+            })
+        };
+
+        write_shard(1, 0, &[10, 30]);
+        write_shard(2, 0, &[20]);
+        let err = format!("{:#}", merge(&[1]).err().unwrap());
+        assert!(err.contains("Missing shard(s) 2/2"), "{err}");
+
+        // shards given in any order are merged by coordinate, with their groups kept apart
+        merge(&[2, 1]).unwrap();
+        let mut reader = bam::Reader::from_path(&output).unwrap();
+        let reads: Vec<(i64, String)> = reader
+            .records()
+            .map(|read| {
+                let read = read.unwrap();
+                let ug = match read.aux(b"UG") {
+                    Ok(Aux::String(ug)) => ug.to_string(),
+                    _ => String::new(),
+                };
+                (read.pos(), ug)
+            })
+            .collect();
+        assert_eq!(
+            reads,
+            [
+                (10, "1-ABCDEFGH".to_string()),
+                (20, "2-ABCDEFGH".to_string()),
+                (30, "1-ABCDEFGH".to_string())
+            ]
+        );
+        let report = GroupReport::read_report_file(&output).unwrap();
+        assert_eq!(report.max_reads_group, "1-ABCDEFGH");
+
+        // shards of runs with other options aren't merged
+        write_shard(2, 20, &[20]);
+        let err = format!("{:#}", merge(&[1, 2]).err().unwrap());
+        assert!(err.contains("(min_mapq=20)"), "{err}");
+    }
+
+    #[test]
+    fn test_shard_tags_stay_apart() {
+        let shards = [Shard { index: 1, count: 2 }, Shard { index: 2, count: 2 }];
+        let tags: Vec<String> = shards
+            .iter()
+            .flat_map(|shard| ["ABCDEFGH", "ABCDEFGI"].map(|tag| shard_tag(shard, tag)))
+            .collect();
+        assert_eq!(
+            tags,
+            ["1-ABCDEFGH", "1-ABCDEFGI", "2-ABCDEFGH", "2-ABCDEFGI"]
+        );
+    }
+}
@@ -18,11 +18,7 @@ pub struct NgramMaker {
 
 impl NgramMaker {
     pub fn new(num_chunks: usize, string_len: usize) -> Self {
-        let mut chunk_size = string_len / num_chunks;
-
-        if string_len % num_chunks != 0 {
-            chunk_size += 1;
-        }
+        let chunk_size = string_len.div_ceil(num_chunks);
 
         let out_vec = RefCell::new(vec![SmolStr::new("NILL"); num_chunks]);
 
@@ -0,0 +1,269 @@
+use crate::merge_report::MergeResult;
+use crate::record::FastqRecord;
+use crate::DedupArgs;
+
+// quality offset of phred+33 encoded FASTQ
+const QUAL_OFFSET: u8 = 33;
+
+// quality assigned to a consensus base when mates disagree with near-equal confidence
+const MIN_CONSENSUS_QUAL: u8 = 2;
+
+/// Merges overlapping R1/R2 FASTQ mates into one fragment using only their sequence overlap,
+/// similar to FLASH or PEAR.
+///
+/// R2 is reverse-complemented and slid along R1; of all placements overlapping by at least
+/// `min_overlap` bases with a fraction of mismatching bases no higher than `max_mismatch_density`,
+/// the longest overlap is kept, so that a short chance match can't outrank the true overlap. Ties go
+/// to the placement with fewer mismatches.
+///
+/// When the insert is shorter than the reads, R2 starts before R1, and both read through into
+/// adapter. Such pairs are merged into their overlap alone, trimming the overhangs.
+#[derive(Debug)]
+pub struct OverlapMerger {
+    pub min_overlap: usize,
+    pub max_mismatch_density: f32,
+}
+
+impl OverlapMerger {
+    pub fn init_from_args(args: &DedupArgs) -> Option<Self> {
+        if args.merge_overlap {
+            Some(Self {
+                min_overlap: args.min_overlap_len,
+                max_mismatch_density: args.max_mismatch_density,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Attempt to merge a pair of mates. The merged read takes the header of R1.
+    pub fn merge(&self, r1: &FastqRecord, r2: &FastqRecord) -> (MergeResult, Option<FastqRecord>) {
+        let seq2 = reverse_complement_bytes(r2.seq());
+        let qual2 = r2.qual().iter().rev().copied().collect::<Vec<u8>>();
+
+        let Some(offset) = self.best_offset(r1.seq(), &seq2) else {
+            return (MergeResult::NoMerge(()), None);
+        };
+
+        let (seq, qual) = match usize::try_from(offset) {
+            Ok(offset) => consensus(r1.seq(), r1.qual(), &seq2, &qual2, offset),
+            // R2 starts before R1: the insert is just the overlap
+            Err(_) => {
+                let start2 = offset.unsigned_abs();
+                let overlap = std::cmp::min(r1.seq().len(), seq2.len() - start2);
+                consensus(
+                    &r1.seq()[..overlap],
+                    &r1.qual()[..overlap],
+                    &seq2[start2..start2 + overlap],
+                    &qual2[start2..start2 + overlap],
+                    0,
+                )
+            }
+        };
+        let merged = FastqRecord::with_attrs(r1.id(), r1.desc(), &seq, &qual);
+
+        (MergeResult::Merge(None), Some(merged))
+    }
+
+    /// Find the position in R1 at which the reverse-complemented R2 starts. Negative if R2 starts
+    /// before R1, i.e. the insert is shorter than R2.
+    fn best_offset(&self, seq1: &[u8], seq2: &[u8]) -> Option<isize> {
+        if seq1.len() < self.min_overlap || seq2.len() < self.min_overlap {
+            return None;
+        }
+
+        // (offset, overlap, mismatches)
+        let mut best: Option<(isize, usize, usize)> = None;
+
+        let first = -((seq2.len() - self.min_overlap) as isize);
+        let last = (seq1.len() - self.min_overlap) as isize;
+        for offset in first..=last {
+            let (start1, start2) = match usize::try_from(offset) {
+                Ok(offset) => (offset, 0),
+                Err(_) => (0, offset.unsigned_abs()),
+            };
+            let overlap = std::cmp::min(seq1.len() - start1, seq2.len() - start2);
+
+            if overlap < self.min_overlap {
+                continue;
+            }
+
+            let mismatches = seq1[start1..start1 + overlap]
+                .iter()
+                .zip(&seq2[start2..start2 + overlap])
+                .filter(|(a, b)| is_mismatch(**a, **b))
+                .count();
+
+            if mismatches as f32 > self.max_mismatch_density * overlap as f32 {
+                continue;
+            }
+
+            let better = match best {
+                Some((_, best_overlap, best_mismatches)) => {
+                    overlap > best_overlap
+                        || (overlap == best_overlap && mismatches < best_mismatches)
+                }
+                None => true,
+            };
+            if better {
+                best = Some((offset, overlap, mismatches));
+            }
+        }
+
+        best.map(|(offset, ..)| offset)
+    }
+}
+
+fn is_mismatch(a: u8, b: u8) -> bool {
+    let (a, b) = (a.to_ascii_uppercase(), b.to_ascii_uppercase());
+    a != b && a != b'N' && b != b'N'
+}
+
+/// Build the merged sequence, resolving overlapping bases by quality: agreeing bases keep the
+/// higher of the two qualities, and disagreeing bases take the more confident call with a quality
+/// reduced by that of the other.
+fn consensus(
+    seq1: &[u8],
+    qual1: &[u8],
+    seq2: &[u8],
+    qual2: &[u8],
+    offset: usize,
+) -> (Vec<u8>, Vec<u8>) {
+    let len = std::cmp::max(seq1.len(), offset + seq2.len());
+    let mut seq = Vec::with_capacity(len);
+    let mut qual = Vec::with_capacity(len);
+
+    for i in 0..len {
+        let b1 = seq1.get(i).copied().zip(qual1.get(i).copied());
+        let b2 = i
+            .checked_sub(offset)
+            .and_then(|j| seq2.get(j).copied().zip(qual2.get(j).copied()));
+
+        let (base, q) = match (b1, b2) {
+            (Some(b1), None) => b1,
+            (None, Some(b2)) => b2,
+            (Some((s1, q1)), Some((s2, q2))) => {
+                if s1.eq_ignore_ascii_case(&s2) {
+                    (s1, std::cmp::max(q1, q2))
+                } else if s1.eq_ignore_ascii_case(&b'N') {
+                    (s2, q2)
+                } else if s2.eq_ignore_ascii_case(&b'N') {
+                    (s1, q1)
+                } else {
+                    let ((base, hi), lo) = if q1 >= q2 {
+                        ((s1, q1), q2)
+                    } else {
+                        ((s2, q2), q1)
+                    };
+                    let q = std::cmp::max(hi - lo, MIN_CONSENSUS_QUAL) + QUAL_OFFSET;
+                    (base, q)
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+
+        seq.push(base);
+        qual.push(q);
+    }
+
+    (seq, qual)
+}
+
+pub fn reverse_complement_bytes(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b.to_ascii_uppercase() {
+            b'A' => b'T',
+            b'T' => b'A',
+            b'G' => b'C',
+            b'C' => b'G',
+            _ => b'N',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merger() -> OverlapMerger {
+        OverlapMerger {
+            min_overlap: 5,
+            max_mismatch_density: 0.1,
+        }
+    }
+
+    fn rec(seq: &[u8], qual: &[u8]) -> FastqRecord {
+        FastqRecord::with_attrs("read_ACGT", None, seq, qual)
+    }
+
+    #[test]
+    fn test_merge_overlapping_pair() {
+        // insert: ACGTACCGTTAGGCATCA; R1 covers the first 12 bases, R2 the last 12
+        let r1 = rec(b"ACGTACCGTTAG", b"IIIIIIIIIIII");
+        let r2 = rec(&reverse_complement_bytes(b"CGTTAGGCATCA"), b"IIIIIIIIIIII");
+
+        let (res, merged) = merger().merge(&r1, &r2);
+        assert!(matches!(res, MergeResult::Merge(_)));
+
+        let merged = merged.unwrap();
+        assert_eq!(merged.seq(), b"ACGTACCGTTAGGCATCA");
+        assert_eq!(merged.id(), "read_ACGT");
+    }
+
+    #[test]
+    fn test_merge_short_insert() {
+        // insert: ACGTACCGTTAG, shorter than the reads, which both read through into adapter
+        let r1 = rec(b"ACGTACCGTTAGAGAT", b"IIIIIIIIIIIIIIII");
+        let r2 = rec(
+            &[reverse_complement_bytes(b"ACGTACCGTTAG"), b"CTCT".to_vec()].concat(),
+            b"IIIIIIIIIIIIIIII",
+        );
+
+        assert_eq!(
+            merger().best_offset(r1.seq(), &reverse_complement_bytes(r2.seq())),
+            Some(-4)
+        );
+        let (res, merged) = merger().merge(&r1, &r2);
+        assert!(matches!(res, MergeResult::Merge(_)));
+        assert_eq!(merged.unwrap().seq(), b"ACGTACCGTTAG");
+    }
+
+    #[test]
+    fn test_merge_short_insert_with_decoy() {
+        // insert: ACGTAGGACGTA, which starts and ends with ACGTA; R1 has a sequencing error at its
+        // sixth base, so the true overlap has a mismatch, while the chance overlap of the insert's
+        // last five bases with R1's first five is exact.
+        let r1 = rec(b"ACGTATGACGTAAGAT", b"IIIIIIIIIIIIIIII");
+        let r2 = rec(
+            &[reverse_complement_bytes(b"ACGTAGGACGTA"), b"CTCT".to_vec()].concat(),
+            b"IIIIIIIIIIIIIIII",
+        );
+
+        assert_eq!(
+            merger().best_offset(r1.seq(), &reverse_complement_bytes(r2.seq())),
+            Some(-4)
+        );
+        let (res, merged) = merger().merge(&r1, &r2);
+        assert!(matches!(res, MergeResult::Merge(_)));
+        assert_eq!(merged.unwrap().seq().len(), 12);
+    }
+
+    #[test]
+    fn test_no_overlap() {
+        let r1 = rec(b"AAAAACCCCC", b"IIIIIIIIII");
+        let r2 = rec(b"GAGAGAGAGA", b"IIIIIIIIII");
+
+        let (res, merged) = merger().merge(&r1, &r2);
+        assert!(matches!(res, MergeResult::NoMerge(_)));
+        assert!(merged.is_none());
+    }
+
+    #[test]
+    fn test_consensus_prefers_higher_quality() {
+        // the mates disagree at the last base of R1; R2 is more confident there
+        let (seq, qual) = consensus(b"ACGTA", b"IIII+", b"ACGTC", b"IIIII", 0);
+        assert_eq!(seq, b"ACGTC");
+        assert_eq!(qual[4], b'I' - b'+' + QUAL_OFFSET);
+    }
+}
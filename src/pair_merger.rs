@@ -1,34 +1,179 @@
+use crate::cli::AlignmentFormat;
+use crate::file_log;
 use crate::merge::handle_dupes;
 use crate::merge_report::MergeReport;
 use crate::read_store::pair_bundles::*;
-use crate::realign::init_remapper;
+use crate::realign::{init_remapper, ReMapper, MAX_REALIGN_SHIFT};
+use crate::rejects::{write_reject, RejectReason};
 use crate::utils::{get_windows, make_bam_reader, make_bam_writer};
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use anyhow::Error;
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use indexmap::IndexMap;
 use log::info;
+use parking_lot::Mutex;
 use rust_htslib::bam::{Read, Writer};
-use std::{thread, thread::JoinHandle};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
 use rust_htslib::bam::Record;
 
-pub fn spawn_writer_thread(mut bam_writer: Writer, r: Receiver<Option<Record>>) -> JoinHandle<i32> {
-    thread::spawn(move || {
-        let mut buffer: Vec<Record> = Vec::with_capacity(1_000_000);
+// number of windows processed as one unit, so that mates starting in neighboring windows can
+// still be merged.
+const WINDOWS_PER_CHUNK: usize = 3;
+
+/// All reads pulled from one chunk of windows, bundled by UMI and awaiting merging. Reads of later
+/// chunks of the same reference start at or after `write_before`.
+struct WindowJob {
+    order: usize,
+    tid: u32,
+    write_before: i64,
+    bundles: PairBundles,
+}
+
+/// The coordinate-sorted output of one chunk of windows, and the reads of discordant pairs dropped
+/// from it.
+pub struct MergedWindow {
+    pub order: usize,
+    pub tid: u32,
+    pub write_before: i64,
+    pub reads: Vec<Record>,
+    pub discordant: Vec<Record>,
+}
+
+/// Holds finished windows until every window read before them has been written, so that output
+/// follows input order regardless of which worker finishes first.
+#[derive(Default)]
+pub struct WindowQueue {
+    pending: BTreeMap<usize, MergedWindow>,
+    next_order: usize,
+}
+
+impl WindowQueue {
+    pub fn push(&mut self, window: MergedWindow) {
+        self.pending.insert(window.order, window);
+    }
+
+    /// Yield the next window in order, if it has finished.
+    pub fn pop_ready(&mut self) -> Option<MergedWindow> {
+        let window = self.pending.remove(&self.next_order)?;
+        self.next_order += 1;
+        Some(window)
+    }
+}
+
+/// Write windows as soon as they can be written in order. Merged reads are realigned, so a window
+/// may hold reads starting after some reads of the next one, which start at or after
+/// `write_before` but may be realigned up to [MAX_REALIGN_SHIFT] bases before it. Reads from there
+/// on are held back until the next window is in. For every window written, a token is returned to
+/// the reader, allowing it to load another window. Discordant pairs are written to `rejects` as
+/// they come, if given.
+///
+/// Yields the number of reads written.
+pub fn spawn_writer_thread(
+    mut bam_writer: Writer,
+    mut rejects: Option<Writer>,
+    r: Receiver<MergedWindow>,
+    tokens: Sender<()>,
+) -> JoinHandle<Result<i32, Error>> {
+    file_log::spawn(move || {
+        let mut queue = WindowQueue::default();
+        let mut held_back: Vec<Record> = Vec::new();
+        let mut cur_ref = None;
         let mut num_writes = 0;
 
-        loop {
-            match r.recv() {
-                Ok(Some(read)) => buffer.push(read),
-                Ok(None) | Err(_) => {
-                    buffer.sort_by_key(|ra| ra.pos());
-                    for read in buffer.drain(..) {
-                        bam_writer.write(&read).expect("unable to write read");
-                        num_writes += 1;
-                    }
-                    buffer.clear();
+        let mut write = |reads: Vec<Record>| -> Result<(), Error> {
+            for read in reads {
+                bam_writer.write(&read)?;
+                num_writes += 1;
+            }
+            Ok(())
+        };
+
+        while let Ok(mut window) = r.recv() {
+            if let Some(rejects) = &mut rejects {
+                for read in window.discordant.drain(..) {
+                    write_reject(rejects, read, RejectReason::DiscordantPair)?;
+                }
+            }
+            queue.push(window);
 
-                    return num_writes;
+            while let Some(window) = queue.pop_ready() {
+                if cur_ref != Some(window.tid) {
+                    write(std::mem::take(&mut held_back))?;
+                    cur_ref = Some(window.tid);
                 }
+
+                let mut reads = std::mem::take(&mut held_back);
+                reads.extend(window.reads);
+                reads.sort_by_key(|read| read.pos());
+                let write_before = window.write_before.saturating_sub(MAX_REALIGN_SHIFT);
+                held_back =
+                    reads.split_off(reads.partition_point(|read| read.pos() < write_before));
+                write(reads)?;
+
+                // the reader may already be done, in which case nobody is waiting on this.
+                tokens.send(()).ok();
+            }
+        }
+
+        write(held_back)?;
+        Ok(num_writes)
+    })
+}
+
+/// Merge pairs for each incoming window, and send the sorted result for writing. Reads of
+/// discordant pairs are sent along only if `keep_discordant`, i.e. if they are written anywhere.
+fn spawn_merge_worker(
+    jobs: Receiver<WindowJob>,
+    out: Sender<MergedWindow>,
+    mapper: ReMapper,
+    ref_fasta: Arc<Vec<u8>>,
+    min_overlap_bp: usize,
+    merge_report: Arc<Mutex<MergeReport>>,
+    keep_discordant: bool,
+) -> JoinHandle<()> {
+    file_log::spawn(move || {
+        while let Ok(mut job) = jobs.recv() {
+            let (s, r): (Sender<Option<Record>>, Receiver<Option<Record>>) = unbounded();
+            let (discordant_s, discordant_r) = match keep_discordant {
+                true => {
+                    let (s, r) = unbounded();
+                    (Some(s), Some(r))
+                }
+                false => (None, None),
+            };
+
+            let merge_results = handle_dupes(
+                &mut job.bundles.read_dict,
+                mapper.clone(),
+                &ref_fasta,
+                min_overlap_bp,
+                s,
+                discordant_s,
+            );
+
+            let mut reads: Vec<Record> = r.iter().flatten().collect();
+            let discordant: Vec<Record> =
+                discordant_r.map_or_else(Vec::new, |r| r.iter().collect());
+            reads.sort_by_key(|read| read.pos());
+
+            let mut report = merge_report.lock();
+            for res in merge_results {
+                report.count(res);
+            }
+            drop(report);
+
+            let window = MergedWindow {
+                order: job.order,
+                tid: job.tid,
+                write_before: job.write_before,
+                reads,
+                discordant,
+            };
+            // the writer stopped early; its error is reported by the reader.
+            if out.send(window).is_err() {
+                break;
             }
         }
     })
@@ -45,28 +190,69 @@ pub struct PairMerger {
 }
 
 impl PairMerger {
-    pub fn merge_windows(&mut self) -> MergeReport {
-        let mut merge_report = MergeReport::new();
+    /// Read the input window by window, merging pairs of each window in parallel across worker
+    /// threads. Finished windows are written in order as soon as possible; the number of windows
+    /// held in memory at once is capped, so memory use scales with window size rather than with
+    /// the size of the input.
+    ///
+    /// Reads of discordant pairs are written to `rejects`, if given.
+    pub fn merge_windows(&mut self, rejects: Option<Writer>) -> Result<MergeReport, Error> {
+        let merge_report = Arc::new(Mutex::new(MergeReport::new()));
 
-        let (header, mut reader) = make_bam_reader(&self.infile, self.threads);
+        let (header, mut reader) = make_bam_reader(&self.infile, None, self.threads, None)?;
         let (mapper, ref_fasta) = init_remapper(&self.ref_fasta);
-        let mut num_writes: i32 = 0;
+        let ref_fasta = Arc::new(ref_fasta);
 
         let ref_count = reader.header().clone().target_count();
         let mut read_count = 0;
 
-        let writer = make_bam_writer(&self.outfile, header.clone(), self.threads);
-        let (so, r): (Sender<Option<Record>>, Receiver<Option<Record>>) = unbounded();
-        let writer_handle = spawn_writer_thread(writer, r);
+        let num_workers = self.threads.max(1);
+        let max_in_flight = num_workers * 2;
+
+        // every window loaded costs a token; the writer hands it back once the window is written.
+        let (token_s, token_r): (Sender<()>, Receiver<()>) = bounded(max_in_flight);
+        for _ in 0..max_in_flight {
+            token_s.send(()).unwrap();
+        }
+
+        let writer = make_bam_writer(
+            &self.outfile,
+            header.clone(),
+            self.threads,
+            AlignmentFormat::Bam,
+            None,
+        );
+        let (out_s, out_r): (Sender<MergedWindow>, Receiver<MergedWindow>) = unbounded();
+        let keep_discordant = rejects.is_some();
+        let writer_handle = spawn_writer_thread(writer, rejects, out_r, token_s);
 
-        for tid in 0..ref_count {
+        let (job_s, job_r): (Sender<WindowJob>, Receiver<WindowJob>) = bounded(num_workers);
+        let workers = (0..num_workers)
+            .map(|_| {
+                spawn_merge_worker(
+                    job_r.clone(),
+                    out_s.clone(),
+                    mapper.clone(),
+                    ref_fasta.clone(),
+                    self.min_overlap_bp as usize,
+                    merge_report.clone(),
+                    keep_discordant,
+                )
+            })
+            .collect::<Vec<JoinHandle<()>>>();
+
+        drop(job_r);
+        drop(out_s);
+
+        let mut order = 0;
+
+        'reading: for tid in 0..ref_count {
             let max_pos = reader.header().target_len(tid).unwrap() as i64;
             let windows = get_windows(self.split_window, max_pos);
             reader.fetch((tid, 0, u32::MAX)).unwrap();
             let mut next_window_reads: Vec<Record> = Vec::with_capacity(100);
 
-            let s = so.clone();
-            for window_chunk in windows.chunks(3) {
+            for window_chunk in windows.chunks(WINDOWS_PER_CHUNK) {
                 let mut bundles = PairBundles {
                     read_dict: IndexMap::new(),
                 };
@@ -93,29 +279,145 @@ impl PairMerger {
                     }
                 }
 
-                let merge_results = handle_dupes(
-                    &mut bundles.read_dict,
-                    mapper.clone(),
-                    &ref_fasta,
-                    self.min_overlap_bp as usize,
-                    s.clone(),
-                );
+                if bundles.read_dict.is_empty() {
+                    continue;
+                }
 
-                for res in merge_results {
-                    merge_report.count(res);
+                // later chunks start with the read carried over, if any
+                let write_before = next_window_reads
+                    .first()
+                    .map_or(i64::MAX, |read| read.pos());
+                let job = WindowJob {
+                    order,
+                    tid,
+                    write_before,
+                    bundles,
+                };
+
+                // with the writer or the workers gone, the writer's error is reported below
+                if token_r.recv().is_err() || job_s.send(job).is_err() {
+                    break 'reading;
                 }
+                order += 1;
             }
-            s.send(None).unwrap();
-            drop(s);
         }
 
-        drop(so);
-        num_writes += writer_handle.join().expect("Writer thread panicked");
+        drop(job_s);
+        for worker in workers {
+            worker.join().expect("Merge worker panicked");
+        }
+
+        let num_writes = writer_handle.join().expect("Writer thread panicked")?;
+
+        let mut merge_report = Arc::try_unwrap(merge_report)
+            .expect("Unable to dereference merge report!")
+            .into_inner();
 
         merge_report.num_inreads = read_count;
         merge_report.num_outreads = num_writes;
         info!("{:?}", merge_report);
 
-        merge_report
+        Ok(merge_report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_header, TempDir};
+    use rust_htslib::bam;
+
+    fn window_of(order: usize, write_before: i64, positions: &[i64]) -> MergedWindow {
+        let reads = positions
+            .iter()
+            .map(|pos| {
+                let mut record = Record::new();
+                record.set_tid(0);
+                record.set_pos(*pos);
+                record
+            })
+            .collect();
+
+        MergedWindow {
+            order,
+            tid: 0,
+            write_before,
+            reads,
+            discordant: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_window_queue_in_order() {
+        let mut queue = WindowQueue::default();
+
+        queue.push(window_of(1, i64::MAX, &[200, 250]));
+        assert!(queue.pop_ready().is_none());
+
+        queue.push(window_of(0, 200, &[10, 50]));
+        let first = queue.pop_ready().unwrap();
+        assert_eq!(
+            first.reads.iter().map(|r| r.pos()).collect::<Vec<i64>>(),
+            [10, 50]
+        );
+
+        let second = queue.pop_ready().unwrap();
+        assert_eq!(
+            second.reads.iter().map(|r| r.pos()).collect::<Vec<i64>>(),
+            [200, 250]
+        );
+
+        assert!(queue.pop_ready().is_none());
+    }
+
+    // write the windows, sent in the order given, and return the positions written
+    fn write_windows(name: &str, windows: Vec<MergedWindow>) -> Vec<i64> {
+        let dir = TempDir::new(name);
+        let outfile = dir.join("merged.bam");
+        let writer = Writer::from_path(&outfile, &test_header(), bam::Format::Bam).unwrap();
+
+        let num_windows = windows.len();
+        let num_reads: usize = windows.iter().map(|window| window.reads.len()).sum();
+        let (out_s, out_r) = unbounded();
+        let (token_s, token_r) = unbounded();
+        let handle = spawn_writer_thread(writer, None, out_r, token_s);
+        for window in windows {
+            out_s.send(window).unwrap();
+        }
+        drop(out_s);
+        assert_eq!(handle.join().unwrap().unwrap(), num_reads as i32);
+        assert_eq!(token_r.len(), num_windows);
+
+        let mut reader = bam::Reader::from_path(&outfile).unwrap();
+        reader.records().map(|read| read.unwrap().pos()).collect()
+    }
+
+    #[test]
+    fn test_writer_sorts_across_windows() {
+        // a read merged in the later window realigns before one merged in the first, which
+        // starts after the first read carried over to the later window
+        let positions = write_windows(
+            "pair_merger_sorts",
+            vec![
+                window_of(1, i64::MAX, &[140, 300]),
+                window_of(0, 150, &[100, 160]),
+            ],
+        );
+        assert_eq!(positions, [100, 140, 160, 300]);
+    }
+
+    #[test]
+    fn test_writer_holds_back_realigned_reads() {
+        // the later window's reads start from 500 on, but one is realigned to before reads of
+        // the first window that start before 500
+        let moved = 500 - MAX_REALIGN_SHIFT;
+        let positions = write_windows(
+            "pair_merger_realigned",
+            vec![
+                window_of(0, 500, &[100, moved + 10, moved + 20]),
+                window_of(1, i64::MAX, &[moved + 5, 600]),
+            ],
+        );
+        assert_eq!(positions, [100, moved + 5, moved + 10, moved + 20, 600]);
     }
 }
@@ -1,23 +1,483 @@
-use crate::cli::DedupArgs;
-use crate::io::bam_io::BamIO;
+use crate::checkpoint::{checkpoint_dir, Checkpoint};
+use crate::cli::{AlignmentFormat, DedupArgs, OutputSort, UnmappedReads};
+use crate::file_log;
+use crate::group_report::{write_read_group_report_file, write_region_report_file, GroupReport};
+use crate::io::bam_input::BamInput;
+use crate::io::bam_io::{BamIO, BamOutput};
+use crate::io::fastqio::STDIO_PATH;
 use crate::io::file_io::FileIO;
+use crate::io::WindowedBamReader;
 use crate::pair_merger::PairMerger;
 use crate::process::file_process::FileProcess;
 use crate::processor::Processor;
 use crate::progbars::ProgressTracker;
-use crate::read_store::BottomHashMap;
-use crate::readkey::ReadKey;
+use crate::read_filter::{FilterCounts, ReadFilter, Verdict};
+use crate::read_groups::ReadGroups;
+use crate::read_store::{BottomHashMap, Retain};
+use crate::readkey::SeqKey;
 use crate::record::{BamRecord, SequenceRecord};
-use crate::utils::{gen_outfile_name, index_bam};
+use crate::rejects::RejectReason;
+use crate::spill::{SpillConfig, Spiller};
+use crate::status;
+use crate::utils::{gen_outfile_name, index_bam, RecordFile, Window};
 use anyhow::{Context, Error};
 use colored::Colorize;
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use indexmap::IndexMap;
 use log::info;
-use std::fs::remove_file;
+use rayon::slice::ParallelSliceMut;
+use rust_htslib::bam::Writer;
+use std::fs::{remove_dir_all, remove_file};
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// unmapped reads copied to the output are sent to the writer in chunks of this many
+const UNMAPPED_CHUNK_SIZE: usize = 100_000;
+
+// stands in for the reference of unmapped reads without one
+const UNMAPPED_TID: u32 = u32::MAX;
+
+/// Reads of one window, or of one spilled partition of a window, awaiting grouping.
+struct PulledBatch {
+    bottomhash: BottomHashMap<BamRecord>,
+    tid: u32,
+    region: Option<usize>,
+    window: Window,
+    num_windows: usize,
+    num_reads: u64,
+    // reads of the window starting before this position may be written once grouped; later ones
+    // must wait for reads carried over to later windows.
+    write_before: i64,
+    last_of_window: bool,
+    set_aside: SetAside,
+    // unmapped reads without a reference, copied to the output as they are, and the number of
+    // those pulled (whether copied or grouped).
+    unmapped: Vec<BamRecord>,
+    num_unmapped: u64,
+}
+
+/// Reads of a window kept out of grouping: counted by [ReadFilter], held to follow their primary,
+/// and with `--rejects-out`, those left out of the output.
+#[derive(Default)]
+struct SetAside {
+    filtered: FilterCounts,
+    followers: Vec<BamRecord>,
+    rejects: Vec<(BamRecord, RejectReason)>,
+}
+
+impl SetAside {
+    fn reject(&mut self, read: BamRecord, keep_rejects: bool) {
+        if keep_rejects {
+            self.rejects.push((read, RejectReason::Filtered));
+        }
+    }
+}
+
+/// Grouped reads of one batch, awaiting writing, with the report of their grouping and the number
+/// of input reads of their window (counted with the last batch of a window), for checkpoints.
+struct GroupedBatch {
+    reads: Vec<BamRecord>,
+    followers: Vec<BamRecord>,
+    unmapped: Vec<BamRecord>,
+    rejects: Vec<(BamRecord, RejectReason)>,
+    tid: u32,
+    window: Window,
+    write_before: i64,
+    report: GroupReport,
+    num_reads: u64,
+}
+
+/// Settings for pulling reads into batches on the reader thread.
+struct PullOptions {
+    separator: String,
+    retain: Retain,
+    keep_rejects: bool,
+    paired: bool,
+    merge_pairs: bool,
+    group_by_length: bool,
+    filter: ReadFilter,
+    unmapped: UnmappedReads,
+    seq_key: Option<SeqKey>,
+    read_groups: Option<Arc<ReadGroups>>,
+    spill: Option<(SpillConfig, u64)>,
+    spill_name: String,
+    // references up to this one were done by an earlier run, see [crate::checkpoint::Checkpoint]
+    resume_after: Option<u32>,
+}
+
+impl PullOptions {
+    /// Key a read by its [ReadKey], including its read group pool with `--per-read-group`.
+    fn key(&self, read: &BamRecord) -> (i64, u64) {
+        let (pos, mut key) = read.get_pos_key(self.group_by_length);
+        if let Some(read_groups) = &self.read_groups {
+            key.read_group = read_groups.pool(read);
+        }
+        (pos, key.get_key())
+    }
+}
+
+/// Pull the reads of each window into a [BottomHashMap], and send it for grouping. With spilling,
+/// the reads of a window are first partitioned to disk, and each partition is sent on its own.
+/// Every batch loaded into memory costs a token, handed back once the batch is written. Reads
+/// failing the [ReadFilter] are counted and left out, or sent along to follow their primary.
+/// Unmapped reads without a reference are pulled last (see [pull_unmapped]).
+///
+/// Yields the number of reads pulled.
+fn spawn_reader_thread(
+    mut reader: WindowedBamReader,
+    opts: PullOptions,
+    s: Sender<PulledBatch>,
+    tokens: Receiver<()>,
+) -> JoinHandle<Result<i64, Error>> {
+    file_log::spawn(move || {
+        let mut read_counter = 0;
+        let take_token = || tokens.recv().context("Writing stopped early");
+
+        let mut spiller: Option<Spiller<BamRecord>> = match &opts.spill {
+            Some((config, expected_memory)) => Some(Spiller::new(
+                config,
+                &opts.spill_name,
+                reader.raw_header.clone(),
+                *expected_memory,
+            )?),
+            None => None,
+        };
+        let genome_len: u64 = reader.target_lens.iter().sum();
+
+        let pos_key = |read: &BamRecord| opts.key(read);
+        let new_bottomhash = || BottomHashMap {
+            read_dict: IndexMap::with_capacity(500),
+            read_count: 0,
+        };
+
+        while reader.next_reference()? {
+            if opts.resume_after.is_some_and(|last| reader.cur_ref <= last) {
+                continue;
+            }
+            let num_windows = reader.windows.len();
+
+            while reader.next_window() {
+                let mut bottomhash = new_bottomhash();
+                let mut window_records = 0;
+                let mut set_aside = SetAside::default();
+
+                // with spilling, the window goes to disk, and its partitions take turns instead
+                if spiller.is_none() {
+                    take_token()?;
+                }
+
+                // assume reads are spread evenly across the genome to begin with; partitions
+                // that turn out larger are split again.
+                if let (Some(spiller), Some((_, expected_memory))) = (&mut spiller, &opts.spill) {
+                    let window_len =
+                        (reader.cur_window.end - reader.cur_window.start).max(0) as u64;
+                    spiller.set_expected_memory(
+                        expected_memory.saturating_mul(window_len) / genome_len.max(1),
+                    );
+                }
+
+                for record in reader.window_records()? {
+                    // R2 alignments may follow their primary as well, which is retrieved with
+                    // --paired
+                    let verdict = opts.filter.check(&record);
+                    if verdict == Verdict::FollowPrimary {
+                        set_aside.filtered.count(verdict);
+                        set_aside.followers.push(record);
+                        continue;
+                    }
+
+                    if record.is_last_in_template() && !opts.merge_pairs {
+                        continue;
+                    }
+
+                    if record.is_mate_unmapped() && opts.paired {
+                        set_aside.reject(record, opts.keep_rejects);
+                        continue;
+                    }
+
+                    if verdict != Verdict::Group {
+                        set_aside.filtered.count(verdict);
+                        set_aside.reject(record, opts.keep_rejects);
+                        continue;
+                    }
+
+                    let (pos, key) = pos_key(&record);
+                    match spiller.as_mut() {
+                        Some(spiller) => spiller.push(record, pos, key)?,
+                        None => {
+                            let umi = record.get_umi(&opts.separator)?;
+                            bottomhash.update_dict(pos, key, umi, record, opts.retain);
+                        }
+                    }
+                    window_records += 1;
+                }
+
+                info!("{} reads pulled from window", window_records);
+                read_counter += window_records as i64;
+
+                let tid = reader.cur_ref;
+                let region = reader.cur_region;
+                let window = reader.cur_window.clone();
+                let send = |bottomhash, num_reads, write_before, last_of_window, set_aside| {
+                    s.send(PulledBatch {
+                        bottomhash,
+                        tid,
+                        region,
+                        window: window.clone(),
+                        num_windows,
+                        num_reads,
+                        write_before,
+                        last_of_window,
+                        set_aside,
+                        unmapped: Vec::new(),
+                        num_unmapped: 0,
+                    })
+                    .context("Grouping stopped early")
+                };
+
+                if let Some(spiller) = spiller.as_mut() {
+                    spiller.drain(&pos_key, &mut |reads| {
+                        take_token()?;
+                        let mut bottomhash = new_bottomhash();
+                        for read in reads {
+                            let read = read?;
+                            let (pos, key) = pos_key(&read);
+                            let umi = read.get_umi(&opts.separator)?;
+                            bottomhash.update_dict(pos, key, umi, read, opts.retain);
+                        }
+
+                        // partitions hold reads from anywhere in the window, so each is
+                        // written as soon as it is grouped, and sorted on its way out (see
+                        // BamIO::init_from_args).
+                        let num_reads = bottomhash.read_count;
+                        send(bottomhash, num_reads, i64::MAX, false, SetAside::default())
+                    })?;
+                    take_token()?;
+                }
+
+                send(
+                    bottomhash,
+                    window_records,
+                    reader.min_unyielded_pos(),
+                    true,
+                    set_aside,
+                )?;
+            }
+        }
+
+        if opts.unmapped != UnmappedReads::Drop && opts.resume_after != Some(UNMAPPED_TID) {
+            read_counter += pull_unmapped(&mut reader, &opts, spiller.as_mut(), &s, &take_token)?;
+        }
+
+        Ok(read_counter)
+    })
+}
+
+/// Pull unmapped reads without a reference, which have no position to be grouped at. They are
+/// either sent to be copied to the output in chunks, or grouped by UMI like FASTQ reads, in
+/// buckets by sequence with a [SeqKey]. Batches take tokens as windows do.
+///
+/// Yields the number of reads pulled for grouping.
+fn pull_unmapped(
+    reader: &mut WindowedBamReader,
+    opts: &PullOptions,
+    spiller: Option<&mut Spiller<BamRecord>>,
+    s: &Sender<PulledBatch>,
+    take_token: &impl Fn() -> Result<(), Error>,
+) -> Result<i64, Error> {
+    let send = |bottomhash, num_reads, last_of_window, set_aside, unmapped, num_unmapped| {
+        s.send(PulledBatch {
+            bottomhash,
+            tid: UNMAPPED_TID,
+            region: None,
+            window: Window { start: -1, end: 0 },
+            num_windows: 1,
+            num_reads,
+            write_before: i64::MAX,
+            last_of_window,
+            set_aside,
+            unmapped,
+            num_unmapped,
+        })
+        .context("Grouping stopped early")
+    };
+    let new_bottomhash = || BottomHashMap {
+        read_dict: IndexMap::with_capacity(500),
+        read_count: 0,
+    };
+
+    if opts.unmapped != UnmappedReads::Dedup {
+        take_token()?;
+        let mut chunk = Vec::with_capacity(UNMAPPED_CHUNK_SIZE);
+        for record in reader.unmapped_records()? {
+            chunk.push(record?);
+            if chunk.len() == UNMAPPED_CHUNK_SIZE {
+                let num_unmapped = chunk.len() as u64;
+                let chunk = std::mem::replace(&mut chunk, Vec::with_capacity(UNMAPPED_CHUNK_SIZE));
+                send(
+                    new_bottomhash(),
+                    0,
+                    false,
+                    SetAside::default(),
+                    chunk,
+                    num_unmapped,
+                )?;
+                take_token()?;
+            }
+        }
+
+        let num_unmapped = chunk.len() as u64;
+        send(
+            new_bottomhash(),
+            0,
+            true,
+            SetAside::default(),
+            chunk,
+            num_unmapped,
+        )?;
+        return Ok(0);
+    }
+
+    // sequence buckets stand in for position; without a SeqKey, all reads share one
+    let pos_key = |read: &BamRecord| {
+        let (_, key) = opts.key(read);
+        let bucket = opts
+            .seq_key
+            .map_or(0, |seq_key| seq_key.bucket(&read.seq().as_bytes()));
+        (bucket, key)
+    };
+
+    let mut bottomhash = new_bottomhash();
+    let mut num_reads = 0;
+    let mut set_aside = SetAside::default();
+    let mut spiller = spiller;
+    if spiller.is_none() {
+        take_token()?;
+    }
+    for record in reader.unmapped_records()? {
+        let record = record?;
+        // unmapped reads have no primary to follow
+        let verdict = match opts.filter.check(&record) {
+            Verdict::FollowPrimary => Verdict::Group,
+            verdict => verdict,
+        };
+
+        if record.is_last_in_template() && !opts.merge_pairs {
+            continue;
+        }
+
+        if verdict != Verdict::Group {
+            set_aside.filtered.count(verdict);
+            set_aside.reject(record, opts.keep_rejects);
+            continue;
+        }
+
+        let (pos, key) = pos_key(&record);
+        match spiller.as_mut() {
+            Some(spiller) => spiller.push(record, pos, key)?,
+            None => {
+                let umi = record.get_umi(&opts.separator)?;
+                bottomhash.update_dict(pos, key, umi, record, opts.retain);
+            }
+        }
+        num_reads += 1;
+    }
+
+    if let Some(spiller) = spiller {
+        spiller.drain(&pos_key, &mut |reads| {
+            take_token()?;
+            let mut bottomhash = new_bottomhash();
+            for read in reads {
+                let read = read?;
+                let (pos, key) = pos_key(&read);
+                let umi = read.get_umi(&opts.separator)?;
+                bottomhash.update_dict(pos, key, umi, read, opts.retain);
+            }
+
+            let num_reads = bottomhash.read_count;
+            send(
+                bottomhash,
+                num_reads,
+                false,
+                SetAside::default(),
+                Vec::new(),
+                0,
+            )
+        })?;
+        take_token()?;
+    }
+
+    send(
+        bottomhash,
+        num_reads,
+        true,
+        set_aside,
+        Vec::new(),
+        num_reads,
+    )?;
+    Ok(num_reads as i64)
+}
+
+/// Write grouped reads as soon as doing so keeps the output sorted. Reads carried over to later
+/// windows may start before some reads of the current one, so those are held back until the
+/// carried reads have been grouped; output sorted on its way out needs no holding back. For every
+/// batch written, a token is returned to the reader.
+///
+/// Yields the number of alignments output along with their primary, and the writer of rejected
+/// reads, left open for pairs found discordant when merging.
+fn spawn_writer_thread(
+    mut output: BamOutput,
+    r: Receiver<GroupedBatch>,
+    tokens: Sender<()>,
+) -> JoinHandle<Result<(i64, Option<Writer>), Error>> {
+    file_log::spawn(move || {
+        let mut outreads: Vec<BamRecord> = Vec::with_capacity(1_000_000);
+        let mut cur_ref = None;
+
+        while let Ok(batch) = r.recv() {
+            if cur_ref != Some(batch.tid) {
+                output.write_reads(&mut outreads)?;
+                output.next_part()?;
+                cur_ref = Some(batch.tid);
+            }
+            if let Some(checkpoint) = &mut output.checkpoint {
+                checkpoint.tally(&batch.report, batch.num_reads);
+            }
+
+            output.cur_ref = batch.tid;
+            output.cur_window = batch.window;
+            output.hold(batch.followers)?;
+            output.write_unmapped(batch.unmapped)?;
+            output.write_rejects(batch.rejects)?;
+            outreads.extend(batch.reads);
+
+            let write_before = match output.sorts() {
+                true => i64::MAX,
+                false => batch.write_before,
+            };
+            outreads.par_sort_by_key(|read| read.pos());
+            let held_back =
+                outreads.split_off(outreads.partition_point(|read| read.pos() < write_before));
+
+            output.write_reads(&mut outreads)?;
+            outreads = held_back;
+
+            // the reader may already be done, in which case nobody is waiting on this.
+            tokens.send(()).ok();
+        }
+
+        output.write_reads(&mut outreads)?;
+        let rejects = output.take_rejects();
+        // the writer is dropped here, to avoid a vague htslib warning when indexing
+        Ok((output.finish()?, rejects))
+    })
+}
 
 pub struct BamFileProcess {
+    // kept until processing is done, as it may own temporary files being read
+    _input: BamInput,
     io: BamIO,
     chunk_processor: Processor,
     outfile: String,
@@ -25,19 +485,73 @@ pub struct BamFileProcess {
     separator: String,
     group_reads: bool,
     progress: bool,
-    ensure_sorted: bool,
+    spill: Option<(SpillConfig, u64)>,
+    pipeline_depth: usize,
+    out_format: AlignmentFormat,
+    reference: Option<String>,
+    unmapped: UnmappedReads,
+    seq_key: Option<SeqKey>,
+    csi: bool,
+    checkpoint: bool,
+    shard: bool,
 }
 
 impl FileProcess for BamFileProcess {
-    fn init_from_args(args: &DedupArgs, file_path: &str, file_name: &str) -> Result<Self, Error> {
-        let outfile = gen_outfile_name(Some(&args.outdir), ".bam", "RUMINA", file_name)?;
-        let bam_io = BamIO::init_from_args(args, file_path, &outfile);
+    fn init_from_args(args: &DedupArgs, file: &RecordFile) -> Result<Self, Error> {
+        let in_format = AlignmentFormat::from_path(&file.fname).unwrap_or(AlignmentFormat::Bam);
+        let out_format = args.output_format.unwrap_or(in_format);
+
+        if (in_format == AlignmentFormat::Cram || out_format == AlignmentFormat::Cram)
+            && args.reference.is_none()
+        {
+            anyhow::bail!(
+                "CRAM input and output require a reference FASTA; supply one with --reference"
+            )
+        }
+
+        if args.merge_pairs.is_some() && out_format != AlignmentFormat::Bam {
+            anyhow::bail!("-m/--merge-pairs requires BAM output (--output-format bam)")
+        }
+
+        // output to stdout keeps the stream's name, `-`
+        let outfile = match args.outdir == STDIO_PATH {
+            true => STDIO_PATH.to_string(),
+            false => {
+                let fname = match file.fpath == STDIO_PATH {
+                    true => format!("stdin.{}", in_format.extension()),
+                    false => file.fname.clone(),
+                };
+                // e.g. sample_RUMINA_shard2of4.bam
+                let suffix = match args.shard {
+                    Some(shard) => format!("RUMINA_{}", shard.label()),
+                    None => "RUMINA".to_string(),
+                };
+                let outfile = gen_outfile_name(
+                    Some(&args.outdir),
+                    &format!(".{}", in_format.extension()),
+                    &suffix,
+                    &fname,
+                )?;
+                Path::new(&outfile)
+                    .with_extension(out_format.extension())
+                    .to_string_lossy()
+                    .to_string()
+            }
+        };
+        let input = BamInput::prepare(args, &file.fpath)?;
+        let bam_io = BamIO::init_from_args(args, &input, &outfile)?;
 
         let mut hasher = DefaultHasher::new();
-        file_name.hash(&mut hasher);
+        file.fname.hash(&mut hasher);
         let seed = hasher.finish();
 
-        let chunk_processor = Processor::init_from_args(args, seed);
+        let mut chunk_processor = Processor::init_from_args(args, seed);
+        chunk_processor.read_groups = args.per_read_group.map(|pool_by| {
+            Arc::new(ReadGroups::from_header(
+                &bam_io.windowed_reader.raw_header,
+                pool_by,
+            ))
+        });
         let mut pair_merger: Option<PairMerger> = None;
         let group_reads = args.only_group;
 
@@ -52,12 +566,16 @@ impl FileProcess for BamFileProcess {
             })
         }
 
-        let ensure_sorted = args.ensure_sorted && args.split_window.is_some();
-
         let separator = args.separator.clone();
         let progress = args.progress;
 
+        let spill = SpillConfig::init_from_args(args).map(|config| {
+            let expected_memory = config.estimate_memory(&file.fpath);
+            (config, expected_memory)
+        });
+
         Ok(Self {
+            _input: input,
             io: bam_io,
             chunk_processor,
             outfile,
@@ -65,111 +583,220 @@ impl FileProcess for BamFileProcess {
             separator,
             group_reads,
             progress,
-            ensure_sorted,
+            spill,
+            pipeline_depth: args.pipeline_depth,
+            out_format,
+            reference: args.reference.clone(),
+            unmapped: args.unmapped_reads(),
+            seq_key: SeqKey::init_from_args(args),
+            csi: args.csi,
+            checkpoint: args.checkpoint || args.resume,
+            shard: args.shard.is_some(),
         })
     }
 
-    fn process(mut self) -> Result<(), Error> {
-        let (mut pos, mut key): (i64, ReadKey);
-        let mut outreads: Vec<BamRecord> = Vec::with_capacity(1_000_000);
+    /// Reading, grouping and writing run as a pipeline: while one window is grouped across the
+    /// rayon thread pool, a reader thread pulls the next window and a writer thread writes the
+    /// previous one. How many windows may be held at once is bounded by `--pipeline-depth`; by
+    /// default, just one, so that stages take turns and memory use stays that of one window.
+    fn process(mut self) -> Result<GroupReport, Error> {
+        let BamIO {
+            windowed_reader,
+            output,
+            num_threads,
+            ..
+        } = self.io;
+        let order = output.order;
+        let filter = output.filter;
+        let regions = windowed_reader.regions().map(|regions| regions.to_vec());
+
+        // with --resume, references done by an earlier run are skipped, and their report carried
+        let checkpoint = output.checkpoint.as_ref();
+        let resume_after = checkpoint.and_then(Checkpoint::last_done);
+        let mut group_report = checkpoint.map_or_else(GroupReport::new, Checkpoint::done_report);
+        let num_done = resume_after.map_or(0, |last| last as usize + 1);
 
         let mut pt = ProgressTracker::initialize_main(
-            self.io.windowed_reader.meta_header.target_count(),
+            regions.as_ref().map_or(
+                windowed_reader.target_lens.len().saturating_sub(num_done),
+                |regions| regions.len(),
+            ) as u32,
             self.progress,
         );
 
-        while self.io.windowed_reader.next_reference()? {
-            pt.initialize_windows(self.io.windowed_reader.windows.len());
+        let opts = PullOptions {
+            separator: self.separator.clone(),
+            retain: Retain::new(self.group_reads, self.chunk_processor.keep_rejects),
+            keep_rejects: self.chunk_processor.keep_rejects,
+            paired: self.chunk_processor.paired,
+            merge_pairs: self.pair_merger.is_some(),
+            group_by_length: self.chunk_processor.group_by_length,
+            filter,
+            unmapped: self.unmapped,
+            seq_key: self.seq_key,
+            read_groups: self.chunk_processor.read_groups.clone(),
+            spill: self.spill.take(),
+            spill_name: self.outfile.replace('/', "_"),
+            resume_after,
+        };
+
+        // every batch loaded costs a token; the writer hands it back once the batch is written, so
+        // that no more than --pipeline-depth batches are held at once.
+        let (token_s, token_r): (Sender<()>, Receiver<()>) = bounded(self.pipeline_depth);
+        for _ in 0..self.pipeline_depth {
+            token_s.send(()).unwrap();
+        }
 
-            while self.io.windowed_reader.next_window() {
-                let mut bottomhash = BottomHashMap {
-                    read_dict: IndexMap::with_capacity(500),
-                    read_count: 0,
-                };
+        let (pulled_s, pulled_r): (Sender<PulledBatch>, Receiver<PulledBatch>) = unbounded();
+        let (grouped_s, grouped_r): (Sender<GroupedBatch>, Receiver<GroupedBatch>) = unbounded();
 
-                let mut window_records = 0;
-                pt.intake_reads_msg();
+        let reader_handle = spawn_reader_thread(windowed_reader, opts, pulled_s, token_r);
+        let writer_handle = spawn_writer_thread(output, grouped_r, token_s);
 
-                for record in self.io.windowed_reader.window_records() {
-                    if record.is_last_in_template() && self.pair_merger.is_none() {
-                        continue;
-                    }
-
-                    if record.is_mate_unmapped() && self.chunk_processor.paired {
-                        continue;
-                    }
+        // reports are kept per batch, and combined per region as well as for the whole file
+        let mut region_reports: Vec<GroupReport> = regions
+            .iter()
+            .flatten()
+            .map(|_| GroupReport::new())
+            .collect();
 
-                    (pos, key) = record.get_pos_key(self.chunk_processor.group_by_length);
-                    self.chunk_processor.pull_read(
-                        record,
-                        pos,
-                        key,
-                        &mut bottomhash,
-                        &self.separator,
-                        self.group_reads,
-                    )?;
-                    window_records += 1;
+        let mut cur_ref = None;
+        for mut batch in pulled_r.iter() {
+            if cur_ref != Some((batch.tid, batch.region)) {
+                if cur_ref.is_some() {
+                    pt.next_ref();
                 }
+                pt.initialize_windows(batch.num_windows);
+                cur_ref = Some((batch.tid, batch.region));
+            }
 
-                info!("{} reads pulled from window", window_records);
-                pt.update_window_reads(window_records);
-
-                outreads.extend(Processor::group_reads(
-                    &mut self.chunk_processor,
-                    &mut bottomhash,
-                    &mut pt.coord_bar,
-                ));
-
-                if !self.ensure_sorted {
-                    self.io.write_reads(&mut outreads)
-                };
-
-                info!(
-                    "Processed {} total reads...",
-                    self.chunk_processor.read_counter
-                );
+            pt.intake_reads_msg();
+            pt.update_window_reads(batch.num_reads);
+
+            let (reads, mut rejects) = Processor::group_reads(
+                &mut self.chunk_processor,
+                &mut batch.bottomhash,
+                &mut pt.coord_bar,
+            );
+
+            let mut batch_report = std::mem::replace(
+                &mut *self.chunk_processor.min_max.lock(),
+                GroupReport::new(),
+            );
+            batch_report.filtered = batch.set_aside.filtered;
+            batch_report.num_unmapped = batch.num_unmapped as i64;
+            if let Some(region_report) = batch.region.map(|region| &mut region_reports[region]) {
+                if batch.last_of_window {
+                    region_report.num_reads_input_file += batch.num_reads as i64;
+                }
+                region_report.merge(&batch_report);
+            }
+            group_report.merge(&batch_report);
+
+            rejects.append(&mut batch.set_aside.rejects);
+            let num_reads = match batch.last_of_window {
+                true => batch.num_reads,
+                false => 0,
+            };
+            grouped_s
+                .send(GroupedBatch {
+                    reads,
+                    followers: batch.set_aside.followers,
+                    unmapped: batch.unmapped,
+                    rejects,
+                    tid: batch.tid,
+                    window: batch.window,
+                    write_before: batch.write_before,
+                    report: batch_report,
+                    num_reads,
+                })
+                .context("Writer thread disconnected")?;
+
+            if batch.last_of_window {
                 pt.next_window()
             }
-            pt.next_ref();
         }
 
+        if cur_ref.is_some() {
+            pt.next_ref();
+        }
         pt.finish();
 
-        self.io.write_reads(&mut outreads);
-
-        let num_reads_in = self.chunk_processor.read_counter;
-        let min_maxes = self.chunk_processor.min_max.clone();
+        drop(grouped_s);
+        self.chunk_processor.read_counter =
+            reader_handle.join().expect("Reader thread panicked")?;
+        let (followers_output, rejects) = writer_handle.join().expect("Writer thread panicked")?;
+        group_report.filtered.followers_output = followers_output;
+        // the output is complete
+        if self.checkpoint {
+            remove_dir_all(checkpoint_dir(&self.outfile)).ok();
+        }
 
-        drop(self.chunk_processor);
+        info!(
+            "Processed {} total reads...",
+            self.chunk_processor.read_counter
+        );
 
         // do final report
-        let mut group_report = Arc::try_unwrap(min_maxes).unwrap().into_inner();
-        group_report.num_reads_input_file = num_reads_in;
+        group_report.num_reads_input_file += self.chunk_processor.read_counter;
+        let read_groups = self.chunk_processor.read_groups.take();
+        let pool_reports = std::mem::take(&mut *self.chunk_processor.pool_reports.lock());
+        drop(self.chunk_processor);
 
         // report on min and max number of reads per group
         // this creates minmax.txt
         if !group_report.is_blank() {
-            println!("{}", "DONE".green());
+            status!("{}", "DONE".green());
 
+            if self.outfile != STDIO_PATH {
+                group_report.write_to_report_file(&self.outfile);
+            }
+            status!("{}\n", group_report);
+        } else if self.shard {
+            // merge-shards reads the report of every shard, even those holding no groups
             group_report.write_to_report_file(&self.outfile);
-            println!("{}\n", group_report);
         }
 
-        drop(self.io.writer); // dropping to avoid vague samtools warning
+        // a shard's regions are its own, rather than the user's
+        if let (Some(regions), true) = (&regions, self.outfile != STDIO_PATH && !self.shard) {
+            write_region_report_file(&self.outfile, regions, &region_reports);
+        }
+
+        if let (Some(read_groups), true) = (&read_groups, self.outfile != STDIO_PATH) {
+            write_read_group_report_file(&self.outfile, read_groups, pool_reports);
+        }
+
+        if self.outfile == STDIO_PATH {
+            status!("Processing done. Output was written to stdout, so it is not indexed.");
+            return Ok(group_report);
+        }
+
+        // only coordinate-sorted output can be indexed; pair merging requires it (see
+        // DedupArgs::validate)
+        if order != OutputSort::Coordinate {
+            status!("Processing done. Output is not coordinate-sorted, so it is not indexed.");
+            return Ok(group_report);
+        }
+
+        if self.out_format == AlignmentFormat::Sam {
+            status!("Processing done. SAM output is not indexed.");
+            return Ok(group_report);
+        }
 
-        eprintln!("Processing done. Attempting to index...");
-        let idx = index_bam(&self.outfile, self.io.num_threads).context("Note: failed to index bam due to unsorted order, and could not sort manually with samtools. Exiting early...")?;
+        status!("Processing done. Attempting to index...");
+        let idx = index_bam(&self.outfile, num_threads, self.reference.as_deref(), self.csi).context("Note: failed to index bam due to unsorted order, and could not sort it. Exiting early...")?;
 
         if let Some(mut pair_merger) = self.pair_merger {
             info!("{:?}", pair_merger);
 
-            let merge_report = pair_merger.merge_windows();
+            let merge_report = pair_merger.merge_windows(rejects)?;
             remove_file(self.outfile).ok();
             remove_file(idx).ok();
-            index_bam(&pair_merger.outfile, self.io.num_threads).unwrap();
-            print!("{merge_report}");
+            index_bam(&pair_merger.outfile, num_threads, None, self.csi).unwrap();
+            // the report ends its own last line
+            status!("{}", merge_report.to_string().trim_end());
         }
 
-        Ok(())
+        Ok(group_report)
     }
 }
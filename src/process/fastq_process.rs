@@ -1,23 +1,35 @@
 use crate::cli::DedupArgs;
+use crate::group_report::GroupReport;
 use crate::io::fastq_dedup_io::FastqIO;
+use crate::io::fastqio::{FastqInput, IntakeOrdered, ReadPair};
 use crate::io::FileIO;
+use crate::merge_report::MergeReport;
+use crate::overlap::OverlapMerger;
 use crate::process::file_process::FileProcess;
 use crate::processor::Processor;
 use crate::progbars::ProgressTracker;
-use crate::read_store::BottomHashMap;
-use crate::readkey::ReadKey;
-use crate::record::FastqRecord;
+use crate::read_store::{BottomHashMap, Retain};
+use crate::readkey::{ReadKey, SeqKey};
 use crate::record::SequenceRecord;
-use crate::utils::gen_outfile_name;
+use crate::record::{FastqPair, FastqRecord};
+use crate::spill::{SpillConfig, Spillable, Spiller};
+use crate::status;
+use crate::utils::{gen_outfile_name, RecordFile};
 use anyhow::{Context, Error};
 use colored::Colorize;
 use indexmap::IndexMap;
+use std::fmt::Debug;
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub struct FastQFileProcess {
     io: FastqIO,
     chunk_processor: Processor,
+    overlap_merger: Option<OverlapMerger>,
+    merge_report: MergeReport,
+    seq_key: Option<SeqKey>,
+    spill: Option<(SpillConfig, u64)>,
     outfile: String,
     separator: String,
     group_reads: bool,
@@ -25,12 +37,63 @@ pub struct FastQFileProcess {
 }
 
 impl FileProcess for FastQFileProcess {
-    fn init_from_args(args: &DedupArgs, file_path: &str, file_name: &str) -> Result<Self, Error> {
-        let outfile = gen_outfile_name(Some(&args.outdir), ".fastq.gz", "RUMINA", file_name)?;
-        let io = FastqIO::init_from_args(args, file_path, &outfile)?;
+    fn init_from_args(args: &DedupArgs, file: &RecordFile) -> Result<Self, Error> {
+        let outfile = gen_outfile_name(Some(&args.outdir), ".fastq.gz", "RUMINA", &file.fname)?;
+
+        let overlap_merger = match file.mate_path {
+            Some(_) => OverlapMerger::init_from_args(args),
+            None => None,
+        };
+
+        let unmerged_outfiles = match overlap_merger {
+            Some(_) => Some((
+                PathBuf::from(gen_outfile_name(
+                    Some(&args.outdir),
+                    ".fastq.gz",
+                    "RUMINA_unmerged_R1",
+                    &file.fname,
+                )?),
+                PathBuf::from(gen_outfile_name(
+                    Some(&args.outdir),
+                    ".fastq.gz",
+                    "RUMINA_unmerged_R2",
+                    &file.fname,
+                )?),
+            )),
+            None => None,
+        };
+
+        // without merging, pairs are deduplicated as units and written to synchronized outputs.
+        let mate_outfile = match (&file.mate_path, &overlap_merger) {
+            (Some(mate_path), None) => {
+                let mate_fname = match &file.sample {
+                    Some(sample) => format!("{sample}_R2.fastq.gz"),
+                    None => Path::new(mate_path)
+                        .file_name()
+                        .and_then(|f| f.to_str())
+                        .context("Unable to read R2 file name")?
+                        .to_string(),
+                };
+                Some(gen_outfile_name(
+                    Some(&args.outdir),
+                    ".fastq.gz",
+                    "RUMINA",
+                    &mate_fname,
+                )?)
+            }
+            _ => None,
+        };
+
+        let io = FastqIO::init_from_args(
+            args,
+            file,
+            &outfile,
+            mate_outfile.as_deref(),
+            unmerged_outfiles,
+        )?;
 
         let mut hasher = DefaultHasher::new();
-        file_name.hash(&mut hasher);
+        file.fname.hash(&mut hasher);
         let seed = hasher.finish();
 
         let chunk_processor = Processor::init_from_args(args, seed);
@@ -38,9 +101,32 @@ impl FileProcess for FastQFileProcess {
         let group_reads = args.only_group;
         let progress = args.progress;
 
+        let seq_key = SeqKey::init_from_args(args);
+
+        // keep track of the expected memory use of all input reads, to decide how finely to
+        // partition them when spilling
+        let spill = match SpillConfig::init_from_args(args) {
+            Some(_) if seq_key.is_none() => {
+                anyhow::bail!("--tmpdir requires --seq-key-len with FASTQ input, as FASTQ reads otherwise all share one position")
+            }
+            Some(config) => {
+                let expected_memory = config.estimate_memory(&file.fpath)
+                    + file
+                        .mate_path
+                        .as_ref()
+                        .map_or(0, |mate_path| config.estimate_memory(mate_path));
+                Some((config, expected_memory))
+            }
+            None => None,
+        };
+
         Ok(Self {
             io,
             chunk_processor,
+            overlap_merger,
+            merge_report: MergeReport::new(),
+            seq_key,
+            spill,
             outfile,
             separator,
             group_reads,
@@ -48,61 +134,253 @@ impl FileProcess for FastQFileProcess {
         })
     }
 
-    fn process(mut self) -> Result<(), Error> {
-        let (mut pos, mut key): (i64, ReadKey);
-        let mut outreads: Vec<FastqRecord> = Vec::with_capacity(1_000_000);
-
-        let mut bottomhash: BottomHashMap<FastqRecord> = BottomHashMap {
-            read_dict: IndexMap::with_capacity(500),
-            read_count: 0,
-        };
-
-        let mut reader = self.io.reader.take().context("Reader unitialized")?;
+    fn process(mut self) -> Result<GroupReport, Error> {
+        let reader = self.io.reader.take().context("Reader unitialized")?;
+        let mate_reader = self.io.mate_reader.take();
         let mut pt = ProgressTracker::initialize_main(1, self.progress);
 
-        let mut refresh_count = 0;
         pt.initialize_windows(1);
         pt.intake_reads_msg();
-        while let Some(record) = reader.records.next_record() {
-            let r = record?;
-            (pos, key) = r.get_pos_key(self.chunk_processor.group_by_length);
-            self.chunk_processor.pull_read(
-                r,
+
+        match mate_reader {
+            Some(mates) if self.overlap_merger.is_none() => {
+                let pulled = self.pull_pairs(reader, mates)?;
+                self.finish(pulled, pt)
+            }
+            mates => {
+                let pulled = self.pull_reads(reader, mates)?;
+                self.finish(pulled, pt)
+            }
+        }
+    }
+}
+
+impl FastQFileProcess {
+    fn get_pos_key<T: SequenceRecord>(&self, read: &T) -> (i64, ReadKey) {
+        fastq_pos_key(read, self.chunk_processor.group_by_length, self.seq_key)
+    }
+
+    fn init_store<T: Spillable<Header = ()>>(&self) -> Result<PulledReads<T>, Error> {
+        match &self.spill {
+            Some((config, expected_memory)) => Ok(PulledReads::Disk(Spiller::new(
+                config,
+                &self.outfile.replace('/', "_"),
+                (),
+                *expected_memory,
+            )?)),
+            None => Ok(PulledReads::Memory(BottomHashMap {
+                read_dict: IndexMap::with_capacity(500),
+                read_count: 0,
+            })),
+        }
+    }
+
+    fn pull<T: Spillable>(
+        &mut self,
+        read: T,
+        pos: i64,
+        key: ReadKey,
+        pulled: &mut PulledReads<T>,
+    ) -> Result<(), Error> {
+        match pulled {
+            PulledReads::Memory(bottomhash) => self.chunk_processor.pull_read(
+                read,
                 pos,
                 key,
-                &mut bottomhash,
+                bottomhash,
                 &self.separator,
-                self.group_reads,
-            )?;
+                Retain::new(self.group_reads, false),
+            ),
+            PulledReads::Disk(spiller) => {
+                self.chunk_processor.read_counter += 1;
+                spiller.push(read, pos, key.get_key())
+            }
+        }
+    }
+
+    /// Pull single-end reads. With R2 supplied, each pair is first merged into a single fragment;
+    /// pairs that don't overlap are set aside untouched.
+    fn pull_reads(
+        &mut self,
+        mut reader: FastqInput,
+        mut mate_reader: Option<FastqInput>,
+    ) -> Result<PulledReads<FastqRecord>, Error> {
+        let (mut pos, mut key): (i64, ReadKey);
+        let mut pulled = self.init_store()?;
+
+        let mut unmerged_order = 0;
+        if let Some(unmerged_writer) = self.io.unmerged_writer.as_mut() {
+            unmerged_writer.run(self.io.num_threads)?;
+        }
+
+        let mut refresh_count = 0;
+        while let Some(record) = reader.records.next_record() {
+            let mut r = record?;
+
+            if let (Some(mates), Some(merger)) = (mate_reader.as_mut(), &self.overlap_merger) {
+                let mate = mates
+                    .records
+                    .next_record()
+                    .context("R2 input has fewer reads than R1 input")??;
+
+                self.merge_report.num_inreads += 2;
+                let (result, merged) = merger.merge(&r, &mate);
+                self.merge_report.count(result);
+
+                match merged {
+                    Some(merged) => {
+                        self.merge_report.num_outreads += 1;
+                        r = merged
+                    }
+                    None => {
+                        self.merge_report.num_outreads += 2;
+                        self.io
+                            .unmerged_writer
+                            .as_ref()
+                            .and_then(|w| w.input_handle.as_ref())
+                            .context("Unmerged read writer uninitialized")?
+                            .send(IntakeOrdered {
+                                pair: ReadPair {
+                                    r1: Some(r),
+                                    r2: Some(mate),
+                                },
+                                order: unmerged_order,
+                            })?;
+                        unmerged_order += 1;
+                        continue;
+                    }
+                }
+            }
+
+            (pos, key) = self.get_pos_key(&r);
+            self.pull(r, pos, key, &mut pulled)?;
 
             refresh_count += 1;
 
             if refresh_count == 1000 {
-                bottomhash.shrink_to_fit();
+                if let PulledReads::Memory(bottomhash) = &mut pulled {
+                    bottomhash.shrink_to_fit();
+                }
                 refresh_count = 0;
             }
         }
 
+        if let Some(mut mates) = mate_reader {
+            if mates.records.next_record().is_some() {
+                anyhow::bail!("R2 input has more reads than R1 input")
+            }
+        }
+
+        if let Some(unmerged_writer) = self.io.unmerged_writer.take() {
+            unmerged_writer.terminate()?;
+        }
+
+        Ok(pulled)
+    }
+
+    /// Pull R1/R2 pairs, keeping each pair together as a single unit for deduplication.
+    fn pull_pairs(
+        &mut self,
+        mut reader: FastqInput,
+        mut mate_reader: FastqInput,
+    ) -> Result<PulledReads<FastqPair>, Error> {
+        let (mut pos, mut key): (i64, ReadKey);
+        let mut pulled = self.init_store()?;
+
+        let mut refresh_count = 0;
+        while let Some(record) = reader.records.next_record() {
+            let mate = mate_reader
+                .records
+                .next_record()
+                .context("R2 input has fewer reads than R1 input")??;
+
+            let pair = FastqPair::new(record?, mate)?;
+
+            (pos, key) = self.get_pos_key(&pair);
+            self.pull(pair, pos, key, &mut pulled)?;
+
+            refresh_count += 1;
+
+            if refresh_count == 1000 {
+                if let PulledReads::Memory(bottomhash) = &mut pulled {
+                    bottomhash.shrink_to_fit();
+                }
+                refresh_count = 0;
+            }
+        }
+
+        if mate_reader.records.next_record().is_some() {
+            anyhow::bail!("R2 input has more reads than R1 input")
+        }
+
+        Ok(pulled)
+    }
+
+    /// Group reads of one bucket store, and write them.
+    fn group_and_write<T: SequenceRecord + Send + Debug>(
+        &mut self,
+        mut bottomhash: BottomHashMap<T>,
+        pt: &mut ProgressTracker,
+    ) -> Result<(), Error>
+    where
+        FastqIO: FileIO<T>,
+    {
         pt.update_window_reads(bottomhash.read_count);
-        // println! {"Processing {} reads...", bottomhash.read_count};
-
-        assert_eq!(bottomhash.read_dict.keys().len(), 1);
-        assert_eq!(
-            bottomhash
-                .read_dict
-                .first()
-                .context("Empty read dict")?
-                .1
-                .len(),
-            1
-        );
-
-        outreads.extend(
-            self.chunk_processor
-                .group_reads(&mut bottomhash, &mut pt.coord_bar),
-        );
-
-        self.io.write_reads(&mut outreads);
+
+        if !bottomhash.read_dict.is_empty() {
+            // rejects are only kept for BAM input (see DedupArgs::validate)
+            let (mut outreads, _) = self
+                .chunk_processor
+                .group_reads(&mut bottomhash, &mut pt.coord_bar);
+            self.io.write_reads(&mut outreads)?;
+        }
+
+        Ok(())
+    }
+
+    /// Group pulled reads, write them, and report. Spilled reads are grouped one partition at a
+    /// time.
+    fn finish<T: Spillable + Send + Debug>(
+        mut self,
+        pulled: PulledReads<T>,
+        mut pt: ProgressTracker,
+    ) -> Result<GroupReport, Error>
+    where
+        FastqIO: FileIO<T>,
+    {
+        match pulled {
+            PulledReads::Memory(bottomhash) => self.group_and_write(bottomhash, &mut pt)?,
+            PulledReads::Disk(mut spiller) => {
+                let (group_by_length, seq_key) =
+                    (self.chunk_processor.group_by_length, self.seq_key);
+                let pos_key = |read: &T| {
+                    let (pos, key) = fastq_pos_key(read, group_by_length, seq_key);
+                    (pos, key.get_key())
+                };
+
+                spiller.drain(&pos_key, &mut |reads| {
+                    let mut bottomhash = BottomHashMap {
+                        read_dict: IndexMap::with_capacity(500),
+                        read_count: 0,
+                    };
+
+                    for read in reads {
+                        let read = read?;
+                        let (pos, key) = pos_key(&read);
+                        let umi = read.get_umi(&self.separator)?;
+                        bottomhash.update_dict(
+                            pos,
+                            key,
+                            umi,
+                            read,
+                            Retain::new(self.group_reads, false),
+                        );
+                    }
+
+                    self.group_and_write(bottomhash, &mut pt)
+                })?;
+            }
+        }
 
         let num_reads_in = self.chunk_processor.read_counter;
         let min_maxes = self.chunk_processor.min_max.clone();
@@ -120,12 +398,37 @@ impl FileProcess for FastQFileProcess {
         // report on min and max number of reads per group
         // this creates minmax.txt
         if !group_report.is_blank() {
-            println!("{}", "DONE".green());
+            status!("{}", "DONE".green());
 
             group_report.write_to_report_file(&self.outfile);
-            println!("{}\n", group_report);
+            status!("{}\n", group_report);
         }
 
-        Ok(())
+        if self.overlap_merger.is_some() {
+            status!("{}", self.merge_report.to_string().trim_end());
+        }
+
+        Ok(group_report)
+    }
+}
+
+/// Reads pulled from the input: either held in memory, or spilled to disk partitions.
+enum PulledReads<T: Spillable> {
+    Memory(BottomHashMap<T>),
+    Disk(Spiller<T>),
+}
+
+/// FASTQ reads share a single position, unless a [SeqKey] is used to split them into buckets by
+/// sequence.
+fn fastq_pos_key<T: SequenceRecord>(
+    read: &T,
+    group_by_length: bool,
+    seq_key: Option<SeqKey>,
+) -> (i64, ReadKey) {
+    let (pos, key) = read.get_pos_key(group_by_length);
+
+    match seq_key {
+        Some(seq_key) => (seq_key.bucket(read.seq_str()), key),
+        None => (pos, key),
     }
 }
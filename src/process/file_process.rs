@@ -1,14 +1,13 @@
 use crate::cli::DedupArgs;
+use crate::group_report::GroupReport;
+use crate::utils::RecordFile;
 use anyhow::Error;
 
 pub trait FileProcess {
-    fn init_from_args(
-        args: &DedupArgs,
-        bam_file_path: &str,
-        bam_file_name: &str,
-    ) -> Result<Self, Error>
+    fn init_from_args(args: &DedupArgs, file: &RecordFile) -> Result<Self, Error>
     where
         Self: Sized;
 
-    fn process(self) -> Result<(), Error>;
+    /// Deduplicate the file, returning its report.
+    fn process(self) -> Result<GroupReport, Error>;
 }
@@ -1,8 +1,11 @@
 use crate::deduplicator::GroupHandler;
 use crate::grouper::Grouper;
+use crate::read_groups::ReadGroups;
 use crate::read_store::bottomhash::BottomHashMap;
+use crate::read_store::Retain;
 use crate::readkey::ReadKey;
 use crate::record::SequenceRecord;
+use crate::rejects::RejectReason;
 use crate::DedupArgs;
 use crate::GroupReport;
 use crate::GroupingMethod;
@@ -27,12 +30,17 @@ pub struct Processor {
     pub only_group: bool,
     pub min_depth: usize,
     pub paired: bool,
+    pub keep_rejects: bool,
+    // with --per-read-group, the pools of read groups, and a report for each pool
+    pub read_groups: Option<Arc<ReadGroups>>,
+    pub pool_reports: Mutex<Vec<GroupReport>>,
     percentage: f32,
     max_edit: u32,
     cluster_rev: bool,
 }
 
 impl Processor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         grouping_method: &GroupingMethod,
         group_by_length: bool,
@@ -40,6 +48,7 @@ impl Processor {
         only_group: bool,
         min_depth: usize,
         paired: bool,
+        keep_rejects: bool,
         percentage: f32,
         max_edit: u32,
         cluster_rev: bool,
@@ -54,6 +63,9 @@ impl Processor {
             only_group,
             min_depth,
             paired,
+            keep_rejects,
+            read_groups: None,
+            pool_reports: Mutex::new(Vec::new()),
             percentage,
             max_edit,
             cluster_rev,
@@ -61,19 +73,14 @@ impl Processor {
     }
 
     pub fn init_from_args(args: &DedupArgs, seed: u64) -> Self {
-        let min_depth = if args.singletons {
-            1
-        } else {
-            args.min_cluster_depth
-        };
-
         Self::new(
             &args.grouping_method,
             args.length,
             seed,
             args.only_group,
-            min_depth,
+            args.min_depth(),
             args.paired,
+            args.rejects_out.is_some(),
             args.percentage,
             args.max_edit,
             args.cluster_rev,
@@ -82,17 +89,18 @@ impl Processor {
 
     // run grouping on pulled reads
     // add tags to Records
-    // output them to list for writing to bam
+    // output them to list for writing to bam, along with rejected reads if kept
     pub fn group_reads<T: SequenceRecord + Send + std::fmt::Debug>(
         &mut self,
         bottomhash: &mut BottomHashMap<T>,
         coord_bar: &mut ProgressBar,
-    ) -> Vec<T> {
+    ) -> (Vec<T>, Vec<(T, RejectReason)>) {
         let grouping_method = Arc::new(&self.grouping_method);
 
         coord_bar.set_length(bottomhash.read_dict.len() as u64);
 
         let outreads = Arc::new(Mutex::new(Vec::new()));
+        let rejects = Arc::new(Mutex::new(Vec::new()));
 
         bottomhash
             .read_dict
@@ -127,16 +135,28 @@ impl Processor {
                         num_umis += 1;
                     }
 
+                    // all reads of a key share a pool; count the reads grouped in it
+                    let pool = self.read_groups.as_ref().map(|read_groups| {
+                        let read = umi_read_map
+                            .values()
+                            .flat_map(|(_, seq_map)| seq_map.values())
+                            .find_map(|seq_entry| seq_entry.reads.first());
+                        let num_reads: i64 =
+                            umi_read_map.values().map(|(count, _)| *count as i64).sum();
+                        (read.map_or(0, |read| read_groups.pool(read)), num_reads)
+                    });
+
                     let mut group_handler = GroupHandler {
                         // make seed for tag unique per position and key
-                        seed: self.seed + position as u64 + key,
+                        seed: self.seed.wrapping_add(position as u64).wrapping_add(key),
                         group_only: self.only_group,
                         min_depth: self.min_depth,
+                        keep_rejects: self.keep_rejects,
                     };
 
                     // perform UMI clustering per the method specified
                     let groupies = grouper.cluster(counts.clone(), &grouping_method);
-                    let (group_report, tagged_reads) = group_handler
+                    let (group_report, tagged_reads, rejected_reads) = group_handler
                         .tag_records(groupies, &mut umi_read_map, counts)
                         .unwrap();
 
@@ -145,9 +165,24 @@ impl Processor {
                     out.extend(tagged_reads);
                     drop(out);
 
+                    if !rejected_reads.is_empty() {
+                        rejects.lock().extend(rejected_reads);
+                    }
+
+                    if let Some((pool, num_reads)) = pool {
+                        let mut pool_reports = self.pool_reports.lock();
+                        if pool_reports.len() <= pool {
+                            pool_reports.resize_with(pool + 1, GroupReport::new);
+                        }
+                        pool_reports[pool].num_reads_input_file += num_reads;
+                        if let Some(group_report) = &group_report {
+                            pool_reports[pool].update(group_report, num_umis);
+                        }
+                    }
+
                     if let Some(group_report) = group_report {
                         let mut min_max = self.min_max.lock();
-                        min_max.update(group_report, num_umis);
+                        min_max.update(&group_report, num_umis);
                         drop(min_max)
                     }
                 }
@@ -158,9 +193,14 @@ impl Processor {
         info!("Outputting final reads for writing...");
         info!("\n{:?}", self.min_max);
 
-        Arc::try_unwrap(outreads)
-            .expect("Unable to dereference tagged reads!")
-            .into_inner()
+        (
+            Arc::try_unwrap(outreads)
+                .expect("Unable to dereference tagged reads!")
+                .into_inner(),
+            Arc::try_unwrap(rejects)
+                .expect("Unable to dereference rejected reads!")
+                .into_inner(),
+        )
     }
     // organize reads in bottomhash based on position
     pub fn pull_read<T: SequenceRecord>(
@@ -170,15 +210,9 @@ impl Processor {
         key: ReadKey,
         bottomhash: &mut BottomHashMap<T>,
         separator: &str,
-        retain_all: bool,
+        retain: Retain,
     ) -> Result<(), Error> {
-        bottomhash.update_dict(
-            pos,
-            key.get_key(),
-            read.get_umi(separator)?,
-            read,
-            retain_all,
-        );
+        bottomhash.update_dict(pos, key.get_key(), read.get_umi(separator)?, read, retain);
         self.read_counter += 1;
         Ok(())
     }
@@ -17,7 +17,7 @@ impl ProgressTracker {
                 coord_bar: ProgressBar::hidden(),
             }
         } else {
-            let _prog = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+            let _prog = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
             _prog.clear().ok();
             let ref_bar = _prog.add(make_reference_bar(num_references as u64));
             ref_bar.set_prefix("REFERENCE");
@@ -95,7 +95,7 @@ impl ProgressTracker {
 }
 
 pub fn make_reference_bar(num_refs: u64) -> ProgressBar {
-    let pb = ProgressBar::with_draw_target(Some(num_refs), ProgressDrawTarget::stdout());
+    let pb = ProgressBar::with_draw_target(Some(num_refs), ProgressDrawTarget::stderr());
     pb.set_style(
         ProgressStyle::with_template(
             "{prefix:<15} {human_pos:>3}/{human_len:<3} {msg:<15} {spinner}",
@@ -108,7 +108,7 @@ pub fn make_reference_bar(num_refs: u64) -> ProgressBar {
 }
 
 pub fn make_windowbar(num_windows: u64) -> ProgressBar {
-    let pb = ProgressBar::with_draw_target(Some(num_windows), ProgressDrawTarget::stdout());
+    let pb = ProgressBar::with_draw_target(Some(num_windows), ProgressDrawTarget::stderr());
     pb.set_style(
         ProgressStyle::with_template(
             "{prefix:<15} {human_pos:>3}/{human_len:<3} {msg:<15} {spinner}",
@@ -121,7 +121,7 @@ pub fn make_windowbar(num_windows: u64) -> ProgressBar {
 }
 
 pub fn make_coordbar(num_coords: u64) -> ProgressBar {
-    let pb = ProgressBar::with_draw_target(Some(num_coords), ProgressDrawTarget::stdout());
+    let pb = ProgressBar::with_draw_target(Some(num_coords), ProgressDrawTarget::stderr());
     pb.set_style(
         ProgressStyle::with_template(
             "{prefix:<15} {human_pos:>3}/{human_len:<3} {bar:40.cyan/blue}",
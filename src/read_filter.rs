@@ -0,0 +1,212 @@
+use crate::cli::{AlignmentPolicy, DedupArgs};
+use crate::record::BamRecord;
+
+// names of SAM flags, as accepted by `samtools view -f/-F`
+const FLAG_NAMES: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+/// Parse SAM flags given as a decimal or hexadecimal number, or as a comma-separated list of flag
+/// names, e.g. `0x904` or `UNMAP,SECONDARY,SUPPLEMENTARY`.
+pub fn parse_flags(flags: &str) -> Result<u16, String> {
+    if let Some(hex) = flags.strip_prefix("0x").or(flags.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|e| format!("invalid flags {flags}: {e}"));
+    }
+
+    if let Ok(flags) = flags.parse::<u16>() {
+        return Ok(flags);
+    }
+
+    flags.split(',').try_fold(0, |all, name| {
+        FLAG_NAMES
+            .iter()
+            .find(|(flag_name, _)| flag_name.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, flag)| all | flag)
+            .ok_or_else(|| format!("unknown flag {name}"))
+    })
+}
+
+/// Why a read was kept out of grouping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    RequiredFlags,
+    ExcludedFlags,
+    Mapq,
+    Secondary,
+    Supplementary,
+}
+
+/// What to do with a read before grouping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Group,
+    Discard(FilterReason),
+    // output the read only if its primary alignment is output
+    FollowPrimary,
+}
+
+/// Number of reads kept out of grouping, by [FilterReason].
+#[derive(Debug, Clone, Default)]
+pub struct FilterCounts {
+    pub required_flags: i64,
+    pub excluded_flags: i64,
+    pub mapq: i64,
+    pub secondary: i64,
+    pub supplementary: i64,
+    // secondary and supplementary alignments held to follow their primary, and of those, the
+    // ones output along with it.
+    pub following_primary: i64,
+    pub followers_output: i64,
+}
+
+impl FilterCounts {
+    pub fn count(&mut self, verdict: Verdict) {
+        match verdict {
+            Verdict::Group => {}
+            Verdict::FollowPrimary => self.following_primary += 1,
+            Verdict::Discard(FilterReason::RequiredFlags) => self.required_flags += 1,
+            Verdict::Discard(FilterReason::ExcludedFlags) => self.excluded_flags += 1,
+            Verdict::Discard(FilterReason::Mapq) => self.mapq += 1,
+            Verdict::Discard(FilterReason::Secondary) => self.secondary += 1,
+            Verdict::Discard(FilterReason::Supplementary) => self.supplementary += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &FilterCounts) {
+        self.required_flags += other.required_flags;
+        self.excluded_flags += other.excluded_flags;
+        self.mapq += other.mapq;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.following_primary += other.following_primary;
+        self.followers_output += other.followers_output;
+    }
+
+    pub fn num_discarded(&self) -> i64 {
+        self.required_flags + self.excluded_flags + self.mapq + self.secondary + self.supplementary
+    }
+}
+
+/// Decides which BAM reads are grouped, in the manner of `samtools view -f/-F/-q`. Secondary and
+/// supplementary alignments are handled by their own [AlignmentPolicy] first, so that following
+/// their primary takes precedence over the other filters.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadFilter {
+    pub require_flags: u16,
+    pub exclude_flags: u16,
+    pub min_mapq: u8,
+    pub secondary: AlignmentPolicy,
+    pub supplementary: AlignmentPolicy,
+}
+
+impl ReadFilter {
+    pub fn init_from_args(args: &DedupArgs) -> Self {
+        Self {
+            require_flags: args.require_flags,
+            exclude_flags: args.exclude_flags,
+            min_mapq: args.min_mapq,
+            secondary: args.secondary,
+            supplementary: args.supplementary,
+        }
+    }
+
+    pub fn check(&self, read: &BamRecord) -> Verdict {
+        let policy = match (read.is_secondary(), read.is_supplementary()) {
+            (true, _) => Some((self.secondary, FilterReason::Secondary)),
+            (_, true) => Some((self.supplementary, FilterReason::Supplementary)),
+            _ => None,
+        };
+
+        match policy {
+            Some((AlignmentPolicy::Drop, reason)) => return Verdict::Discard(reason),
+            Some((AlignmentPolicy::FollowPrimary, _)) => return Verdict::FollowPrimary,
+            Some((AlignmentPolicy::Group, _)) | None => {}
+        }
+
+        let flags = read.flags();
+        if flags & self.require_flags != self.require_flags {
+            Verdict::Discard(FilterReason::RequiredFlags)
+        } else if flags & self.exclude_flags != 0 {
+            Verdict::Discard(FilterReason::ExcludedFlags)
+        } else if read.mapq() < self.min_mapq {
+            Verdict::Discard(FilterReason::Mapq)
+        } else {
+            Verdict::Group
+        }
+    }
+
+    /// Whether secondary or supplementary alignments are held back until their primary is
+    /// output.
+    pub fn follows_primary(&self) -> bool {
+        self.secondary == AlignmentPolicy::FollowPrimary
+            || self.supplementary == AlignmentPolicy::FollowPrimary
+    }
+
+    /// Whether the read is handled by its [AlignmentPolicy] rather than grouped like other reads.
+    pub fn is_set_aside(&self, read: &BamRecord) -> bool {
+        (read.is_secondary() && self.secondary != AlignmentPolicy::Group)
+            || (read.is_supplementary() && self.supplementary != AlignmentPolicy::Group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("2308"), Ok(0x904));
+        assert_eq!(parse_flags("0x904"), Ok(0x904));
+        assert_eq!(parse_flags("UNMAP,secondary, SUPPLEMENTARY"), Ok(0x904));
+        assert!(parse_flags("UNMAPPED").is_err());
+    }
+
+    #[test]
+    fn test_read_filter() {
+        let filter = ReadFilter {
+            require_flags: 0x1,
+            exclude_flags: 0x200,
+            min_mapq: 10,
+            secondary: AlignmentPolicy::Drop,
+            supplementary: AlignmentPolicy::FollowPrimary,
+        };
+
+        let read = |flags: u16, mapq: u8| {
+            let mut read = BamRecord::new();
+            read.set_flags(flags);
+            read.set_mapq(mapq);
+            read
+        };
+
+        assert_eq!(filter.check(&read(0x1, 10)), Verdict::Group);
+        assert_eq!(
+            filter.check(&read(0x0, 60)),
+            Verdict::Discard(FilterReason::RequiredFlags)
+        );
+        assert_eq!(
+            filter.check(&read(0x201, 60)),
+            Verdict::Discard(FilterReason::ExcludedFlags)
+        );
+        assert_eq!(
+            filter.check(&read(0x1, 9)),
+            Verdict::Discard(FilterReason::Mapq)
+        );
+        assert_eq!(
+            filter.check(&read(0x101, 60)),
+            Verdict::Discard(FilterReason::Secondary)
+        );
+        // supplementary alignments follow their primary, regardless of other filters
+        assert_eq!(filter.check(&read(0x800, 0)), Verdict::FollowPrimary);
+    }
+}
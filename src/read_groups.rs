@@ -0,0 +1,124 @@
+use crate::cli::ReadGroupPool;
+use crate::record::SequenceRecord;
+use indexmap::IndexMap;
+use rust_htslib::bam::Header;
+use std::fmt;
+
+/// Name of the pool of reads without a read group, or with one missing from the header.
+const NO_READ_GROUP: &str = "none";
+
+/// What a pool of reads is named after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolKind {
+    NoReadGroup,
+    ReadGroup,
+    Library,
+}
+
+impl fmt::Display for PoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PoolKind::NoReadGroup => "none",
+            PoolKind::ReadGroup => "read_group",
+            PoolKind::Library => "library",
+        })
+    }
+}
+
+/// The pools reads are deduplicated in with `--per-read-group`: one per read group (`@RG` line) of
+/// the header, or with [ReadGroupPool::Library], one per library (`LB`), so that lanes sequenced
+/// from the same library are deduplicated together. Reads of different pools are never grouped
+/// together.
+#[derive(Debug)]
+pub struct ReadGroups {
+    // pool of each read group ID
+    pools: IndexMap<Vec<u8>, usize>,
+    // pool kinds and names, by index; reads without a known read group make up the first pool.
+    // Pools are keyed by kind as well as name, so that a read group without a library is never
+    // pooled with a library of the same name as its ID
+    pub names: Vec<(PoolKind, String)>,
+}
+
+impl ReadGroups {
+    pub fn from_header(header: &Header, pool_by: ReadGroupPool) -> Self {
+        let mut names = vec![(PoolKind::NoReadGroup, NO_READ_GROUP.to_string())];
+        let mut pools = IndexMap::new();
+
+        for read_group in header.to_hashmap().remove("RG").unwrap_or_default() {
+            let Some(id) = read_group.get("ID") else {
+                continue;
+            };
+
+            // read groups without a library are pooled on their own
+            let key = match (pool_by, read_group.get("LB")) {
+                (ReadGroupPool::Library, Some(library)) => (PoolKind::Library, library),
+                _ => (PoolKind::ReadGroup, id),
+            };
+            let pool = match names.iter().position(|(kind, name)| (*kind, name) == key) {
+                Some(pool) => pool,
+                None => {
+                    names.push((key.0, key.1.to_string()));
+                    names.len() - 1
+                }
+            };
+            pools.insert(id.as_bytes().to_vec(), pool);
+        }
+
+        Self { pools, names }
+    }
+
+    /// The pool of a read, by its RG tag.
+    pub fn pool<T: SequenceRecord>(&self, read: &T) -> usize {
+        read.read_group()
+            .and_then(|id| self.pools.get(id).copied())
+            .unwrap_or(0)
+    }
+
+    pub fn num_pools(&self) -> usize {
+        self.names.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::BamRecord;
+    use rust_htslib::bam::record::Aux;
+    use rust_htslib::bam::HeaderView;
+
+    #[test]
+    fn test_read_group_pools() {
+        let header = Header::from_template(&HeaderView::from_bytes(
+            b"@SQ\tSN:chr1\tLN:1000\n\
+            @RG\tID:A.L1\tLB:libA\tSM:a\n\
+            @RG\tID:A.L2\tLB:libA\tSM:a\n\
+            @RG\tID:B.L1\tSM:b\n\
+            @RG\tID:C.L1\tLB:B.L1\tSM:c",
+        ));
+
+        let mut read = BamRecord::new();
+        let mut pool_of = |read_groups: &ReadGroups, id: Option<&str>| {
+            read.remove_aux(b"RG").ok();
+            if let Some(id) = id {
+                read.push_aux(b"RG", Aux::String(id)).unwrap();
+            }
+            let (kind, name) = &read_groups.names[read_groups.pool(&read)];
+            format!("{kind}:{name}")
+        };
+
+        let by_id = ReadGroups::from_header(&header, ReadGroupPool::Id);
+        assert_eq!(by_id.num_pools(), 5);
+        assert_eq!(pool_of(&by_id, Some("A.L2")), "read_group:A.L2");
+        assert_eq!(pool_of(&by_id, Some("D.L1")), "none:none");
+        assert_eq!(pool_of(&by_id, None), "none:none");
+
+        // lanes of a library share a pool; read groups without a library keep their own, even if
+        // a library has the same name as their ID
+        let by_library = ReadGroups::from_header(&header, ReadGroupPool::Library);
+        assert_eq!(by_library.num_pools(), 4);
+        assert_eq!(pool_of(&by_library, Some("A.L1")), "library:libA");
+        assert_eq!(pool_of(&by_library, Some("A.L2")), "library:libA");
+        assert_eq!(pool_of(&by_library, Some("B.L1")), "read_group:B.L1");
+        assert_eq!(pool_of(&by_library, Some("C.L1")), "library:B.L1");
+    }
+}
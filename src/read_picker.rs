@@ -25,8 +25,9 @@ pub fn correct_errors<T: SequenceRecord>(clusters: &mut SeqMap<T>) -> Vec<T> {
             .then(seq_entry2.qual_sum.cmp(&seq_entry1.qual_sum))
     });
 
-    // return the first read of the sequence cluster at the top of the sort
-    let (_, mut seq_entry) = clusters.swap_remove_index(0).unwrap();
+    // return the first read of the sequence cluster at the top of the sort. The rest are left
+    // behind as duplicates (see take_duplicates).
+    let (_, seq_entry) = clusters.get_index_mut(0).unwrap();
     vec![seq_entry.reads.swap_remove(0)]
 }
 
@@ -41,6 +42,15 @@ pub fn push_all_reads<T: SequenceRecord>(clusters: &mut SeqMap<T>) -> Vec<T> {
     reads_to_write
 }
 
+// used with --rejects-out to collect the reads of a group left behind by correct_errors, including
+// those set aside by their sequence cluster
+pub fn take_duplicates<T: SequenceRecord>(clusters: &mut SeqMap<T>) -> Vec<T> {
+    clusters
+        .drain(..)
+        .flat_map(|(_seq, seq_entry)| seq_entry.reads.into_iter().chain(seq_entry.duplicates))
+        .collect()
+}
+
 // get the number of reads across all UMIs within a group
 // this is useful for setting a threshold for reads observed per UMI group
 pub fn get_counts(top_umi: &IndexSet<smol_str::SmolStr>, counts: &UmiHistogram) -> i64 {
@@ -54,7 +64,7 @@ pub fn get_counts(top_umi: &IndexSet<smol_str::SmolStr>, counts: &UmiHistogram)
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::read_store::read_store::{ReadStore, SeqMap};
+    use crate::read_store::read_store::{ReadStore, Retain, SeqMap};
     use rust_htslib::bam::Record;
     use std::collections::HashMap;
 
@@ -64,7 +74,7 @@ mod tests {
         record.set(b"read1", None, b"ATCG", b"####");
 
         let mut cluster = SeqMap::new();
-        cluster.intake(record, true);
+        cluster.intake(record, Retain::All);
 
         let result = correct_errors(&mut cluster);
         assert_eq!(result.len(), 1);
@@ -84,15 +94,39 @@ mod tests {
         record2.set(b"read2", None, b"ATCG", b"####");
         record3.set(b"read3", None, b"ATGG", b"####");
 
-        cluster.intake(record1, true);
-        cluster.intake(record2, true);
-        cluster.intake(record3, true);
+        cluster.intake(record1, Retain::All);
+        cluster.intake(record2, Retain::All);
+        cluster.intake(record3, Retain::All);
 
         let result = correct_errors(&mut cluster);
         assert_eq!(result.len(), 1);
         assert_eq!(result[0].seq().as_bytes(), b"ATCG");
     }
 
+    #[test]
+    fn test_take_duplicates() {
+        let mut cluster: SeqMap<Record> = SeqMap::new();
+        let mut record1 = Record::new();
+        let mut record2 = Record::new();
+        let mut record3 = Record::new();
+        record1.set(b"read1", None, b"ATCG", b"####");
+        record2.set(b"read2", None, b"ATCG", b"IIII");
+        record3.set(b"read3", None, b"ATGG", b"IIII");
+
+        cluster.intake(record1, Retain::BestAndDuplicates);
+        cluster.intake(record2, Retain::BestAndDuplicates);
+        cluster.intake(record3, Retain::BestAndDuplicates);
+
+        // the read of best quality is chosen, as if duplicates weren't kept
+        let result = correct_errors(&mut cluster);
+        assert_eq!(result[0].qname(), b"read2");
+
+        let taken = take_duplicates(&mut cluster);
+        let mut duplicates: Vec<&[u8]> = taken.iter().map(|read| read.qname()).collect();
+        duplicates.sort();
+        assert_eq!(duplicates, [b"read1", b"read3"]);
+    }
+
     #[test]
     fn test_get_counts() {
         let top_umi = IndexSet::from([
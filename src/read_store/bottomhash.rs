@@ -24,14 +24,7 @@ pub struct BottomHashMap<T: SequenceRecord> {
 }
 
 impl<T: SequenceRecord> BottomHashMap<T> {
-    pub fn update_dict(
-        &mut self,
-        position: i64,
-        key: u64,
-        umi: SmolStr,
-        read: T,
-        retain_all: bool,
-    ) {
+    pub fn update_dict(&mut self, position: i64, key: u64, umi: SmolStr, read: T, retain: Retain) {
         let (count, seq_map) = self
             .read_dict
             .entry(position)
@@ -42,7 +35,7 @@ impl<T: SequenceRecord> BottomHashMap<T> {
             .or_insert_with(|| (0, SeqMap::new()));
 
         *count += 1;
-        self.read_count += seq_map.intake(read, retain_all) as u64;
+        self.read_count += seq_map.intake(read, retain) as u64;
     }
 
     pub fn shrink_to_fit(&mut self) {
@@ -1,8 +1,9 @@
 pub mod bottomhash;
 pub mod pair_bundles;
+#[allow(clippy::module_inception)]
 pub mod read_store;
 
 pub use crate::read_store::{
     bottomhash::BottomHashMap,
-    read_store::{ReadStore, SeqMap},
+    read_store::{ReadStore, Retain, SeqMap},
 };
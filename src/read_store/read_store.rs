@@ -33,27 +33,49 @@ pub type UmiReadMap<T> = IndexMap<SmolStr, (i32, SeqMap<T>)>;
 /// Associates all reads sharing a given sequence.
 pub type SeqMap<T> = IndexMap<u64, SeqEntry<T>>;
 
+/// Which reads a [SeqEntry] holds on to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retain {
+    /// only the read with the best sequence quality
+    Best,
+    /// the read with the best sequence quality, with the others set aside as duplicates
+    BestAndDuplicates,
+    /// all reads, to be output with a group tag
+    All,
+}
+
+impl Retain {
+    pub fn new(group_only: bool, keep_duplicates: bool) -> Self {
+        match (group_only, keep_duplicates) {
+            (true, _) => Self::All,
+            (false, true) => Self::BestAndDuplicates,
+            (false, false) => Self::Best,
+        }
+    }
+}
+
 pub trait ReadStore<T: SequenceRecord> {
-    fn combine(&mut self, other: SeqMap<T>, retain_all: bool);
-    fn intake(&mut self, read: T, retain_all: bool) -> u8;
+    fn combine(&mut self, other: SeqMap<T>, retain: Retain);
+    fn intake(&mut self, read: T, retain: Retain) -> u8;
 }
 
 impl<T: SequenceRecord> ReadStore<T> for SeqMap<T> {
     /// Combine two [SeqMap]s into one
-    fn combine(&mut self, mut other: SeqMap<T>, retain_all: bool) {
+    fn combine(&mut self, mut other: SeqMap<T>, retain: Retain) {
         other.drain(..).for_each(|(other_seq, mut seq_entry)| {
-            let e = self.entry(other_seq).or_insert(SeqEntry::new(retain_all));
+            let e = self.entry(other_seq).or_insert(SeqEntry::new(retain));
             seq_entry.reads.drain(..).for_each(|read| {
                 ((e.up_method)(e, read));
-            })
+            });
+            e.duplicates.append(&mut seq_entry.duplicates);
         });
     }
 
     /// Update [Self] with a new read
-    fn intake(&mut self, read: T, retain_all: bool) -> u8 {
+    fn intake(&mut self, read: T, retain: Retain) -> u8 {
         let mut h = std::hash::DefaultHasher::new();
         read.seq_str().hash(&mut h);
-        let e = self.entry(h.finish()).or_insert(SeqEntry::new(retain_all));
+        let e = self.entry(h.finish()).or_insert(SeqEntry::new(retain));
 
         (e.up_method)(e, read)
     }
@@ -64,7 +86,7 @@ impl<T: SequenceRecord> ReadStore<T> for SeqMap<T> {
 /// [Self::count] tracks all reads with matching sequence, regardless of whether all or one is retained.
 ///
 /// Can hold all reads per sequence, or one read per sequence, in which case the read with the best
-/// sequence quality is retained per sequence.
+/// sequence quality is retained per sequence (see [Retain]).
 pub struct SeqEntry<T>
 where
     T: SequenceRecord,
@@ -73,6 +95,8 @@ where
     pub reads: Vec<T>,
     pub count: i32,
     pub qual_sum: u32,
+    // reads not retained, with [Retain::BestAndDuplicates]
+    pub duplicates: Vec<T>,
     pub up_method: for<'a> fn(&'a mut Self, read: T) -> u8,
 }
 
@@ -99,6 +123,29 @@ impl<T: SequenceRecord> SeqEntry<T> {
         ret
     }
 
+    /// only keep one read per sequence, as [Self::up_keep_single], setting the others aside
+    pub fn up_keep_single_with_duplicates(&mut self, read: T) -> u8 {
+        let s: u32 = read.qual().iter().map(|a| u32::from(*a)).sum();
+
+        let ret = match self.reads.is_empty() {
+            true => 1,
+            false => 0,
+        };
+
+        if s > self.qual_sum {
+            self.duplicates.append(&mut self.reads);
+            self.reads.push(read);
+            self.qual_sum = s;
+        } else {
+            self.duplicates.push(read);
+        }
+
+        assert_eq!(self.reads.len(), 1);
+        self.count += 1;
+
+        ret
+    }
+
     /// keep all
     pub fn up_group(&mut self, read: T) -> u8 {
         self.reads.push(read);
@@ -106,11 +153,12 @@ impl<T: SequenceRecord> SeqEntry<T> {
         1
     }
 
-    pub fn new(group: bool) -> Self {
+    pub fn new(retain: Retain) -> Self {
         // determine whether to retain a single read per sequence, or all reads
-        let up_method = match group {
-            true => Self::up_group,
-            false => Self::up_keep_single,
+        let up_method = match retain {
+            Retain::All => Self::up_group,
+            Retain::Best => Self::up_keep_single,
+            Retain::BestAndDuplicates => Self::up_keep_single_with_duplicates,
         };
 
         let reads = vec![];
@@ -121,6 +169,7 @@ impl<T: SequenceRecord> SeqEntry<T> {
             reads,
             count,
             qual_sum,
+            duplicates: Vec::new(),
             up_method,
         }
     }
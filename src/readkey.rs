@@ -1,3 +1,4 @@
+use crate::cli::{DedupArgs, SeqKeyMethod};
 use std::hash::{DefaultHasher, Hash, Hasher};
 
 // this module is responsible for creating a key for batching reads
@@ -8,6 +9,8 @@ pub struct ReadKey {
     pub length: usize,
     pub reverse: bool,
     pub chr: usize,
+    // pool of the read's read group, with --per-read-group (see [crate::read_groups::ReadGroups])
+    pub read_group: usize,
 }
 
 impl Hash for ReadKey {
@@ -15,12 +18,19 @@ impl Hash for ReadKey {
         self.length.hash(state);
         self.reverse.hash(state);
         self.chr.hash(state);
+        // reads outside any read group pool are keyed as without --per-read-group, so that their
+        // group tags are the same either way
+        if self.read_group != 0 {
+            self.read_group.hash(state);
+        }
     }
 }
 
 impl PartialEq for ReadKey {
     fn eq(&self, other: &Self) -> bool {
-        self.length == other.length && self.reverse == other.reverse
+        self.length == other.length
+            && self.reverse == other.reverse
+            && self.read_group == other.read_group
     }
 }
 
@@ -31,3 +41,66 @@ impl ReadKey {
         hasher.finish()
     }
 }
+
+/// FASTQ reads have no alignment coordinates to batch by. A [SeqKey] stands in for position,
+/// deriving a bucket from a k-mer of the read sequence, so that reads sharing a UMI but coming from
+/// different molecules are kept apart.
+#[derive(Debug, Clone, Copy)]
+pub struct SeqKey {
+    method: SeqKeyMethod,
+    k: usize,
+}
+
+impl SeqKey {
+    pub fn new(method: SeqKeyMethod, k: usize) -> Self {
+        assert!(k > 0);
+        Self { method, k }
+    }
+
+    pub fn init_from_args(args: &DedupArgs) -> Option<Self> {
+        args.seq_key_len
+            .map(|k| Self::new(args.seq_key, k as usize))
+    }
+
+    /// Get the bucket of a sequence. Sequences shorter than k are keyed by their entire sequence.
+    pub fn bucket(&self, seq: &[u8]) -> i64 {
+        let kmer = match self.method {
+            SeqKeyMethod::Prefix => &seq[..self.k.min(seq.len())],
+            SeqKeyMethod::Minimizer => seq
+                .windows(self.k.min(seq.len()).max(1))
+                .min_by_key(|kmer| hash_kmer(kmer))
+                .unwrap_or(seq),
+        };
+
+        // keep buckets non-negative, as positions usually are
+        (hash_kmer(kmer) >> 1) as i64
+    }
+}
+
+fn hash_kmer(kmer: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    kmer.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn test_seq_key_prefix() {
+    let seq_key = SeqKey::new(SeqKeyMethod::Prefix, 4);
+
+    assert_eq!(seq_key.bucket(b"ACGTAAAA"), seq_key.bucket(b"ACGTCCCC"));
+    assert_ne!(seq_key.bucket(b"ACGTAAAA"), seq_key.bucket(b"TCGTAAAA"));
+    assert_eq!(seq_key.bucket(b"AC"), seq_key.bucket(b"AC"));
+}
+
+#[test]
+fn test_seq_key_minimizer() {
+    let seq_key = SeqKey::new(SeqKeyMethod::Minimizer, 3);
+
+    let prefix_key = SeqKey::new(SeqKeyMethod::Prefix, 3);
+
+    // both sequences consist of the same 3-mers, so they share a minimizer despite differing
+    // in their first bases
+    assert_eq!(seq_key.bucket(b"ACGTAC"), seq_key.bucket(b"TACGTAC"));
+    assert_ne!(prefix_key.bucket(b"ACGTAC"), prefix_key.bucket(b"TACGTAC"));
+    assert!(seq_key.bucket(b"") >= 0);
+}
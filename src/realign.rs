@@ -5,6 +5,10 @@ use rust_htslib::bam::record::{Cigar, CigarString};
 
 pub type ReMapper = Aligner<fn(u8, u8) -> i32>;
 
+/// Merged reads are realigned to the reference within this many bases of where their pair
+/// aligned, so realignment can't move a read further than this.
+pub const MAX_REALIGN_SHIFT: i64 = 100;
+
 pub fn init_remapper(ref_fasta_file: &String) -> (ReMapper, Vec<u8>) {
     let aligner: ReMapper = Aligner::new(-5, -1, blosum62, 19, 70);
 
@@ -52,7 +52,9 @@ pub trait SequenceRecord {
     fn qual(&self) -> &[u8];
     fn get_umi(&self, separator: &str) -> Result<SmolStr, Error>;
     fn get_pos_key(&self, group_by_length: bool) -> (i64, ReadKey);
-    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8]);
+    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8], family_size: i64);
+    /// ID of the read's read group (RG tag), if any.
+    fn read_group(&self) -> Option<&[u8]>;
     #[allow(dead_code)]
     fn qname(&self) -> &[u8];
 }
@@ -100,6 +102,7 @@ impl SequenceRecord for BamRecord {
                 length: self.seq_len_from_cigar(false) * group_by_length as usize,
                 reverse: true,
                 chr: self.tid() as usize,
+                read_group: 0,
             };
             (pos, key)
         } else {
@@ -110,17 +113,25 @@ impl SequenceRecord for BamRecord {
                 length: self.seq_len_from_cigar(false) * group_by_length as usize,
                 reverse: false,
                 chr: self.tid() as usize,
+                read_group: 0,
             };
             (pos, key)
         }
     }
 
-    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8]) {
+    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8], _family_size: i64) {
         self.push_aux(b"BX", Aux::String(str::from_utf8(umi).unwrap()))
             .unwrap();
         self.push_aux(b"UG", Aux::String(str::from_utf8(group_tag).unwrap()))
             .unwrap();
     }
+
+    fn read_group(&self) -> Option<&[u8]> {
+        match self.aux(b"RG") {
+            Ok(Aux::String(id)) => Some(id.as_bytes()),
+            _ => None,
+        }
+    }
 }
 
 /// A wrapper around [bio::io::fastq::Record]
@@ -153,15 +164,250 @@ impl SequenceRecord for FastqRecord {
     fn get_pos_key(&self, group_by_length: bool) -> (i64, ReadKey) {
         let pos = 1;
         let key = ReadKey {
-            length: self.seq_str().len() & group_by_length as usize,
+            length: self.seq_str().len() * group_by_length as usize,
             reverse: false,
             chr: 1,
+            read_group: 0,
         };
 
         (pos, key)
     }
 
-    fn mark_group(&mut self, _tag: &[u8], _group_tag: &[u8]) {}
+    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8], family_size: i64) {
+        let comment = group_comment(umi, group_tag, family_size);
+        let desc = with_comment(self.desc(), &comment);
+        *self = FastqRecord::with_attrs(self.id(), Some(&desc), self.seq(), self.qual());
+    }
+
+    fn read_group(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Format group annotations as SAM tags for a FASTQ header comment, so that aligners can carry
+/// them into alignments (`bwa mem -C`, `minimap2 -y`): MI holds the group ID, RX the corrected UMI,
+/// and cD the number of reads in the group.
+fn group_comment(umi: &[u8], group_tag: &[u8], family_size: i64) -> String {
+    format!(
+        "MI:Z:{}\tRX:Z:{}\tcD:i:{}",
+        str::from_utf8(group_tag).unwrap(),
+        str::from_utf8(umi).unwrap(),
+        family_size
+    )
+}
+
+// group annotations replace the comment already on the read, as aligners copy the comment into
+// alignments whole, and fields such as Illumina's `1:N:0:ACGT` would make them invalid. Fields that
+// are SAM tags themselves (e.g. `BC:Z:ACGT`) are kept, unless they are group annotations.
+fn with_comment(desc: Option<&str>, comment: &str) -> String {
+    desc.into_iter()
+        .flat_map(|desc| desc.split('\t'))
+        .filter(|field| is_sam_tag(field) && !["MI:", "RX:", "cD:"].contains(&&field[..3]))
+        .chain(std::iter::once(comment))
+        .collect::<Vec<&str>>()
+        .join("\t")
+}
+
+// whether a comment field has the shape of a SAM tag, TAG:TYPE:VALUE
+fn is_sam_tag(field: &str) -> bool {
+    let field = field.as_bytes();
+    field.len() > 5
+        && field[0].is_ascii_alphabetic()
+        && field[1].is_ascii_alphanumeric()
+        && field[2] == b':'
+        && b"AifZHB".contains(&field[3])
+        && field[4] == b':'
+}
+
+/// A pair of FASTQ mates deduplicated as a single unit. Mates are stored as one concatenated
+/// sequence, so that grouping and representative selection consider both mates together; they are
+/// split back into R1 and R2 records for output.
+#[derive(Debug)]
+pub struct FastqPair {
+    id1: String,
+    desc1: Option<String>,
+    id2: String,
+    desc2: Option<String>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    r1_len: usize,
+}
+
+impl FastqPair {
+    pub fn new(r1: FastqRecord, r2: FastqRecord) -> Result<Self, Error> {
+        if mate_name(r1.id()) != mate_name(r2.id()) {
+            anyhow::bail!(
+                "R1 and R2 inputs are out of sync: found {} and {}",
+                r1.id(),
+                r2.id()
+            )
+        }
+
+        let r1_len = r1.seq().len();
+        let seq = [r1.seq(), r2.seq()].concat();
+        let qual = [r1.qual(), r2.qual()].concat();
+
+        Ok(Self {
+            id1: r1.id().to_string(),
+            desc1: r1.desc().map(str::to_string),
+            id2: r2.id().to_string(),
+            desc2: r2.desc().map(str::to_string),
+            seq,
+            qual,
+            r1_len,
+        })
+    }
+
+    /// Split the pair back into its R1 and R2 records.
+    pub fn into_mates(self) -> (FastqRecord, FastqRecord) {
+        let r1 = FastqRecord::with_attrs(
+            &self.id1,
+            self.desc1.as_deref(),
+            &self.seq[..self.r1_len],
+            &self.qual[..self.r1_len],
+        );
+        let r2 = FastqRecord::with_attrs(
+            &self.id2,
+            self.desc2.as_deref(),
+            &self.seq[self.r1_len..],
+            &self.qual[self.r1_len..],
+        );
+
+        (r1, r2)
+    }
+}
+
+// strip /1 and /2 mate suffixes, so that mate names can be compared
+fn mate_name(id: &str) -> &str {
+    id.strip_suffix("/1")
+        .or_else(|| id.strip_suffix("/2"))
+        .unwrap_or(id)
+}
+
+impl SequenceRecord for FastqPair {
+    fn _seq(&self) -> String {
+        unsafe { std::str::from_utf8_unchecked(&self.seq).to_string() }
+    }
+
+    fn seq_str(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn get_umi(&self, separator: &str) -> Result<SmolStr, Error> {
+        Ok(SmolStr::from(extract_umi_from_header(
+            &self.id1, separator,
+        )?))
+    }
+
+    fn qual(&self) -> &[u8] {
+        &self.qual
+    }
+
+    fn qname(&self) -> &[u8] {
+        self.id1.as_bytes()
+    }
+
+    fn get_pos_key(&self, group_by_length: bool) -> (i64, ReadKey) {
+        let pos = 1;
+        let key = ReadKey {
+            length: self.seq.len() * group_by_length as usize,
+            reverse: false,
+            chr: 1,
+            read_group: 0,
+        };
+
+        (pos, key)
+    }
+
+    fn mark_group(&mut self, umi: &[u8], group_tag: &[u8], family_size: i64) {
+        let comment = group_comment(umi, group_tag, family_size);
+        self.desc1 = Some(with_comment(self.desc1.as_deref(), &comment));
+        self.desc2 = Some(with_comment(self.desc2.as_deref(), &comment));
+    }
+
+    fn read_group(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[test]
+fn test_fastq_pair_roundtrip() {
+    let r1 = FastqRecord::with_attrs("read1_ACGT/1", Some("1:N:0"), b"AAAC", b"IIII");
+    let r2 = FastqRecord::with_attrs("read1_ACGT/2", Some("2:N:0"), b"GGT", b"###");
+
+    let pair = FastqPair::new(r1, r2).unwrap();
+    assert_eq!(pair.seq_str(), b"AAACGGT");
+    assert_eq!(pair.get_umi("_").unwrap(), "ACGT");
+
+    let (r1, r2) = pair.into_mates();
+    assert_eq!((r1.seq(), r1.qual()), (&b"AAAC"[..], &b"IIII"[..]));
+    assert_eq!(
+        (r2.seq(), r2.qual(), r2.desc()),
+        (&b"GGT"[..], &b"###"[..], Some("2:N:0"))
+    );
+}
+
+#[test]
+fn test_fastq_mark_group() {
+    let mut r1 = FastqRecord::with_attrs("read1_ACGT", Some("1:N:0:ACGT"), b"AAAC", b"IIII");
+    r1.mark_group(b"ACGA", b"abcdefgh", 4);
+
+    assert_eq!(r1.id(), "read1_ACGT");
+    assert_eq!(r1.desc(), Some("MI:Z:abcdefgh\tRX:Z:ACGA\tcD:i:4"));
+    assert!(r1.desc().unwrap().split('\t').all(is_sam_tag));
+    assert_eq!(r1.seq(), b"AAAC");
+
+    // SAM tags already in the comment are kept, but group annotations are replaced
+    let mut r1 = FastqRecord::with_attrs(
+        "read1_ACGT",
+        Some("BC:Z:ACGT\tMI:Z:old\t1:N:0"),
+        b"AAAC",
+        b"IIII",
+    );
+    r1.mark_group(b"ACGA", b"abcdefgh", 4);
+    assert_eq!(
+        r1.desc(),
+        Some("BC:Z:ACGT\tMI:Z:abcdefgh\tRX:Z:ACGA\tcD:i:4")
+    );
+
+    let mut r2 = FastqRecord::with_attrs("read2_ACGT", None, b"AAAC", b"IIII");
+    r2.mark_group(b"ACGA", b"abcdefgh", 4);
+    assert_eq!(r2.desc(), Some("MI:Z:abcdefgh\tRX:Z:ACGA\tcD:i:4"));
+
+    let m1 = FastqRecord::with_attrs("read3_ACGT/1", Some("1:N:0"), b"AAAC", b"IIII");
+    let m2 = FastqRecord::with_attrs("read3_ACGT/2", None, b"GGT", b"###");
+    let mut pair = FastqPair::new(m1, m2).unwrap();
+    pair.mark_group(b"ACGA", b"abcdefgh", 4);
+    let (m1, m2) = pair.into_mates();
+    assert_eq!(m1.desc(), Some("MI:Z:abcdefgh\tRX:Z:ACGA\tcD:i:4"));
+    assert_eq!(m2.desc(), Some("MI:Z:abcdefgh\tRX:Z:ACGA\tcD:i:4"));
+}
+
+#[test]
+fn test_fastq_pair_out_of_sync() {
+    let r1 = FastqRecord::with_attrs("read1_ACGT", None, b"AAAC", b"IIII");
+    let r2 = FastqRecord::with_attrs("read2_ACGT", None, b"GGT", b"###");
+
+    assert!(FastqPair::new(r1, r2).is_err());
+}
+
+#[test]
+fn test_fastq_key_by_length() {
+    // reads of lengths 4 and 6 used to share a key with --length, as only the lowest bit of the
+    // length was kept
+    let r1 = FastqRecord::with_attrs("read1_ACGT", None, b"AAAC", b"IIII");
+    let r2 = FastqRecord::with_attrs("read2_ACGT", None, b"AAACGT", b"IIIIII");
+
+    assert_eq!(r1.get_pos_key(true).1.length, 4);
+    assert_eq!(r2.get_pos_key(true).1.length, 6);
+    assert_ne!(
+        r1.get_pos_key(true).1.get_key(),
+        r2.get_pos_key(true).1.get_key()
+    );
+    assert_eq!(
+        r1.get_pos_key(false).1.get_key(),
+        r2.get_pos_key(false).1.get_key()
+    );
 }
 
 #[test]
@@ -0,0 +1,45 @@
+use crate::record::BamRecord;
+use anyhow::{Context, Error};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Writer;
+
+/// Tag holding the reason a read was rejected, in the BAM given with `--rejects-out`.
+pub const REJECT_TAG: &[u8; 2] = b"RJ";
+
+/// Why a read was left out of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    // not chosen as its group's representative
+    Duplicate,
+    // its group had fewer reads than --min-depth
+    BelowMinDepth,
+    // it overlapped its mate with mismatching bases, with --merge-pairs
+    DiscordantPair,
+    // it failed a read filter (see [crate::read_filter::ReadFilter])
+    Filtered,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Duplicate => "duplicate",
+            Self::BelowMinDepth => "below_min_depth",
+            Self::DiscordantPair => "discordant_pair",
+            Self::Filtered => "filtered",
+        }
+    }
+}
+
+/// Write a rejected read, tagged with the reason. Reads that were grouped carry the ID of their
+/// group in UG, as output reads do.
+pub fn write_reject(
+    writer: &mut Writer,
+    mut read: BamRecord,
+    reason: RejectReason,
+) -> Result<(), Error> {
+    read.remove_aux(REJECT_TAG).ok();
+    read.push_aux(REJECT_TAG, Aux::String(reason.as_str()))?;
+    writer
+        .write(&read)
+        .context("Failed to write to --rejects-out")
+}
@@ -0,0 +1,380 @@
+use crate::cli::dedup_args::DEFAULT_MAX_MEMORY_MB;
+use crate::cli::DedupArgs;
+use crate::record::{BamRecord, FastqPair, FastqRecord, SequenceRecord};
+use anyhow::{Context, Error};
+use bio::io::fastq;
+use rust_htslib::bam::{self, Read};
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+// loaded reads are assumed to take up about this many times their uncompressed size on disk.
+pub const MEMORY_OVERHEAD: u64 = 3;
+// compressed inputs (BAM, gzipped FASTQ) are assumed to be about this many times smaller than
+// their uncompressed reads.
+const COMPRESSION_RATIO: u64 = 4;
+// limits on how many partition files are open at once, and how often a partition too large to fit
+// in memory is split again before it is processed regardless.
+const MAX_PARTITIONS: usize = 256;
+const MAX_SPLIT_DEPTH: u64 = 3;
+
+/// Settings for out-of-core deduplication: where to spill reads, and how much memory the reads of
+/// one partition may take up.
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    pub tmpdir: PathBuf,
+    pub max_memory: u64,
+}
+
+impl SpillConfig {
+    pub fn init_from_args(args: &DedupArgs) -> Option<Self> {
+        args.tmpdir.as_ref().map(|tmpdir| Self {
+            tmpdir: PathBuf::from(tmpdir),
+            max_memory: args.max_memory.unwrap_or(DEFAULT_MAX_MEMORY_MB) * 1024 * 1024,
+        })
+    }
+
+    /// Roughly estimate the memory needed to hold all reads of an input file.
+    pub fn estimate_memory(&self, input_path: &str) -> u64 {
+        let size = fs::metadata(input_path).map(|m| m.len()).unwrap_or(0);
+        let compressed = input_path.ends_with(".gz") || input_path.ends_with(".bam");
+
+        size * MEMORY_OVERHEAD * if compressed { COMPRESSION_RATIO } else { 1 }
+    }
+}
+
+/// Writes reads to a partition file.
+pub trait PartitionWriter<T> {
+    fn push(&mut self, read: T) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+pub type PartitionReads<T> = Box<dyn Iterator<Item = Result<T, Error>>>;
+type PartitionWriters<T> = Vec<Box<dyn PartitionWriter<T>>>;
+
+/// Records that can be spilled to partition files and read back.
+pub trait Spillable: SequenceRecord + Sized + 'static {
+    type Header;
+
+    fn create_partition(
+        path: &Path,
+        header: &Self::Header,
+    ) -> Result<Box<dyn PartitionWriter<Self>>, Error>;
+
+    fn read_partition(path: &Path) -> Result<PartitionReads<Self>, Error>;
+}
+
+impl PartitionWriter<FastqRecord> for fastq::Writer<File> {
+    fn push(&mut self, read: FastqRecord) -> Result<(), Error> {
+        self.write_record(&read).map_err(Error::msg)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        fastq::Writer::flush(self).map_err(Error::msg)
+    }
+}
+
+impl Spillable for FastqRecord {
+    type Header = ();
+
+    fn create_partition(
+        path: &Path,
+        _header: &(),
+    ) -> Result<Box<dyn PartitionWriter<Self>>, Error> {
+        Ok(Box::new(fastq::Writer::to_file(path)?))
+    }
+
+    fn read_partition(path: &Path) -> Result<PartitionReads<Self>, Error> {
+        let records = fastq::Reader::from_file(path)
+            .map_err(Error::msg)?
+            .records()
+            .map(|r| r.map_err(Error::msg));
+
+        Ok(Box::new(records))
+    }
+}
+
+// pairs are spilled as interleaved R1/R2 records.
+impl PartitionWriter<FastqPair> for fastq::Writer<File> {
+    fn push(&mut self, pair: FastqPair) -> Result<(), Error> {
+        let (r1, r2) = pair.into_mates();
+        self.write_record(&r1)?;
+        self.write_record(&r2)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        fastq::Writer::flush(self).map_err(Error::msg)
+    }
+}
+
+impl Spillable for FastqPair {
+    type Header = ();
+
+    fn create_partition(
+        path: &Path,
+        _header: &(),
+    ) -> Result<Box<dyn PartitionWriter<Self>>, Error> {
+        Ok(Box::new(fastq::Writer::to_file(path)?))
+    }
+
+    fn read_partition(path: &Path) -> Result<PartitionReads<Self>, Error> {
+        let mut records = FastqRecord::read_partition(path)?;
+
+        let pairs = std::iter::from_fn(move || {
+            let r1 = records.next()?;
+            let r2 = records.next();
+
+            Some(match (r1, r2) {
+                (Ok(r1), Some(Ok(r2))) => FastqPair::new(r1, r2),
+                (Err(e), _) | (_, Some(Err(e))) => Err(e),
+                (_, None) => Err(anyhow::anyhow!("Spilled pair is missing its R2 record")),
+            })
+        });
+
+        Ok(Box::new(pairs))
+    }
+}
+
+impl PartitionWriter<BamRecord> for bam::Writer {
+    fn push(&mut self, read: BamRecord) -> Result<(), Error> {
+        bam::Writer::write(self, &read).map_err(Error::msg)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Spillable for BamRecord {
+    type Header = bam::Header;
+
+    fn create_partition(
+        path: &Path,
+        header: &bam::Header,
+    ) -> Result<Box<dyn PartitionWriter<Self>>, Error> {
+        let mut writer = bam::Writer::from_path(path, header, bam::Format::Bam)?;
+        writer.set_compression_level(bam::CompressionLevel::Fastest)?;
+        Ok(Box::new(writer))
+    }
+
+    fn read_partition(path: &Path) -> Result<PartitionReads<Self>, Error> {
+        let mut reader = bam::Reader::from_path(path)?;
+
+        let records = std::iter::from_fn(move || {
+            let mut record = BamRecord::new();
+            match reader.read(&mut record)? {
+                Ok(()) => Some(Ok(record)),
+                Err(e) => Some(Err(Error::msg(e))),
+            }
+        });
+
+        Ok(Box::new(records))
+    }
+}
+
+fn partition_of(pos: i64, key: u64, seed: u64, num_partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (seed, pos, key).hash(&mut hasher);
+    (hasher.finish() % num_partitions as u64) as usize
+}
+
+/// Spills reads to partition files in a temporary directory, split by a hash of their position
+/// and key. Reads that would be grouped together always share a partition, so each partition can
+/// be grouped on its own.
+///
+/// A partition that turns out to be too large for the memory budget is split again before it is
+/// processed, up to a fixed depth. The temporary directory is removed once the [Spiller] is dropped.
+pub struct Spiller<T: Spillable> {
+    dir: PathBuf,
+    max_memory: u64,
+    num_partitions: usize,
+    header: T::Header,
+    writers: PartitionWriters<T>,
+    paths: Vec<PathBuf>,
+    num_files: usize,
+}
+
+impl<T: Spillable> Spiller<T> {
+    pub fn new(
+        config: &SpillConfig,
+        name: &str,
+        header: T::Header,
+        expected_memory: u64,
+    ) -> Result<Self, Error> {
+        let dir = config
+            .tmpdir
+            .join(format!("rumina_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create temporary directory {}", dir.display()))?;
+
+        let mut spiller = Self {
+            dir,
+            max_memory: config.max_memory,
+            num_partitions: 0,
+            header,
+            writers: Vec::new(),
+            paths: Vec::new(),
+            num_files: 0,
+        };
+        spiller.set_expected_memory(expected_memory);
+
+        Ok(spiller)
+    }
+
+    /// Update how much memory the reads spilled next are expected to take up, which decides how
+    /// many partitions they are split into.
+    pub fn set_expected_memory(&mut self, expected_memory: u64) {
+        self.num_partitions =
+            (expected_memory.div_ceil(self.max_memory.max(1)) as usize).clamp(2, MAX_PARTITIONS);
+    }
+
+    fn open_partitions(
+        &mut self,
+        num_partitions: usize,
+    ) -> Result<(PartitionWriters<T>, Vec<PathBuf>), Error> {
+        let mut writers = Vec::with_capacity(num_partitions);
+        let mut paths = Vec::with_capacity(num_partitions);
+
+        for _ in 0..num_partitions {
+            let path = self.dir.join(format!("partition_{}", self.num_files));
+            self.num_files += 1;
+
+            writers.push(T::create_partition(&path, &self.header)?);
+            paths.push(path);
+        }
+
+        Ok((writers, paths))
+    }
+
+    pub fn push(&mut self, read: T, pos: i64, key: u64) -> Result<(), Error> {
+        if self.writers.is_empty() {
+            (self.writers, self.paths) = self.open_partitions(self.num_partitions)?;
+        }
+
+        let idx = partition_of(pos, key, 0, self.writers.len());
+        self.writers[idx].push(read)
+    }
+
+    /// Close all partitions, and hand the reads of each to `process` in turn. `pos_key` must
+    /// yield the same position and key as used to push a read. Processed partitions are deleted,
+    /// after which the spiller can be reused.
+    pub fn drain<K, F>(&mut self, pos_key: &K, process: &mut F) -> Result<(), Error>
+    where
+        K: Fn(&T) -> (i64, u64),
+        F: FnMut(PartitionReads<T>) -> Result<(), Error>,
+    {
+        for mut writer in self.writers.drain(..) {
+            writer.flush()?;
+        }
+
+        for path in std::mem::take(&mut self.paths) {
+            self.process_partition(&path, 1, pos_key, process)?;
+        }
+
+        Ok(())
+    }
+
+    fn process_partition<K, F>(
+        &mut self,
+        path: &Path,
+        depth: u64,
+        pos_key: &K,
+        process: &mut F,
+    ) -> Result<(), Error>
+    where
+        K: Fn(&T) -> (i64, u64),
+        F: FnMut(PartitionReads<T>) -> Result<(), Error>,
+    {
+        let expected_memory = fs::metadata(path)?.len() * MEMORY_OVERHEAD;
+
+        if expected_memory <= self.max_memory || depth > MAX_SPLIT_DEPTH {
+            process(T::read_partition(path)?)?;
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+
+        // the partition is too large; split it again with a different hash.
+        let num_partitions = self.num_partitions;
+        let (mut writers, paths) = self.open_partitions(num_partitions)?;
+
+        for read in T::read_partition(path)? {
+            let read = read?;
+            let (pos, key) = pos_key(&read);
+            writers[partition_of(pos, key, depth, num_partitions)].push(read)?;
+        }
+
+        for mut writer in writers {
+            writer.flush()?;
+        }
+        fs::remove_file(path)?;
+
+        for path in paths {
+            self.process_partition(&path, depth + 1, pos_key, process)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Spillable> Drop for Spiller<T> {
+    fn drop(&mut self) {
+        self.writers.clear();
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_of(id: &str, seq: &[u8]) -> FastqRecord {
+        FastqRecord::with_attrs(id, None, seq, &vec![b'I'; seq.len()])
+    }
+
+    #[test]
+    fn test_spill_roundtrip() {
+        let config = SpillConfig {
+            tmpdir: std::env::temp_dir(),
+            // small enough that every partition is split again
+            max_memory: 1,
+        };
+
+        let mut spiller: Spiller<FastqRecord> =
+            Spiller::new(&config, "test_spill_roundtrip", (), 1000).unwrap();
+
+        let pos_key = |read: &FastqRecord| (read.seq()[0] as i64, 0);
+        for (i, seq) in [b"AAAA", b"CCCC", b"AAAT", b"GGGG", b"CCCA"]
+            .iter()
+            .enumerate()
+        {
+            let read = read_of(&format!("read{i}_ACGT"), &seq[..]);
+            let (pos, key) = pos_key(&read);
+            spiller.push(read, pos, key).unwrap();
+        }
+
+        let mut partitions: Vec<Vec<Vec<u8>>> = Vec::new();
+        spiller
+            .drain(&pos_key, &mut |reads| {
+                let seqs = reads
+                    .map(|r| r.unwrap().seq().to_vec())
+                    .collect::<Vec<Vec<u8>>>();
+                if !seqs.is_empty() {
+                    partitions.push(seqs);
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        // all reads come back, and reads sharing a position share a partition
+        assert_eq!(partitions.iter().map(|p| p.len()).sum::<usize>(), 5);
+        for partition in &partitions {
+            assert!(partition.iter().all(|seq| seq[0] == partition[0][0]));
+        }
+        assert_eq!(partitions.len(), 3);
+
+        let dir = spiller.dir.clone();
+        drop(spiller);
+        assert!(!dir.exists());
+    }
+}
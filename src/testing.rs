@@ -0,0 +1,85 @@
+// Fixtures shared by unit tests: temporary directories and small BAM files.
+use rust_htslib::bam::{self, header::HeaderRecord, record::CigarString, Header, Record};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory under the system temporary directory, removed along with its contents once
+/// dropped, so that it is cleaned up even if a test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Make an empty directory named after `name` and the process ID, so that runs of the test
+    /// suite at the same time don't share it.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rumina_{name}_{}", std::process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// A header declaring the given references and their lengths.
+pub fn header_with(refs: &[(&str, u64)]) -> Header {
+    let mut header = Header::new();
+    for (name, len) in refs {
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", name).push_tag(b"LN", len);
+        header.push_record(&sq);
+    }
+    header
+}
+
+/// A header declaring a single reference, `chr1`, 1000 bases long.
+pub fn test_header() -> Header {
+    header_with(&[("chr1", 1000)])
+}
+
+/// A read mapped to reference `tid` at `pos`, with as many bases as its cigar takes, all `A`.
+pub fn mapped_read(name: &str, tid: i32, pos: i64, cigar: &str, reverse: bool) -> Record {
+    let cigar = CigarString::try_from(cigar).unwrap();
+    let len = cigar.iter().map(|c| c.len()).sum::<u32>() as usize;
+
+    let mut read = Record::new();
+    read.set(
+        name.as_bytes(),
+        Some(&cigar),
+        &vec![b'A'; len],
+        &vec![30; len],
+    );
+    read.unset_unmapped();
+    read.set_tid(tid);
+    read.set_pos(pos);
+    if reverse {
+        read.set_reverse();
+    }
+    read
+}
+
+/// Write reads to a BAM file in the order given.
+pub fn write_bam(path: &Path, header: &Header, reads: impl IntoIterator<Item = Record>) {
+    let mut writer = bam::Writer::from_path(path, header, bam::Format::Bam).unwrap();
+    for read in reads {
+        writer.write(&read).unwrap();
+    }
+}
+
+/// As [write_bam], then index the file next to it. Reads must be given in coordinate order.
+pub fn write_indexed_bam(path: &Path, header: &Header, reads: impl IntoIterator<Item = Record>) {
+    write_bam(path, header, reads);
+    bam::index::build(path, None, bam::index::Type::Bai, 1).unwrap();
+}
@@ -1,11 +1,25 @@
+use crate::cli::dedup_args::DEFAULT_MAX_MEMORY_MB;
+use crate::cli::AlignmentFormat;
+use crate::io::bam_sorter::sort_bam;
+use crate::io::fastqio::STDIO_PATH;
+use crate::spill::SpillConfig;
+use crate::status;
 use anyhow::{Context, Error};
-use rust_htslib::bam::{index, Header, IndexedReader, Read, Writer};
+use rust_htslib::bam::{self, index, Header, IndexedReader, Read, Writer};
 use std::path::Path;
-use std::process::Command;
+
+/// Longest reference a BAI can index.
+const BAI_MAX_LEN: u64 = (1 << 29) - 1;
+
+/// Bin size of CSI indexes, as 2^shift bp; the default of samtools.
+const CSI_MIN_SHIFT: u32 = 14;
 
 pub struct RecordFile {
     pub fname: String,
     pub fpath: String,
+    pub mate_path: Option<String>,
+    // the sample of a --samplesheet, after which outputs are named
+    pub sample: Option<String>,
 }
 
 pub enum FileType {
@@ -18,11 +32,21 @@ pub fn identify_file_type(path: &Path) -> Option<FileType> {
     let fpath = path.to_str()?.to_string();
 
     if fname.ends_with(".fastq.gz") {
-        return Some(FileType::FastqFile(RecordFile { fname, fpath }));
+        return Some(FileType::FastqFile(RecordFile {
+            fname,
+            fpath,
+            mate_path: None,
+            sample: None,
+        }));
     }
 
-    if fname.ends_with(".bam") {
-        return Some(FileType::BamFile(RecordFile { fname, fpath }));
+    if fname.ends_with(".bam") || fname.ends_with(".cram") {
+        return Some(FileType::BamFile(RecordFile {
+            fname,
+            fpath,
+            mate_path: None,
+            sample: None,
+        }));
     }
 
     None
@@ -95,31 +119,87 @@ pub fn get_windows(window_size: Option<i64>, max_pos: i64) -> Vec<Window> {
     }
 }
 
-pub fn make_bam_writer(file_name: &str, header: Header, num_threads: usize) -> Writer {
-    let mut bam_writer =
-        Writer::from_path(file_name, &header, rust_htslib::bam::Format::Bam).unwrap();
+/// Make a writer for alignments in the given format, writing to stdout given `-`. CRAM output is
+/// encoded against `reference`.
+pub fn make_bam_writer(
+    file_name: &str,
+    header: Header,
+    num_threads: usize,
+    format: AlignmentFormat,
+    reference: Option<&str>,
+) -> Writer {
+    let mut bam_writer = match file_name == STDIO_PATH {
+        true => Writer::from_stdout(&header, format.hts_format()).unwrap(),
+        false => Writer::from_path(file_name, &header, format.hts_format()).unwrap(),
+    };
     bam_writer.set_threads(num_threads).unwrap();
+    if let Some(reference) = reference {
+        bam_writer.set_reference(reference).unwrap();
+    }
     bam_writer
 }
 
-pub fn make_bam_reader(input_file: &str, num_threads: usize) -> (Header, IndexedReader) {
-    let mut bam_reader = IndexedReader::from_path(input_file).unwrap();
+/// Make an indexed reader for a BAM or CRAM file, with its index at `index` if given, or else next
+/// to it. CRAM input is decoded against `reference`.
+pub fn make_bam_reader(
+    input_file: &str,
+    index: Option<&str>,
+    num_threads: usize,
+    reference: Option<&str>,
+) -> Result<(Header, IndexedReader), Error> {
+    let mut bam_reader = match index {
+        Some(index) => IndexedReader::from_path_and_index(input_file, index),
+        None => IndexedReader::from_path(input_file),
+    }
+    .with_context(|| format!("Failed to open {input_file} with its index"))?;
     bam_reader.set_threads(num_threads).unwrap();
+    if let Some(reference) = reference {
+        bam_reader
+            .set_reference(reference)
+            .with_context(|| format!("Failed to load reference {reference}"))?;
+    }
     let header = Header::from_template(bam_reader.header());
 
-    (header, bam_reader)
+    Ok((header, bam_reader))
+}
+
+/// Whether a BAM's header has a reference too long to be indexed with a BAI, which can only hold
+/// positions below 2^29.
+fn needs_csi(bam_name: &str) -> bool {
+    bam::Reader::from_path(bam_name).is_ok_and(|reader| {
+        let header = reader.header();
+        (0..header.target_count()).any(|tid| header.target_len(tid).unwrap_or(0) > BAI_MAX_LEN)
+    })
 }
 
 pub fn _index_bam(
     bam_name: &str,
     num_threads: usize,
+    csi: bool,
+) -> Result<String, rust_htslib::errors::Error> {
+    index_bam_to(bam_name, bam_name, num_threads, csi)
+}
+
+/// Index an alignment file, writing the index to `idx_stem` plus the extension of the index
+/// (e.g. `.bai`). Returns the index's path.
+pub fn index_bam_to(
+    bam_name: &str,
+    idx_stem: &str,
+    num_threads: usize,
+    csi: bool,
 ) -> Result<String, rust_htslib::errors::Error> {
     // this function will return an error if the input bam is not sorted.
-    let idx_name = format!("{bam_name}.bai");
+    let (idx_type, idx_name) = match AlignmentFormat::from_path(bam_name) {
+        Some(AlignmentFormat::Cram) => (index::Type::Bai, format!("{idx_stem}.crai")),
+        _ if csi || needs_csi(bam_name) => {
+            (index::Type::Csi(CSI_MIN_SHIFT), format!("{idx_stem}.csi"))
+        }
+        _ => (index::Type::Bai, format!("{idx_stem}.bai")),
+    };
     let res = index::build(
         Path::new(bam_name),
         Some(Path::new(&idx_name)),
-        index::Type::Bai,
+        idx_type,
         num_threads.try_into().unwrap(),
     );
 
@@ -129,16 +209,22 @@ pub fn _index_bam(
     }
 }
 
-pub fn index_bam(bam_name: &str, num_threads: usize) -> Result<String, Error> {
-    let idx = match _index_bam(bam_name, num_threads) {
+/// Index a BAM or CRAM file, sorting it first if it's unsorted. BAMs get a CSI rather than a BAI
+/// if `csi` is set, or if a reference is too long for a BAI.
+pub fn index_bam(
+    bam_name: &str,
+    num_threads: usize,
+    reference: Option<&str>,
+    csi: bool,
+) -> Result<String, Error> {
+    let idx = match _index_bam(bam_name, num_threads, csi) {
         Err(_) => {
-            eprintln!("=======================");
-            eprintln!(
-                "Original output file unsorted. Attempting to resort with samtools in $PATH..."
-            );
-            try_sort_bam(bam_name, num_threads)?;
-            let _idx = index_bam(bam_name, num_threads)?;
-            eprintln!("Sort successful. Index built.");
+            status!("=======================");
+            status!("Original output file unsorted. Attempting to resort...");
+            try_sort_bam(bam_name, num_threads, reference)?;
+            let _idx = _index_bam(bam_name, num_threads, csi)
+                .with_context(|| format!("Failed to index {bam_name} after sorting"))?;
+            status!("Sort successful. Index built.");
             _idx
         }
         Ok(_idx) => _idx,
@@ -147,26 +233,53 @@ pub fn index_bam(bam_name: &str, num_threads: usize) -> Result<String, Error> {
     Ok(idx)
 }
 
-pub fn try_sort_bam(bam_name: &str, num_threads: usize) -> Result<(), Error> {
-    let tempname = format!("{bam_name}_PRE_SORT");
-    let threads_str = num_threads.to_string();
-
-    let c = Command::new("samtools")
-        .arg("sort")
-        .arg("-o")
-        .arg(&tempname)
-        .arg(bam_name)
-        .arg("-@")
-        .arg(&threads_str)
-        .spawn()
-        .expect("Failed to start samtools")
-        .wait()?;
-
-    if !c.success() {
-        anyhow::bail!("Failed to sort bam file")
-    }
+/// Sort a BAM file by coordinate in place, spilling sorted runs next to it if its reads take up
+/// more than the default memory budget.
+pub fn try_sort_bam(
+    bam_name: &str,
+    num_threads: usize,
+    reference: Option<&str>,
+) -> Result<(), Error> {
+    let tmpdir = Path::new(bam_name)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    let config = SpillConfig {
+        tmpdir,
+        max_memory: DEFAULT_MAX_MEMORY_MB * 1024 * 1024,
+    };
 
-    std::fs::rename(tempname, bam_name)?;
+    sort_bam(bam_name, num_threads, &config, reference).context("Failed to sort bam file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{header_with, mapped_read, write_bam, TempDir};
+
+    #[test]
+    fn test_index_bam_csi_for_long_references() {
+        let tmpdir = TempDir::new("index_bam");
+
+        for (name, len) in [("short", 1000), ("long", BAI_MAX_LEN + 1)] {
+            let bam_name = tmpdir
+                .join(format!("{name}.bam"))
+                .to_string_lossy()
+                .to_string();
+            let read = mapped_read("read1", 0, len as i64 - 10, "4M", false);
+            write_bam(Path::new(&bam_name), &header_with(&[("chr1", len)]), [read]);
 
-    Ok(())
+            let idx = _index_bam(&bam_name, 1, false).unwrap();
+            let expected = match name {
+                "short" => "bai",
+                _ => "csi",
+            };
+            assert!(idx.ends_with(expected), "{idx}");
+
+            // CSI-indexed input is read like BAI-indexed input
+            let (_, mut reader) = make_bam_reader(&bam_name, None, 1, None).unwrap();
+            reader.fetch("chr1").unwrap();
+            assert_eq!(reader.records().count(), 1);
+        }
+    }
 }